@@ -4,22 +4,9 @@ use std::{
     process::Command,
 };
 
-use bindgen::{builder, callbacks::ParseCallbacks};
+use bindgen::builder;
 use libbpf_cargo::SkeletonBuilder;
 
-#[derive(Debug)]
-struct ActionCallbacks;
-
-impl ParseCallbacks for ActionCallbacks {
-    fn add_derives(&self, name: &str) -> Vec<String> {
-        if name == "Action" {
-            vec!["Deserialize".into(), "Serialize".into()]
-        } else {
-            vec![]
-        }
-    }
-}
-
 fn main() {
     let bpf = "./src/bpf".to_string();
     let out = format!("{bpf}/.output");
@@ -64,13 +51,14 @@ fn main() {
         .generate_comments(false)
         .allowlist_type("Action")
         .allowlist_type("Event")
+        .allowlist_type("PowerMode")
+        .allowlist_type("Metric")
+        .allowlist_type("Frame")
         .allowlist_var("HAS_ADDR4")
         .allowlist_var("HAS_QOS")
         .allowlist_var("CHECK_QOS")
         .allowlist_var("HDR_SIZE_3ADDR")
         .allowlist_var("HDR_SIZE_4ADDR")
-        .raw_line("use serde::{Deserialize, Serialize};")
-        .parse_callbacks(Box::new(ActionCallbacks))
         .generate()
         .expect("generate ffi bindings")
         .write_to_file(&bnd)
@@ -0,0 +1,281 @@
+use crate::error::{Error, Result};
+
+use mesh_analyzer::{ControlClient, Request, Response, SessionState, StreamAddr};
+
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::{self, stdin, ErrorKind},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+// how often the services are asked if they're done correlating
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+
+// service running as a daemon, and the station it traces for the session
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub station: String,
+    pub capture: Option<String>,
+    pub addr: StreamAddr,
+}
+
+// parse a node like 'nodeA:mon0=10.0.0.1:7000' (the capture interface is optional)
+pub fn parse_node(node: &str) -> std::result::Result<Node, String> {
+    let (station, addr) = node
+        .split_once('=')
+        .ok_or_else(|| format!("'{node}' isn't in the format 'STATION[:CAPTURE]=ADDR'"))?;
+    let (station, capture) = match station.split_once(':') {
+        Some((station, capture)) => (station, Some(capture.to_string())),
+        None => (station, None),
+    };
+    if station.is_empty() || capture.as_deref() == Some("") {
+        return Err(format!("'{node}' has an empty field"));
+    }
+    Ok(Node {
+        station: station.to_string(),
+        capture,
+        addr: addr.parse()?,
+    })
+}
+
+// run a capture session on all the nodes, starting and stopping them at the same time,
+// and fetch their files into a folder with the name of the session
+// (the instants are in wall clock time, so the clocks of the nodes should be synchronized)
+// (the nodes have 'wait' to finish correlating, the files of the others are still fetched)
+pub fn run_session(
+    session: &str,
+    nodes: &[Node],
+    delay: Duration,
+    duration: Option<Duration>,
+    wait: Duration,
+) -> Result<PathBuf> {
+    let mut clients = nodes
+        .iter()
+        .map(|node| {
+            ControlClient::connect(&node.addr).map_err(|source| Error::Connect {
+                addr: node.addr.clone(),
+                source,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // start
+    let at = SystemTime::now() + delay;
+    println!("Starting session {session} at {}", format_instant(at));
+    let started = request_all(&mut clients, nodes, |node| Request::Start {
+        session: session.to_string(),
+        station: Some(node.station.clone()),
+        interface: node.capture.clone(),
+        at: Some(at),
+    });
+    if let Err(err) = all_answered(nodes, &started) {
+        // (the nodes that did start would keep tracing, with nobody to stop them)
+        let stop = Request::Stop {
+            session: session.to_string(),
+            at: None,
+        };
+        for ((client, node), _) in clients
+            .iter_mut()
+            .zip(nodes)
+            .zip(&started)
+            .filter(|(_, started)| **started)
+        {
+            match client.request(&stop) {
+                Ok(_) => println!("Stopped session {session} on {}", node.station),
+                Err(err) => eprintln!(
+                    "Can't stop session {session} on {} ({}): {err}",
+                    node.addr, node.station
+                ),
+            }
+        }
+        return Err(err);
+    }
+
+    // wait for the end of the session
+    match duration {
+        Some(duration) => {
+            thread::sleep(at.duration_since(SystemTime::now()).unwrap_or_default() + duration)
+        }
+        None => {
+            println!("Press Enter to stop the session.");
+            stdin()
+                .read_line(&mut String::new())
+                .expect("read stop from stdin");
+        }
+    }
+
+    // stop
+    let at = SystemTime::now() + delay;
+    println!("Stopping session {session} at {}", format_instant(at));
+    let stopped = request_all(&mut clients, nodes, |_| Request::Stop {
+        session: session.to_string(),
+        at: Some(at),
+    });
+    // (the files of the nodes that did stop are still fetched, so they aren't lost)
+    let stop_failed = all_answered(nodes, &stopped).err();
+
+    // fetch the files once they're correlated
+    let folder = PathBuf::from(session);
+    create_dir_all(&folder).map_err(|source| Error::SessionFolder {
+        path: folder.clone(),
+        source,
+    })?;
+    let deadline = Instant::now() + wait;
+    let mut pending = vec![];
+    for ((client, node), _) in clients
+        .iter_mut()
+        .zip(nodes)
+        .zip(&stopped)
+        .filter(|(_, stopped)| **stopped)
+    {
+        let files = match wait_for_files(client, session, node, deadline) {
+            Some(files) => files,
+            None => {
+                pending.push(node.station.clone());
+                continue;
+            }
+        };
+        for file in files {
+            let name = match file.file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if let Err(err) = fetch_file(client, session, &name, &folder) {
+                eprintln!("Can't fetch {name} from {}: {err}", node.addr);
+            }
+        }
+    }
+    if let Some(err) = stop_failed {
+        eprintln!(
+            "Session {session} didn't stop everywhere, the files of the others are in {}",
+            folder.display()
+        );
+        return Err(err);
+    }
+    if !pending.is_empty() {
+        return Err(Error::Pending { pending, wait });
+    }
+    println!("Session {session} saved in {}", folder.display());
+    Ok(folder)
+}
+
+// send a request to all the nodes at the same time, and wait for all of them to answer
+// (the services wait for the instant in the request before answering)
+// (whether each node did what it was asked, in the same order)
+fn request_all(
+    clients: &mut [ControlClient],
+    nodes: &[Node],
+    request: impl Fn(&Node) -> Request + Sync,
+) -> Vec<bool> {
+    let request = &request;
+    thread::scope(|scope| {
+        let handles = clients
+            .iter_mut()
+            .zip(nodes)
+            .map(|(client, node)| scope.spawn(move || (node, client.request(&request(node)))))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                let (node, response) = handle.join().expect("join control request thread");
+                if let Err(err) = &response {
+                    eprintln!("Service at {} ({}) failed: {err}", node.addr, node.station);
+                }
+                response.is_ok()
+            })
+            .collect()
+    })
+}
+
+// the nodes that didn't do what they were asked make the session fail
+fn all_answered(nodes: &[Node], answered: &[bool]) -> Result<()> {
+    let failed = nodes
+        .iter()
+        .zip(answered)
+        .filter(|(_, answered)| !**answered)
+        .map(|(node, _)| node.station.clone())
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        return Err(Error::Session { failed });
+    }
+    Ok(())
+}
+
+// ask a node for the status of the session until it's finished, and get its files
+// ('None' if it's still going at 'deadline', and a node that doesn't answer
+//  as expected is left without files)
+fn wait_for_files(
+    client: &mut ControlClient,
+    session: &str,
+    node: &Node,
+    deadline: Instant,
+) -> Option<Vec<PathBuf>> {
+    let request = Request::Status {
+        session: Some(session.to_string()),
+    };
+    loop {
+        // (a node that stopped answering counts as still going,
+        //  and even after the deadline each node is asked once)
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .max(STATUS_INTERVAL);
+        if let Err(err) = client.set_timeout(Some(timeout)) {
+            eprintln!("Can't get status from {}: {err}", node.addr);
+            return Some(vec![]);
+        }
+        match client.request(&request) {
+            Ok(Response::Status(status)) => {
+                if let Some(status) = status
+                    .into_iter()
+                    .find(|s| s.state == SessionState::Finished)
+                {
+                    if status.drops > 0 {
+                        eprintln!(
+                            "The kernel of {} dropped {} events/frames during the session.",
+                            node.station, status.drops
+                        );
+                    }
+                    return Some(status.files);
+                }
+            }
+            Ok(response) => {
+                eprintln!("Unexpected status from {}: {response:?}", node.addr);
+                return Some(vec![]);
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return None;
+            }
+            Err(err) => {
+                eprintln!("Can't get status from {}: {err}", node.addr);
+                return Some(vec![]);
+            }
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(STATUS_INTERVAL);
+    }
+}
+
+// fetch a file into a temporary one first, since services on the same machine
+// might be writing their sessions in the same folder
+fn fetch_file(
+    client: &mut ControlClient,
+    session: &str,
+    name: &str,
+    folder: &Path,
+) -> io::Result<()> {
+    let partial = folder.join(format!(".{name}.part"));
+    client.fetch(session, name, &mut File::create(&partial)?)?;
+    rename(partial, folder.join(name))
+}
+
+fn format_instant(at: SystemTime) -> String {
+    OffsetDateTime::from(at)
+        .format(&Rfc3339)
+        .expect("format instant")
+}
@@ -0,0 +1,133 @@
+use mesh_analyzer::{ExitCode, StreamAddr};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::PathBuf,
+    time::Duration,
+};
+
+// what can stop central
+#[derive(Debug)]
+pub enum Error {
+    // the folder of the stations can't be read
+    Folder {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // the folder doesn't have any files of stations
+    NoFiles(PathBuf),
+    // there are files, but none that make up a station
+    NoStations(PathBuf),
+    // a station has more than one events file
+    Ambiguous {
+        station: String,
+        files: Vec<PathBuf>,
+    },
+    // a file of a station can't be read
+    File(mesh_analyzer::Error),
+    // can't connect to a service, or listen for them
+    Connect {
+        addr: StreamAddr,
+        source: io::Error,
+    },
+    Listen {
+        addr: StreamAddr,
+        source: io::Error,
+    },
+    // a service didn't do what it was asked for a session
+    Session {
+        failed: Vec<String>,
+    },
+    // some services didn't finish the session in time
+    Pending {
+        pending: Vec<String>,
+        wait: Duration,
+    },
+    // the files of a session can't be saved
+    SessionFolder {
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Self::Folder { source, .. } | Self::SessionFolder { source, .. }
+                if source.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                ExitCode::NoPermission
+            }
+            Self::Folder { .. } | Self::NoFiles(_) | Self::NoStations(_) => ExitCode::NoInput,
+            Self::Ambiguous { .. } => ExitCode::Usage,
+            Self::File(err) => err.exit_code(),
+            Self::Connect { .. } => ExitCode::Unavailable,
+            Self::Listen { .. } => ExitCode::Io,
+            Self::Session { .. } | Self::Pending { .. } => ExitCode::Protocol,
+            Self::SessionFolder { .. } => ExitCode::CantCreate,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Folder { path, source } => write!(f, "can't read {}: {source}", path.display()),
+            Self::NoFiles(path) => write!(
+                f,
+                "{} doesn't have any '.json', '.bin', '.pcap' nor '.manifest' files \
+                 (extensions must be lowercase)",
+                path.display()
+            ),
+            Self::NoStations(path) => write!(
+                f,
+                "{} doesn't have any stations (each one needs its events file and a '.pcap' \
+                 with the same name, or a '.manifest' that names them)",
+                path.display()
+            ),
+            Self::Ambiguous { station, files } => write!(
+                f,
+                "station {station} has more than one events file ({}), \
+                 move the ones that aren't its own to another folder",
+                files
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::File(err) => write!(f, "{err}"),
+            Self::Connect { addr, source } => write!(
+                f,
+                "can't connect to the service at {addr}: {source} \
+                 (is it running with --daemon {addr}?)"
+            ),
+            Self::Listen { addr, source } => {
+                write!(f, "can't listen for services on {addr}: {source}")
+            }
+            Self::Session { failed } => {
+                write!(f, "the session failed on {}", failed.join(", "))
+            }
+            Self::Pending { pending, wait } => write!(
+                f,
+                "the session didn't finish on {} after {} s (long captures take a while \
+                 to correlate, use a longer --wait)",
+                pending.join(", "),
+                wait.as_secs()
+            ),
+            Self::SessionFolder { path, source } => {
+                write!(f, "can't save the session in {}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mesh_analyzer::Error> for Error {
+    fn from(err: mesh_analyzer::Error) -> Self {
+        Self::File(err)
+    }
+}
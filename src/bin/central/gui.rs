@@ -1,16 +1,18 @@
 use crate::util::Station;
 
-use mesh_analyzer::{Event, MacAddr};
+use mesh_analyzer::{Event, MacAddr, NeighborStats, PlinkState};
 
 use std::{
     cell::RefCell,
     collections::HashMap,
     process::{Child, Command},
-    sync::Mutex,
+    sync::{mpsc::Receiver, Mutex},
+    time::SystemTime,
 };
 
 use eframe::{
     egui::{
+        plot::{Legend, Line, Plot, VLine, Value, Values},
         CentralPanel, CollapsingHeader, Context, Direction, Grid, Layout, RichText, ScrollArea,
         Style, Ui, Visuals, Window,
     },
@@ -24,35 +26,61 @@ use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 pub struct Gui {
     stations: Vec<Station>,
     events_windows_open: RefCell<HashMap<(String, usize), bool>>,
+    paths_windows_open: RefCell<HashMap<String, bool>>,
+    neighbors_windows_open: RefCell<HashMap<String, bool>>,
     wireshark_open: RefCell<HashMap<String, Mutex<Child>>>,
+    live: Option<Receiver<(String, Event)>>,
 }
 
 impl Gui {
-    pub fn new(cc: &CreationContext, stations: Vec<Station>) -> Self {
+    pub fn new(
+        cc: &CreationContext,
+        stations: Vec<Station>,
+        live: Option<Receiver<(String, Event)>>,
+    ) -> Self {
         cc.egui_ctx.set_style(Style {
             visuals: Visuals::dark(),
             ..Style::default()
         });
-        let mut events_windows_open =
-            HashMap::with_capacity(stations[0].events().len() * stations.len());
+        // (windows are identified by the station and the id of the event,
+        //  since the rows change when live events arrive)
+        let mut events_windows_open = HashMap::with_capacity(
+            stations.first().map_or(0, |s| s.events().len()) * stations.len(),
+        );
         for station in &stations {
             let name = station.name();
-            for (i, event) in station.events().iter().enumerate() {
-                if event.is_some() {
-                    events_windows_open.insert((name.to_string(), i), false);
-                }
+            for event in station.events().iter().flatten() {
+                events_windows_open.insert((name.to_string(), *event.id()), false);
             }
         }
         Self {
             stations,
             events_windows_open: RefCell::new(events_windows_open),
+            paths_windows_open: RefCell::new(HashMap::default()),
+            neighbors_windows_open: RefCell::new(HashMap::default()),
             wireshark_open: RefCell::new(HashMap::default()),
+            live,
+        }
+    }
+
+    // add the events that were streamed since the last frame
+    fn receive_live_events(&mut self) {
+        let events = match &self.live {
+            Some(live) => live.try_iter().collect::<Vec<_>>(),
+            None => return,
+        };
+        if events.is_empty() {
+            return;
+        }
+        for (station, event) in events {
+            Station::add_live_event(&mut self.stations, station, event);
         }
+        Station::sort(&mut self.stations);
     }
 
     fn gui_table(&mut self, ui: &mut Ui) {
         let num_stations = self.stations.len();
-        let num_events = self.stations[0].events().len();
+        let num_events = self.stations.first().map_or(0, |s| s.events().len());
         TableBuilder::new(ui)
             .striped(true)
             .cell_layout(Layout::centered_and_justified(Direction::LeftToRight))
@@ -64,7 +92,20 @@ impl Gui {
                 });
                 for station in &self.stations {
                     head.col(|ui| {
-                        ui.heading(RichText::new(station.name()).strong());
+                        ui.horizontal(|ui| {
+                            ui.heading(RichText::new(station.name()).strong())
+                                .on_hover_text(station.description());
+                            if station.snapshots() > 0 && ui.button("Paths").clicked() {
+                                self.paths_windows_open
+                                    .borrow_mut()
+                                    .insert(station.name().to_string(), true);
+                            }
+                            if !station.neighbors().is_empty() && ui.button("Neighbors").clicked() {
+                                self.neighbors_windows_open
+                                    .borrow_mut()
+                                    .insert(station.name().to_string(), true);
+                            }
+                        });
                     });
                 }
             })
@@ -84,16 +125,15 @@ impl Gui {
                                             ui.with_layout(
                                                 Layout::top_down_justified(Align::Center),
                                                 |ui| {
-                                                    ui.label(format!(
-                                                        "{} ({})",
-                                                        event.action(),
-                                                        self.name_from_mac(event.dst())
-                                                    ));
+                                                    ui.label(self.event_summary(event));
                                                     if ui.button("Info").clicked() {
                                                         self.events_windows_open
                                                             .borrow_mut()
                                                             .insert(
-                                                                (station.name().to_string(), i),
+                                                                (
+                                                                    station.name().to_string(),
+                                                                    *event.id(),
+                                                                ),
                                                                 true,
                                                             );
                                                     }
@@ -120,6 +160,9 @@ impl Gui {
                 ui.label("Interface:");
                 ui.label(format!("{} [{}]", event.iface(), event.mac(),));
                 ui.end_row();
+                ui.label("Network namespace:");
+                ui.label(format!("{} (ifindex {})", event.netns(), event.ifindex()));
+                ui.end_row();
                 ui.label("Timestamp:");
                 ui.label(
                     OffsetDateTime::from(*event.ts())
@@ -127,28 +170,223 @@ impl Gui {
                         .expect("format timestamp"),
                 );
                 ui.end_row();
-                ui.label("Path information:");
-                ui.end_row();
-                ui.label("\t\tDestination:");
-                ui.label(format!(
-                    "{} [{}]",
-                    self.name_from_mac(event.dst()),
-                    event.dst()
-                ));
-                ui.end_row();
-                if let Some(nh) = event.old_nh() {
-                    ui.label("\t\tOld nexthop:");
-                    ui.label(format!("{} [{}]", self.name_from_mac(nh), nh));
+                if event.from_pkt() {
+                    ui.label("Retries:");
+                    ui.label(event.retries().to_string());
                     ui.end_row();
                 }
-                if let Some(nh) = event.new_nh() {
-                    ui.label("\t\tNew nexthop:");
-                    ui.label(format!("{} [{}]", self.name_from_mac(nh), nh));
+                if event.from_path() {
+                    ui.label("Path information:");
+                    ui.end_row();
+                    ui.label("\t\tDestination:");
+                    ui.label(format!(
+                        "{} [{}]",
+                        self.name_from_mac(event.dst()),
+                        event.dst()
+                    ));
+                    ui.end_row();
+                    if let Some(nh) = event.old_nh() {
+                        ui.label("\t\tOld nexthop:");
+                        ui.label(format!("{} [{}]", self.name_from_mac(nh), nh));
+                        ui.end_row();
+                    }
+                    if let Some(nh) = event.new_nh() {
+                        ui.label("\t\tNew nexthop:");
+                        ui.label(format!("{} [{}]", self.name_from_mac(nh), nh));
+                        ui.end_row();
+                    }
+                }
+                if let (Some(drift), Some(adjust)) = (event.tsf_drift(), event.tsf_adjust()) {
+                    ui.label("Synchronization information:");
+                    ui.end_row();
+                    ui.label("\t\tClock drift:");
+                    ui.label(format!("{drift} µs"));
+                    ui.end_row();
+                    ui.label("\t\tTSF adjustment:");
+                    ui.label(format!("{adjust:+} µs"));
                     ui.end_row();
                 }
+                if let Some(peer) = event.peer() {
+                    ui.label("Power mode information:");
+                    ui.end_row();
+                    ui.label("\t\tPeer:");
+                    ui.label(format!("{} [{}]", self.name_from_mac(peer), peer));
+                    ui.end_row();
+                    if let Some(pm) = event.old_pm() {
+                        ui.label("\t\tOld power mode:");
+                        ui.label(pm.to_string());
+                        ui.end_row();
+                    }
+                    if let Some(pm) = event.new_pm() {
+                        ui.label("\t\tNew power mode:");
+                        ui.label(pm.to_string());
+                        ui.end_row();
+                    }
+                }
             });
     }
 
+    // where the events didn't match the path tables that the kernel had
+    fn gui_paths_grid(&self, ui: &mut Ui, station: &Station) {
+        if station.divergences().is_empty() {
+            ui.label(format!(
+                "The events match all {} path snapshots of the kernel.",
+                station.snapshots()
+            ));
+            return;
+        }
+        ScrollArea::vertical().show(ui, |ui| {
+            Grid::new(format!("{}_paths", station.name()))
+                .striped(true)
+                .show(ui, |ui| {
+                    for label in ["Snapshot", "Interface", "Destination", "Divergence"] {
+                        ui.label(RichText::new(label).strong());
+                    }
+                    ui.end_row();
+                    for divergence in station.divergences() {
+                        ui.label(
+                            OffsetDateTime::from(divergence.ts)
+                                .format(&Rfc3339)
+                                .expect("format snapshot timestamp"),
+                        );
+                        ui.label(divergence.iface.as_str());
+                        ui.label(format!(
+                            "{} [{}]",
+                            self.name_from_mac(&divergence.dst),
+                            divergence.dst
+                        ));
+                        ui.label(divergence.kind.to_string());
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+
+    // statistics of each neighbor over time, with the path events that involve it
+    // (in seconds since the first sample of the station)
+    fn gui_neighbors_plots(&self, ui: &mut Ui, station: &Station) {
+        let start = station
+            .neighbors()
+            .iter()
+            .filter_map(|n| n.samples.first())
+            .map(|s| s.ts)
+            .min()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let secs = |ts: &SystemTime| match ts.duration_since(start) {
+            Ok(after) => after.as_secs_f64(),
+            Err(before) => -before.duration().as_secs_f64(),
+        };
+
+        ScrollArea::vertical().show(ui, |ui| {
+            for neighbor in station.neighbors() {
+                let events = station
+                    .events()
+                    .iter()
+                    .flatten()
+                    .filter(|e| involves(e, neighbor))
+                    .map(|e| secs(e.ts()))
+                    .collect::<Vec<_>>();
+                let id = format!("{}_{}_{}", station.name(), neighbor.iface, neighbor.peer);
+                let title = format!(
+                    "{} [{}] on {} ({} path events)",
+                    self.name_from_mac(&neighbor.peer),
+                    neighbor.peer,
+                    neighbor.iface,
+                    events.len()
+                );
+                CollapsingHeader::new(title).id_source(&id).show(ui, |ui| {
+                    let samples = &neighbor.samples;
+                    let series = |name: &str, value: &dyn Fn(usize) -> Option<f64>| {
+                        let values = (0..samples.len())
+                            .filter_map(|i| Some(Value::new(secs(&samples[i].ts), value(i)?)))
+                            .collect::<Vec<_>>();
+                        Line::new(Values::from_values(values)).name(name)
+                    };
+                    // (the retries and failures are counted since the neighbor was added,
+                    //  so each sample shows how many there were since the one before)
+                    let delta = |i: usize, count: &dyn Fn(usize) -> Option<u32>| {
+                        let prev = i.checked_sub(1).and_then(count).unwrap_or(0);
+                        Some(f64::from(count(i)?.saturating_sub(prev)))
+                    };
+                    let plots: [(&str, Vec<Line>); 4] = [
+                        (
+                            "signal",
+                            vec![
+                                series("signal (dBm)", &|i| samples[i].signal.map(f64::from)),
+                                series("average (dBm)", &|i| samples[i].signal_avg.map(f64::from)),
+                            ],
+                        ),
+                        (
+                            "bitrate",
+                            vec![series("tx bitrate (Mbps)", &|i| {
+                                samples[i].tx_bitrate.map(|r| f64::from(r) / 1000.0)
+                            })],
+                        ),
+                        (
+                            "retries",
+                            vec![
+                                series("tx retries", &|i| delta(i, &|i| samples[i].tx_retries)),
+                                series("tx failed", &|i| delta(i, &|i| samples[i].tx_failed)),
+                            ],
+                        ),
+                        (
+                            "metric",
+                            vec![
+                                series("airtime metric", &|i| {
+                                    samples[i].airtime_metric.map(f64::from)
+                                }),
+                                series("inactive (ms)", &|i| samples[i].inactive_ms.map(f64::from)),
+                            ],
+                        ),
+                    ];
+                    for (plot, lines) in plots {
+                        Plot::new(format!("{id}_{plot}"))
+                            .height(120.0)
+                            .legend(Legend::default())
+                            .show(ui, |plot_ui| {
+                                for line in lines {
+                                    plot_ui.line(line);
+                                }
+                                for x in &events {
+                                    plot_ui.vline(VLine::new(*x));
+                                }
+                            });
+                    }
+
+                    // (the peer link is only shown when it changes)
+                    let mut changes: Vec<(f64, Option<PlinkState>)> = vec![];
+                    for sample in samples {
+                        if changes.last().map(|(_, state)| *state) != Some(sample.plink_state) {
+                            changes.push((secs(&sample.ts), sample.plink_state));
+                        }
+                    }
+                    ui.label(format!(
+                        "Peer link: {}",
+                        changes
+                            .iter()
+                            .map(|(at, state)| match state {
+                                Some(state) => format!("{state} at {at:.1} s"),
+                                None => format!("unknown at {at:.1} s"),
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                });
+            }
+        });
+    }
+
+    // short description of an event to show in the table
+    fn event_summary(&self, event: &Event) -> String {
+        if let Some(peer) = event.peer() {
+            format!("{} ({})", event.action(), self.name_from_mac(peer))
+        } else if let Some(adjust) = event.tsf_adjust() {
+            format!("{} ({adjust:+} µs)", event.action())
+        } else {
+            format!("{} ({})", event.action(), self.name_from_mac(event.dst()))
+        }
+    }
+
     fn name_from_mac(&self, mac: &MacAddr) -> &str {
         for station in &self.stations {
             if station.mac().contains(mac) {
@@ -159,6 +397,16 @@ impl Gui {
     }
 }
 
+// path event of the interface that has the neighbor as its destination or nexthop
+fn involves(event: &Event, neighbor: &NeighborStats) -> bool {
+    let peer = Some(&neighbor.peer);
+    event.from_path()
+        && event.mac() == &neighbor.mac
+        && (event.dst() == &neighbor.peer
+            || event.old_nh().as_ref() == peer
+            || event.new_nh().as_ref() == peer)
+}
+
 impl App for Gui {
     fn on_exit(&mut self, _gl: &GlowContext) {
         #[allow(unused_must_use)]
@@ -172,18 +420,37 @@ impl App for Gui {
     }
 
     fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+        self.receive_live_events();
         CentralPanel::default().show(ctx, |ui| {
             ScrollArea::horizontal().show(ui, |ui| {
                 self.gui_table(ui);
             });
         });
+        for (name, open) in self.neighbors_windows_open.borrow_mut().iter_mut() {
+            if let Some(station) = self.stations.iter().find(|s| s.name() == name) {
+                Window::new(format!("Station {name} | Neighbors"))
+                    .open(open)
+                    .show(ctx, |ui| self.gui_neighbors_plots(ui, station));
+            }
+        }
+        for (name, open) in self.paths_windows_open.borrow_mut().iter_mut() {
+            if let Some(station) = self.stations.iter().find(|s| s.name() == name) {
+                Window::new(format!("Station {name} | Paths"))
+                    .open(open)
+                    .show(ctx, |ui| self.gui_paths_grid(ui, station));
+            }
+        }
         for window in self.events_windows_open.borrow_mut().iter_mut() {
             let mut station = None;
             let mut event = None;
             for sta in &self.stations {
                 if sta.name() == window.0 .0 {
                     station = Some(sta);
-                    event = sta.events()[window.0 .1].as_ref();
+                    event = sta
+                        .events()
+                        .iter()
+                        .flatten()
+                        .find(|e| *e.id() == window.0 .1);
                     break;
                 }
             }
@@ -201,6 +468,13 @@ impl App for Gui {
                     if event.from_pkt() {
                         ui.separator();
                         ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                            let pcap = match station.pcap() {
+                                Some(pcap) => pcap,
+                                None => {
+                                    ui.label("This event was streamed live, so it doesn't have packets associated to it yet.");
+                                    return;
+                                }
+                            };
                             if event.pkts().is_empty() {
                                 ui.label("This event doesn't have any packets associated to it, even though it should.");
                                 if ui.button("Open capture file in Wireshark anyway").clicked() {
@@ -212,13 +486,26 @@ impl App for Gui {
                                         Command::new("wireshark")
                                             .args([
                                                 "-r",
-                                                &station.pcap().to_string_lossy()
+                                                &pcap.to_string_lossy()
                                             ])
                                             .spawn()
                                             .expect("start wireshark (1)")
                                         ));
                                 }
-                            } else if event.pkts().len() == 1 {
+                            } else {
+                                let best = *event.best_packet().expect("event has packets");
+                                ui.label(format!(
+                                    "Best match is packet {} ({:.0}% confidence)",
+                                    best.pkt,
+                                    best.score * 100.0
+                                ));
+                                if event.pkts().len() > 1 {
+                                    CollapsingHeader::new("Other packets").show(ui, |ui| {
+                                        for pkt in &event.pkts()[1..] {
+                                            ui.label(format!("{} ({:.0}%)", pkt.pkt, pkt.score * 100.0));
+                                        }
+                                    });
+                                }
                                 if ui.button("Open event in Wireshark").clicked() {
                                     #[allow(unused_must_use)]
                                     if let Some(chl) = self.wireshark_open.borrow_mut().get_mut(station.name()) {
@@ -228,34 +515,12 @@ impl App for Gui {
 
                                     Command::new("wireshark").args([
                                         "-r",
-                                        &station.pcap().to_string_lossy(),
+                                        &pcap.to_string_lossy(),
                                         "-g",
-                                        &event.pkts()[0].to_string(),
+                                        &best.pkt.to_string(),
                                     ]).spawn().expect("start wireshark (2)")
                                     ));
                                 }
-                            } else {
-                                ui.label("This event has several packets associated to it (should be only one):");
-                                CollapsingHeader::new("Packets").show(ui, |ui| {
-                                    for pkt in event.pkts() {
-                                        ui.label(pkt.to_string());
-                                    }
-                                });
-                                if ui.button("Open Wireshark at the first event").clicked() {
-                                    #[allow(unused_must_use)]
-                                    if let Some(chl) = self.wireshark_open.borrow_mut().get_mut(station.name()) {
-                                        chl.get_mut().expect("get lock on wireshark child (3)").kill();
-                                    }
-                                    self.wireshark_open.borrow_mut().insert(station.name().to_string(), Mutex::new(
-
-                                    Command::new("wireshark").args([
-                                        "-r",
-                                        &station.pcap().to_string_lossy(),
-                                        "-g",
-                                        &event.pkts()[0].to_string(),
-                                    ]).spawn().expect("start wireshark (3)")
-                                    ));
-                                }
                             }
                         });
                     }
@@ -0,0 +1,45 @@
+use mesh_analyzer::{read_message, Event, Message, StreamListener};
+
+use std::{
+    sync::mpsc::{channel, Receiver},
+    thread,
+};
+
+use eframe::egui::Context;
+
+// accept the services streaming events, each connection in its own thread
+// (the gui gets woken up every time an event arrives)
+pub fn start_listener(listener: StreamListener, ctx: Context) -> Receiver<(String, Event)> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(mut stream) => {
+                let sender = sender.clone();
+                let ctx = ctx.clone();
+                thread::spawn(move || {
+                    if let Err(err) = stream.handshake() {
+                        eprintln!("Dropped connection from a service: {err}");
+                        return;
+                    }
+                    loop {
+                        match read_message(&mut stream) {
+                            Ok(Some(Message::Event { station, event })) => {
+                                if sender.send((station, event)).is_err() {
+                                    return;
+                                }
+                                ctx.request_repaint();
+                            }
+                            Ok(None) => return,
+                            Err(err) => {
+                                eprintln!("Dropped connection from a service: {err}");
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+            Err(err) => eprintln!("Can't accept connection from a service: {err}"),
+        }
+    });
+    receiver
+}
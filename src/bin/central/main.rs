@@ -1,28 +1,84 @@
+mod coordinator;
+mod error;
 mod gui;
+mod live;
 mod util;
 
 use crate::{
+    coordinator::{run_session, Node},
+    error::{Error, Result},
     gui::Gui,
+    live::start_listener,
     util::{get_files, setup_cli_arg_parser, Station},
 };
 
-use std::path::Path;
+use mesh_analyzer::StreamAddr;
+
+use std::{path::Path, process::exit, time::Duration};
 
 use eframe::{run_native, NativeOptions};
 
 fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err}");
+        exit(err.exit_code().code());
+    }
+}
+
+fn run() -> Result<()> {
     let cli_args = setup_cli_arg_parser().get_matches();
-    let folder = Path::new(
-        cli_args
-            .get_one::<String>("FOLDER")
-            .expect("get folder from cli arguments"),
-    );
 
-    let stations = Station::stations_from_files(get_files(folder));
+    // a session is run before showing its files
+    let session = cli_args
+        .get_one::<String>("session")
+        .map(|session| {
+            let nodes = cli_args
+                .get_many::<Node>("node")
+                .expect("get nodes from cli arguments")
+                .cloned()
+                .collect::<Vec<_>>();
+            let delay = Duration::from_millis(
+                *cli_args
+                    .get_one::<u64>("delay")
+                    .expect("get delay from cli arguments"),
+            );
+            let duration = cli_args
+                .get_one::<u64>("duration")
+                .map(|secs| Duration::from_secs(*secs));
+            let wait = Duration::from_secs(
+                *cli_args
+                    .get_one::<u64>("wait")
+                    .expect("get wait from cli arguments"),
+            );
+            run_session(session, &nodes, delay, duration, wait)
+        })
+        .transpose()?;
+
+    // stations from files (if there's a folder) are shown together with the live ones
+    let folder = session
+        .as_deref()
+        .or_else(|| cli_args.get_one::<String>("FOLDER").map(Path::new));
+    let stations = match folder {
+        Some(folder) => Station::stations_from_files(folder, get_files(folder)?)?,
+        None => vec![],
+    };
+    let listener = cli_args
+        .get_one::<StreamAddr>("listen")
+        .map(|addr| {
+            addr.listen().map_err(|source| Error::Listen {
+                addr: addr.clone(),
+                source,
+            })
+        })
+        .transpose()?;
 
+    // (the gui exits the process when it's closed)
     run_native(
         "Mesh Analyzer",
         NativeOptions::default(),
-        Box::new(|cc| Box::new(Gui::new(cc, stations))),
-    );
+        Box::new(|cc| {
+            let live = listener.map(|listener| start_listener(listener, cc.egui_ctx.clone()));
+            Box::new(Gui::new(cc, stations, live))
+        }),
+    )
 }
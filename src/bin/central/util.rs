@@ -1,48 +1,132 @@
-use mesh_analyzer::{Event, MacAddr};
+use crate::{
+    coordinator::parse_node,
+    error::{Error, Result},
+};
+
+use mesh_analyzer::{
+    read_events_file, replay_paths, Event, MacAddr, Manifest, NeighborSample, NeighborStats,
+    PathDivergence, PathSnapshot, StreamAddr,
+};
 
 use std::{
-    fs::File,
-    io::Read,
+    collections::BTreeMap,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
 };
 
-use clap::{App, Arg, Command};
-use serde_json::from_slice;
+use clap::{value_parser, App, Arg, ArgAction, Command};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 pub fn setup_cli_arg_parser() -> App<'static> {
     Command::new("Mesh Analyzer - Central")
         .author("Carlos Pinto <up201606191@up.pt>")
         .arg(
             Arg::new("FOLDER")
-                .required(true)
+                .required_unless_present_any(&["listen", "session"])
+                .conflicts_with("session")
                 .help("Folder with the files generated by all the stations."),
         )
+        .arg(
+            Arg::new("listen")
+                .short('l')
+                .long("listen")
+                .takes_value(true)
+                .value_name("ADDR")
+                .value_parser(StreamAddr::from_str)
+                .help(
+                    "Show the events streamed by services while they trace, \
+                     listening on '<host>:<port>' or 'unix:<path>'.",
+                ),
+        )
+        .arg(
+            Arg::new("session")
+                .short('s')
+                .long("session")
+                .takes_value(true)
+                .value_name("NAME")
+                .requires("node")
+                .help(
+                    "Run a capture session on the nodes, starting and stopping them at the \
+                     same time, and show the files in a folder with this name.",
+                ),
+        )
+        .arg(
+            Arg::new("node")
+                .short('n')
+                .long("node")
+                .takes_value(true)
+                .value_name("NODE")
+                .action(ArgAction::Append)
+                .value_parser(parse_node)
+                .requires("session")
+                .help(
+                    "Service running as a daemon to use in the session, like \
+                     'nodeA:mon0=<host>:<port>' or 'nodeA=unix:<path>' \
+                     (the capture interfaces are optional, separated by commas \
+                     like 'nodeA:mon0,mon1'). Can be repeated.",
+                ),
+        )
+        .arg(
+            Arg::new("delay")
+                .long("delay")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .default_value("2000")
+                .help("How far ahead the nodes are told to start and stop, in milliseconds."),
+        )
+        .arg(
+            Arg::new("duration")
+                .long("duration")
+                .takes_value(true)
+                .value_name("SECS")
+                .value_parser(value_parser!(u64))
+                .requires("session")
+                .help("Stop the session after this many seconds, instead of waiting for Enter."),
+        )
+        .arg(
+            Arg::new("wait")
+                .long("wait")
+                .takes_value(true)
+                .value_name("SECS")
+                .value_parser(value_parser!(u64))
+                .default_value("600")
+                .requires("session")
+                .help(
+                    "How long the nodes have to correlate their files after the session stops, \
+                     in seconds.",
+                ),
+        )
 }
 
-pub fn get_files(folder: &Path) -> Vec<PathBuf> {
+pub fn get_files(folder: &Path) -> Result<Vec<PathBuf>> {
     let mut files = vec![];
     for entry in folder
         .read_dir()
-        .expect("get folder reader")
-        .filter(Result::is_ok)
-        .map(|e| e.unwrap().path())
+        .map_err(|source| Error::Folder {
+            path: folder.to_path_buf(),
+            source,
+        })?
+        .flatten()
+        .map(|e| e.path())
     {
         // for each entry in the folder...
         if let Some(ext) = entry.extension() {
-            // save the ones that end in '.json' or '.pcap'
-            if ext == "json" || ext == "pcap" {
+            // save the ones that end in '.json', '.bin', '.pcap' or '.manifest'
+            // (events files can be in either format, it's detected when reading them)
+            if ext == "json" || ext == "bin" || ext == "pcap" || ext == "manifest" {
                 files.push(entry);
             }
         }
     }
 
-    assert!(
-        !files.is_empty(),
-        "no '.json' nor '.pcap' files found (extensions must be lowercase)"
-    );
+    if files.is_empty() {
+        return Err(Error::NoFiles(folder.to_path_buf()));
+    }
 
     files.sort();
-    files
+    Ok(files)
 }
 
 #[derive(Debug)]
@@ -50,91 +134,174 @@ pub struct Station {
     name: String,
     mac: Vec<MacAddr>,
     events: Vec<Option<Event>>,
-    pcap: PathBuf,
+    pcap: Option<PathBuf>,
+    manifest: Option<Manifest>,
+    snapshots: usize,
+    divergences: Vec<PathDivergence>,
+    neighbors: Vec<NeighborStats>,
 }
 
 impl Station {
-    pub fn stations_from_files(files: Vec<PathBuf>) -> Vec<Self> {
-        let mut stations = Vec::with_capacity(files.len() / 2);
-
-        // 'get_files()' already makes sure that 'files' is sorted,
-        // and also that all elements end with either '.json' or '.pcap'
-        // so we can assume that we can do 'f1 == f2' after getting the stems
-        // and we can also assume that 'f1' is '.json' and 'f2' is '.pcap'
-        let mut idx = 1;
-        while idx < files.len() {
-            let f1 = &files[idx - 1].file_stem();
-            let f2 = &files[idx].file_stem();
-
-            // there's a match, both files belong to a station
-            if f1.is_some() && f1 == f2 {
-                // store the events in a 'Vec'
-                let mut events = vec![];
-                File::open(&files[idx - 1])
-                    .expect("open events file")
-                    .read_to_end(&mut events)
-                    .expect("read contents of events file");
-                let events: Vec<Option<Event>> =
-                    from_slice(&events).expect("deserialize events file");
-
-                // get the MAC addresses in a 'Vec' as well
-                // (multiple MACs in case there was more than one interface)
-                let mut mac = vec![];
-                for event in events
-                    .iter()
-                    .filter(|e| e.is_some())
-                    .map(|e| e.as_ref().unwrap())
-                {
-                    if !mac.contains(event.mac()) {
-                        mac.push(event.mac().clone());
-                    }
-                }
+    pub fn stations_from_files(folder: &Path, files: Vec<PathBuf>) -> Result<Vec<Self>> {
+        let (manifests, files) = files
+            .into_iter()
+            .partition::<Vec<_>, _>(|f| f.extension().is_some_and(|ext| ext == "manifest"));
+        let mut stations = vec![];
 
-                // store the station in the final 'Vec'
-                stations.push(Self {
-                    name: f1.unwrap().to_string_lossy().to_string(),
-                    mac,
-                    events,
-                    pcap: files[idx].clone(),
-                });
-                idx += 1;
-
-                // there's only one more file in the list, meaning it doesn't
-                // have a match, and the loop will stop after this
-                if idx < files.len() {
-                    let next = &files[idx].file_stem();
-                    if idx == files.len() - 1 && next.is_some() {
-                        eprintln!(
-                            "File missing for station {}",
-                            next.unwrap().to_string_lossy()
-                        );
-                    }
+        // the manifest of a station says which files are its own
+        // (so the files it names aren't paired by their names afterwards)
+        let mut used = vec![];
+        for path in manifests {
+            let manifest = match Manifest::read(&path) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    eprintln!("Ignoring manifest {}: {err}", path.display());
+                    continue;
                 }
+            };
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            let events_path = dir.join(&manifest.files.events);
+            let pcap = dir.join(&manifest.files.capture);
+            if !events_path.exists() {
+                eprintln!("File missing for station {}", manifest.station.name);
+                continue;
+            }
+            used.push(events_path.clone());
+            used.push(pcap.clone());
+            stations.push(Self::from_events_file(
+                manifest.station.name.clone(),
+                &events_path,
+                Some(pcap).filter(|pcap| pcap.exists()),
+                Some(manifest),
+            )?);
+        }
+
+        // files without a manifest (from older versions of the service) are paired by name
+        let files = files
+            .into_iter()
+            .filter(|f| !used.contains(f))
+            .collect::<Vec<_>>();
+        if !files.is_empty() {
+            stations.extend(Self::pair_by_name(&files)?);
+        }
+
+        if stations.is_empty() {
+            return Err(Error::NoStations(folder.to_path_buf()));
+        }
+
+        Self::sort(&mut stations);
+        Ok(stations)
+    }
 
-            // no match, meaning 'f1' doesn't have a match
-            } else if f1.is_some() {
-                eprintln!("File missing for station {}", f1.unwrap().to_string_lossy());
+    fn pair_by_name(files: &[PathBuf]) -> Result<Vec<Self>> {
+        // the events files and the capture of each name
+        // ('get_files()' already makes sure that all of them end with '.json', '.bin' or '.pcap',
+        //  and the map keeps them sorted by name)
+        let mut by_stem = BTreeMap::<_, (Vec<&PathBuf>, Option<&PathBuf>)>::new();
+        for file in files {
+            let stem = match file.file_stem() {
+                Some(stem) => stem,
+                None => continue,
+            };
+            let (events, pcap) = by_stem.entry(stem).or_default();
+            if file.extension().is_some_and(|ext| ext == "pcap") {
+                *pcap = Some(file);
+            } else {
+                events.push(file);
+            }
+        }
 
-                // these were the two last files, and in that case, if 'f1'
-                // didn't have a match, 'f2' won't have one either
-                if idx == files.len() - 1 && f2.is_some() {
-                    eprintln!("File missing for station {}", f2.unwrap().to_string_lossy());
+        let mut stations = Vec::with_capacity(by_stem.len());
+        for (stem, (events, pcap)) in by_stem {
+            let name = stem.to_string_lossy().to_string();
+            match (events.as_slice(), pcap) {
+                ([events], Some(pcap)) => {
+                    stations.push(Self::from_events_file(
+                        name,
+                        events,
+                        Some(pcap.clone()),
+                        None,
+                    )?);
                 }
+                // (like a '.bin' next to the '.json' it was converted from)
+                ([_, _, ..], _) => {
+                    return Err(Error::Ambiguous {
+                        station: name,
+                        files: events.into_iter().cloned().collect(),
+                    });
+                }
+                _ => eprintln!("File missing for station {name}"),
             }
-            idx += 1;
         }
 
-        // loop never ran because there's only one file
-        if idx == 1 {
-            if let Some(f) = files[0].file_stem() {
-                eprintln!("File missing for station {}", f.to_string_lossy());
+        Ok(stations)
+    }
+
+    fn from_events_file(
+        name: String,
+        events_path: &Path,
+        pcap: Option<PathBuf>,
+        manifest: Option<Manifest>,
+    ) -> Result<Self> {
+        // store the events in a 'Vec'
+        // (either as a JSON array or NDJSON, from any version of the service)
+        let events = read_events_file(events_path)?
+            .events
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+
+        // get the MAC addresses in a 'Vec' as well
+        // (multiple MACs in case there was more than one interface,
+        //  the manifest also has the ones of interfaces without events)
+        let mut mac = vec![];
+        let traced = manifest
+            .iter()
+            .flat_map(|m| m.interfaces.iter().map(|i| &i.mac));
+        for addr in traced.chain(events.iter().flatten().map(Event::mac)) {
+            if !mac.contains(addr) {
+                mac.push(addr.clone());
             }
         }
 
-        assert!(!stations.is_empty(), "no stations found");
+        // check the events against the path tables of the kernel, if they were dumped
+        // (before the events are spread between the stations)
+        let files = manifest.as_ref().map(|m| &m.files);
+        let snapshots = read_station_file(
+            events_path,
+            files.and_then(|f| f.paths.as_deref()),
+            PathSnapshot::read_file,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Ignoring the path snapshots of station {name}: {err}");
+            vec![]
+        });
+        let divergences = replay_paths(
+            &events.iter().flatten().cloned().collect::<Vec<_>>(),
+            &snapshots,
+        );
+
+        // statistics of the neighbors over time, if they were sampled
+        let neighbors = read_station_file(
+            events_path,
+            files.and_then(|f| f.neighbors.as_deref()),
+            NeighborSample::read_file,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Ignoring the neighbors of station {name}: {err}");
+            vec![]
+        });
 
-        Self::sort(&mut stations);
-        stations
+        Ok(Self {
+            name,
+            mac,
+            events,
+            pcap,
+            manifest,
+            snapshots: snapshots.len(),
+            divergences,
+            neighbors: NeighborStats::from_samples(neighbors),
+        })
     }
 
     pub fn name(&self) -> &str {
@@ -149,19 +316,129 @@ impl Station {
         self.events.as_slice()
     }
 
-    pub fn pcap(&self) -> &Path {
-        &self.pcap
+    // (no snapshots means that the service didn't dump the path tables)
+    pub fn snapshots(&self) -> usize {
+        self.snapshots
+    }
+
+    pub fn divergences(&self) -> &[PathDivergence] {
+        &self.divergences
+    }
+
+    pub fn neighbors(&self) -> &[NeighborStats] {
+        &self.neighbors
+    }
+
+    // where and how the station was traced, from its manifest
+    pub fn description(&self) -> String {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return format!("{} (no manifest)", self.name),
+        };
+        let format_ts = |ts: SystemTime| {
+            OffsetDateTime::from(ts)
+                .format(&Rfc3339)
+                .expect("format timestamp")
+        };
+        let interfaces = manifest
+            .interfaces
+            .iter()
+            .map(|i| format!("{} [{}]", i.name, i.mac))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let capture = match (&manifest.capture.interface, manifest.capture.kernel) {
+            (_, true) => "kernel".to_string(),
+            (Some(interface), _) => interface.clone(),
+            (None, _) => "none".to_string(),
+        };
+        let mut kept = vec![];
+        if let Some(filter) = &manifest.capture.filter {
+            kept.push(format!("filter '{filter}'"));
+        }
+        if let Some(snaplen) = manifest.capture.snaplen {
+            kept.push(format!("{snaplen} bytes"));
+        }
+        if let Some(mesh_id) = &manifest.capture.mesh_id {
+            kept.push(format!("mesh {mesh_id}"));
+        }
+        let capture = if kept.is_empty() {
+            capture
+        } else {
+            format!("{capture} ({})", kept.join(", "))
+        };
+        let counters = &manifest.counters;
+        let mut sampled = String::new();
+        if self.snapshots > 0 {
+            sampled += &format!(
+                "\nPath snapshots: {}, divergences: {}",
+                self.snapshots,
+                self.divergences.len()
+            );
+        }
+        if counters.neighbor_samples > 0 {
+            sampled += &format!(
+                "\nNeighbors: {}, samples: {}",
+                self.neighbors.len(),
+                counters.neighbor_samples
+            );
+        }
+        format!(
+            "Host: {} (kernel {})\nService: {}\nInterfaces: {interfaces}\nCapture: {capture}\n\
+             Start: {}\nStop: {}\nEvents: {}, frames: {}, metrics: {}, drops: {}{sampled}",
+            manifest.station.hostname,
+            manifest.station.kernel,
+            manifest.producer,
+            format_ts(manifest.start),
+            manifest
+                .stop
+                .map_or_else(|| "unknown".to_string(), format_ts),
+            counters.events,
+            counters.frames,
+            counters.metrics,
+            counters.drops
+        )
+    }
+
+    // stations streamed live don't have a capture file
+    pub fn pcap(&self) -> Option<&Path> {
+        self.pcap.as_deref()
+    }
+
+    // add an event streamed by a service, creating its station if needed
+    // (the stations need to be sorted again afterwards)
+    pub fn add_live_event(stations: &mut Vec<Self>, name: String, event: Event) {
+        let station = match stations.iter().position(|s| s.name == name) {
+            Some(idx) => &mut stations[idx],
+            None => {
+                stations.push(Self {
+                    name,
+                    mac: vec![],
+                    events: vec![],
+                    pcap: None,
+                    manifest: None,
+                    snapshots: 0,
+                    divergences: vec![],
+                    neighbors: vec![],
+                });
+                stations.last_mut().expect("get new live station")
+            }
+        };
+        if !station.mac.contains(event.mac()) {
+            station.mac.push(event.mac().clone());
+        }
+        station.events.push(Some(event));
     }
 
     // sort events between all stations, placing 'None' where an event from
     // another station is in the order
-    fn sort(stations: &mut [Self]) {
+    pub fn sort(stations: &mut [Self]) {
         let mut events = vec![];
         for station in stations.iter_mut() {
             station
                 .events
                 .iter()
-                .for_each(|event| events.push((station.name.clone(), event.clone().unwrap())));
+                .flatten()
+                .for_each(|event| events.push((station.name.clone(), event.clone())));
             station.events.clear();
         }
 
@@ -182,3 +459,19 @@ impl Station {
         }
     }
 }
+
+// read a file that the manifest of a station names, from next to its events file
+// (nothing if the manifest doesn't name one)
+fn read_station_file<T>(
+    events_path: &Path,
+    name: Option<&str>,
+    read: impl Fn(&Path) -> mesh_analyzer::Result<Vec<T>>,
+) -> mesh_analyzer::Result<Vec<T>> {
+    match name {
+        Some(name) => {
+            let dir = events_path.parent().unwrap_or_else(|| Path::new("."));
+            read(&dir.join(name))
+        }
+        None => Ok(vec![]),
+    }
+}
@@ -0,0 +1,69 @@
+use mesh_analyzer::{read_events_file, write_events_file, EventFormat, ExitCode};
+
+use std::{path::PathBuf, process::exit, str::FromStr};
+
+use clap::{App, Arg, Command};
+
+fn setup_cli_arg_parser() -> App<'static> {
+    Command::new("Mesh Analyzer - Convert")
+        .author("Carlos Pinto <up201606191@up.pt>")
+        .arg(
+            Arg::new("INPUT")
+                .required(true)
+                .help("Events file to convert (in any format)."),
+        )
+        .arg(
+            Arg::new("OUTPUT").help(
+                "Where to write the converted file (INPUT with another extension if omitted).",
+            ),
+        )
+        .arg(
+            Arg::new("to")
+                .short('t')
+                .long("to")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .value_parser(EventFormat::from_str)
+                .default_value("json")
+                .help("Format to convert to, 'json' or 'binary'."),
+        )
+}
+
+fn main() {
+    let cli_args = setup_cli_arg_parser().get_matches();
+    let input = PathBuf::from(
+        cli_args
+            .get_one::<String>("INPUT")
+            .expect("get input from cli arguments"),
+    );
+    let format = *cli_args
+        .get_one::<EventFormat>("to")
+        .expect("get format from cli arguments");
+    let output = match cli_args.get_one::<String>("OUTPUT") {
+        Some(output) => PathBuf::from(output),
+        None => input.with_extension(format.extension()),
+    };
+    if output == input {
+        eprintln!(
+            "Can't continue because the converted file would overwrite the original one. \
+             Give it an OUTPUT."
+        );
+        exit(ExitCode::Usage.code());
+    }
+
+    // reading also brings the events up to the current schema
+    let events = read_events_file(&input).unwrap_or_else(|err| {
+        eprintln!("Can't read events: {err}");
+        exit(err.exit_code().code());
+    });
+    write_events_file(&output, &events, format).unwrap_or_else(|err| {
+        eprintln!("Can't write events: {err}");
+        exit(err.exit_code().code());
+    });
+    println!(
+        "Converted {} events from {} to {} ({format})",
+        events.events.len(),
+        input.display(),
+        output.display()
+    );
+}
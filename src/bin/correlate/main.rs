@@ -0,0 +1,141 @@
+use mesh_analyzer::{correlate_files, LinkRetries, Manifest, Radios, CORRELATION_WINDOW};
+
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
+
+use clap::{value_parser, App, Arg, Command};
+
+// how many links are shown with their retries
+const MAX_LINKS: usize = 10;
+
+fn setup_cli_arg_parser() -> App<'static> {
+    Command::new("Mesh Analyzer - Correlate")
+        .author("Carlos Pinto <up201606191@up.pt>")
+        .arg(
+            Arg::new("EVENTS")
+                .required(true)
+                .help("Events file of a station (in any format)."),
+        )
+        .arg(
+            Arg::new("CAPTURE")
+                .required(true)
+                .help("Packet capture of the same station."),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Where to write the correlated events (EVENTS is rewritten if omitted)."),
+        )
+        .arg(
+            Arg::new("manifest")
+                .short('m')
+                .long("manifest")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Manifest of the station, for the radio of each capture interface \
+                     (the one next to EVENTS if omitted).",
+                ),
+        )
+        .arg(
+            Arg::new("window")
+                .short('w')
+                .long("window")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .help(
+                    "How far apart in milliseconds a packet and an event can be \
+                     and still be related (5000 if omitted).",
+                ),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Print every packet that gets associated with an event."),
+        )
+}
+
+fn main() {
+    let cli_args = setup_cli_arg_parser().get_matches();
+    let events = PathBuf::from(
+        cli_args
+            .get_one::<String>("EVENTS")
+            .expect("get events file from cli arguments"),
+    );
+    let capture = PathBuf::from(
+        cli_args
+            .get_one::<String>("CAPTURE")
+            .expect("get capture file from cli arguments"),
+    );
+    let output = cli_args
+        .get_one::<String>("output")
+        .map_or_else(|| events.clone(), PathBuf::from);
+    let window = cli_args
+        .get_one::<u64>("window")
+        .map_or(CORRELATION_WINDOW, |ms| Duration::from_millis(*ms));
+
+    // with more than one capture interface, events only match packets from their own radio
+    let manifest = cli_args.get_one::<String>("manifest").map_or_else(
+        || {
+            let dir = events.parent().unwrap_or_else(|| Path::new("."));
+            events
+                .file_stem()
+                .map(|stem| Manifest::path(dir, &stem.to_string_lossy()))
+                .filter(|path| path.exists())
+        },
+        |path| Some(PathBuf::from(path)),
+    );
+    let radios = match &manifest {
+        Some(path) => match Manifest::read(path) {
+            Ok(manifest) => Radios::from_manifest(&manifest),
+            Err(err) => {
+                eprintln!("Can't read manifest: {err}");
+                exit(err.exit_code().code());
+            }
+        },
+        None => Radios::default(),
+    };
+
+    // the packets each event had before are replaced,
+    // and the events are brought up to the current schema
+    let verbose = cli_args.contains_id("verbose");
+    let correlation = correlate_files(&events, &capture, &output, &radios, window, verbose)
+        .unwrap_or_else(|err| {
+            eprintln!(
+                "Can't correlate {} with {}: {err}",
+                events.display(),
+                capture.display()
+            );
+            exit(err.exit_code().code());
+        });
+    let stats = &correlation.stats;
+    println!("Matched: {}", stats.matched);
+    println!("Unmatched: {}", stats.unmatched);
+    println!("Ambiguous: {}", stats.ambiguous);
+    println!("Retries: {}", stats.retries);
+    println!(
+        "Packets: {} ({} skipped because they aren't 802.11 or their header wasn't captured whole)",
+        stats.packets, stats.skipped
+    );
+
+    // the links with the most retries are first
+    for link in correlation.links.iter().take(MAX_LINKS) {
+        println!(
+            "{} -> {}: {} retries in {} frames",
+            link.transmitter, link.receiver, link.retries, link.frames
+        );
+    }
+    println!(
+        "Wrote events to {} and retries to {}",
+        output.display(),
+        LinkRetries::path(&output).display()
+    );
+}
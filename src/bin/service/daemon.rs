@@ -0,0 +1,441 @@
+use crate::{
+    error,
+    exporter::Stats,
+    output::{OutputConfig, Outputs, Sink},
+    util::correlate,
+};
+
+use mesh_analyzer::{
+    read_message, write_message, Connection, Event, Frame, Message, Metric, NeighborSample,
+    PathSnapshot, RawEvent, Request, Response, SessionState, SessionStatus, StreamListener,
+};
+
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, File},
+    io::copy,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+// a capture session started through the control socket
+// (its files go to a folder with its name)
+struct Session {
+    dir: PathBuf,
+    state: SessionState,
+    outputs: Option<Outputs>,
+    stop: Arc<AtomicBool>,
+    // its files couldn't be written, so it isn't traced anymore until it's stopped
+    failed: bool,
+    events: usize,
+    drops_start: u64,
+    drops: u64,
+    files: Vec<PathBuf>,
+}
+
+impl Session {
+    fn status(&self, name: &str, drops: u64) -> SessionStatus {
+        let running = self.state == SessionState::Running;
+        SessionStatus {
+            name: name.to_string(),
+            state: self.state,
+            events: match &self.outputs {
+                Some(outputs) => outputs.events(),
+                None => self.events,
+            },
+            drops: if running {
+                drops - self.drops_start
+            } else {
+                self.drops
+            },
+            files: self.files.clone(),
+        }
+    }
+
+    // stop the captures of the session and take its outputs to be finished
+    fn end(&mut self, drops: u64) -> Outputs {
+        let outputs = self.outputs.take().expect("running session has outputs");
+        self.stop.store(true, Ordering::Relaxed);
+        self.events = outputs.events();
+        self.drops = drops - self.drops_start;
+        self.state = SessionState::Correlating;
+        outputs
+    }
+}
+
+// service that keeps the bpf program loaded and traces for any number of sessions
+pub struct Daemon {
+    config: OutputConfig,
+    stream: Option<Sender<Message>>,
+    stats: Arc<Stats>,
+    sessions: BTreeMap<String, Session>,
+    drops: u64,
+    verbose: bool,
+}
+
+impl Daemon {
+    pub fn new(
+        config: OutputConfig,
+        stream: Option<Sender<Message>>,
+        stats: Arc<Stats>,
+        verbose: bool,
+    ) -> Self {
+        Self {
+            config,
+            stream,
+            stats,
+            sessions: BTreeMap::new(),
+            drops: 0,
+            verbose,
+        }
+    }
+
+    // keep the drops counter of the kernel, so that each session knows its own drops
+    #[inline]
+    pub fn set_drops(&mut self, drops: u64) {
+        self.drops = drops;
+    }
+
+    fn start(
+        &mut self,
+        name: String,
+        station: Option<String>,
+        interface: Option<String>,
+    ) -> Result<(), String> {
+        if !valid_name(&name) {
+            return Err(format!("'{name}' isn't a valid session name"));
+        }
+        let station = station.unwrap_or_else(|| name.clone());
+        if !valid_name(&station) {
+            return Err(format!("'{station}' isn't a valid station name"));
+        }
+        if self
+            .sessions
+            .get(&name)
+            .map_or(false, |s| s.state != SessionState::Finished)
+        {
+            return Err(format!("session {name} is still going"));
+        }
+        if interface.is_none() && !self.config.kernel_capture {
+            return Err("a session needs an interface for packet capture, \
+                 unless the daemon captures frames from the kernel"
+                .to_string());
+        }
+
+        let dir = PathBuf::from(&name);
+        if Outputs::exists(&dir, &station, &self.config) && !self.config.force {
+            return Err(format!(
+                "the files of session {name} already exist \
+                 (start the daemon with --force to overwrite them)"
+            ));
+        }
+        create_dir_all(&dir).map_err(|err| format!("can't create folder {name}: {err}"))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut outputs = Outputs::new(
+            dir.clone(),
+            station,
+            self.config.clone(),
+            self.stream.clone(),
+            self.stats.clone(),
+        )
+        .map_err(|err| err.to_string())?;
+        // (several devices are separated by commas)
+        let interfaces = interface
+            .iter()
+            .flat_map(|interface| interface.split(','))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        outputs
+            .start_captures(&stop, &interfaces)
+            .map_err(|err| err.to_string())?;
+        self.sessions.insert(
+            name,
+            Session {
+                dir,
+                state: SessionState::Running,
+                outputs: Some(outputs),
+                stop,
+                failed: false,
+                events: 0,
+                drops_start: self.drops,
+                drops: 0,
+                files: vec![],
+            },
+        );
+        Ok(())
+    }
+
+    // stop tracing for a session, its files are closed and correlated in the background
+    fn stop(daemon: &Arc<Mutex<Self>>, name: &str) -> Result<(), String> {
+        let (outputs, drops, window, stats, verbose) = {
+            let mut daemon = daemon.lock().expect("lock daemon to stop session");
+            let verbose = daemon.verbose;
+            let window = daemon.config.window;
+            let drops = daemon.drops;
+            let stats = daemon.stats.clone();
+            let session = daemon
+                .sessions
+                .get_mut(name)
+                .filter(|s| s.state == SessionState::Running)
+                .ok_or_else(|| format!("session {name} isn't running"))?;
+            let outputs = session.end(drops);
+            (outputs, session.drops, window, stats, verbose)
+        };
+
+        let daemon = daemon.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            let files = finish_outputs(outputs, drops, window, &stats, verbose);
+            let mut daemon = daemon.lock().expect("lock daemon to finish session");
+            if let Some(session) = daemon.sessions.get_mut(&name) {
+                session.state = SessionState::Finished;
+                session.files = files;
+            }
+        });
+        Ok(())
+    }
+
+    // stop every running session and wait for their files
+    pub fn stop_all(&mut self) {
+        let drops = self.drops;
+        let verbose = self.verbose;
+        for session in self.sessions.values_mut() {
+            if session.state == SessionState::Running {
+                let outputs = session.end(drops);
+                session.files = finish_outputs(
+                    outputs,
+                    session.drops,
+                    self.config.window,
+                    &self.stats,
+                    verbose,
+                );
+                session.state = SessionState::Finished;
+            }
+        }
+    }
+
+    fn status(&self, name: Option<&str>) -> Result<Vec<SessionStatus>, String> {
+        match name {
+            Some(name) => self
+                .sessions
+                .get(name)
+                .map(|session| vec![session.status(name, self.drops)])
+                .ok_or_else(|| format!("session {name} doesn't exist")),
+            None => Ok(self
+                .sessions
+                .iter()
+                .map(|(name, session)| session.status(name, self.drops))
+                .collect()),
+        }
+    }
+
+    // path of a file of a finished session, from its file name
+    fn file(&self, name: &str, file: &str) -> Result<PathBuf, String> {
+        let session = self
+            .sessions
+            .get(name)
+            .ok_or_else(|| format!("session {name} doesn't exist"))?;
+        if session.state != SessionState::Finished {
+            return Err(format!("session {name} isn't finished yet"));
+        }
+        session
+            .files
+            .iter()
+            .find(|path| path.file_name().map_or(false, |f| f == file))
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "session {name} doesn't have file {file} (in {})",
+                    session.dir.display()
+                )
+            })
+    }
+
+    // hand something from the kernel to every running session
+    // (a session whose files can't be written is left out, but the daemon and the others go on)
+    fn write_running(&mut self, mut write: impl FnMut(&mut Outputs) -> error::Result<()>) {
+        for (name, session) in &mut self.sessions {
+            if session.failed {
+                continue;
+            }
+            if let Some(outputs) = &mut session.outputs {
+                if let Err(err) = write(outputs) {
+                    eprintln!("Stopped tracing session {name}: {err}");
+                    session.failed = true;
+                }
+            }
+        }
+    }
+}
+
+// everything from the kernel goes to all the running sessions
+impl Sink for Daemon {
+    fn write_event(&mut self, raw: RawEvent) -> error::Result<Option<Event>> {
+        let mut event = None;
+        self.write_running(|outputs| {
+            event = outputs.write_event(raw)?.or(event.take());
+            Ok(())
+        });
+        Ok(event)
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> error::Result<()> {
+        self.write_running(|outputs| outputs.write_frame(frame));
+        Ok(())
+    }
+
+    fn push_metric(&mut self, metric: Metric) -> error::Result<()> {
+        self.write_running(|outputs| outputs.push_metric(metric.clone()));
+        Ok(())
+    }
+
+    fn push_paths(&mut self, snapshot: PathSnapshot) -> error::Result<()> {
+        self.write_running(|outputs| outputs.push_paths(snapshot.clone()));
+        Ok(())
+    }
+
+    fn push_neighbor(&mut self, sample: NeighborSample) -> error::Result<()> {
+        self.write_running(|outputs| outputs.push_neighbor(sample.clone()));
+        Ok(())
+    }
+}
+
+// close and correlate the files of a session
+// (if they can't be finished, the session is left without files)
+fn finish_outputs(
+    outputs: Outputs,
+    drops: u64,
+    window: Duration,
+    stats: &Stats,
+    verbose: bool,
+) -> Vec<PathBuf> {
+    let outputs = match outputs.finish(drops) {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            eprintln!("Can't finish the files of a session: {err}");
+            return vec![];
+        }
+    };
+    stats.queue_correlations(outputs.len());
+    let mut files = vec![];
+    for output in &outputs {
+        if let Err(err) = correlate(output, window, verbose) {
+            eprintln!(
+                "Can't correlate station {}: {err}",
+                output.events_path().display()
+            );
+        }
+        stats.correlated();
+        files.extend(output.files());
+    }
+    files
+}
+
+// sessions are folders and stations are files, so their name must be a single plain path component
+fn valid_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+// wait for an instant of the wall clock (nothing to wait for if it already passed)
+fn wait_until(at: Option<SystemTime>) {
+    if let Some(wait) = at.and_then(|at| at.duration_since(SystemTime::now()).ok()) {
+        thread::sleep(wait);
+    }
+}
+
+// accept control connections, each in its own thread
+pub fn start_control(listener: StreamListener, daemon: Arc<Mutex<Daemon>>) {
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(connection) => {
+                let daemon = daemon.clone();
+                thread::spawn(move || handle_control(connection, &daemon));
+            }
+            Err(err) => eprintln!("Can't accept control connection: {err}"),
+        }
+    });
+}
+
+// answer the requests of a connection until it's closed
+fn handle_control(mut connection: Connection, daemon: &Arc<Mutex<Daemon>>) {
+    if let Err(err) = connection.handshake() {
+        eprintln!("Dropped control connection: {err}");
+        return;
+    }
+    loop {
+        let request = match read_message::<_, Request>(&mut connection) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(err) => {
+                eprintln!("Dropped control connection: {err}");
+                return;
+            }
+        };
+
+        let mut file = None;
+        let response = match request {
+            Request::Start {
+                session,
+                station,
+                interface,
+                at,
+            } => {
+                wait_until(at);
+                daemon
+                    .lock()
+                    .expect("lock daemon to start session")
+                    .start(session, station, interface)
+                    .map(|_| Response::Started)
+            }
+            Request::Stop { session, at } => {
+                wait_until(at);
+                Daemon::stop(daemon, &session).map(|_| Response::Stopped)
+            }
+            Request::Status { session } => daemon
+                .lock()
+                .expect("lock daemon to get status")
+                .status(session.as_deref())
+                .map(Response::Status),
+            Request::Fetch {
+                session,
+                file: name,
+            } => daemon
+                .lock()
+                .expect("lock daemon to fetch file")
+                .file(&session, &name)
+                .and_then(|path| {
+                    let opened = File::open(&path)
+                        .map_err(|err| format!("can't open {}: {err}", path.display()))?;
+                    let len = opened
+                        .metadata()
+                        .map_err(|err| format!("can't read {}: {err}", path.display()))?
+                        .len();
+                    file = Some(opened);
+                    Ok(Response::File { name, len })
+                }),
+        };
+        let response = response.unwrap_or_else(Response::Error);
+
+        if let Err(err) = write_message(&mut connection, &response) {
+            eprintln!("Dropped control connection: {err}");
+            return;
+        }
+        if let Some(mut file) = file {
+            if let Err(err) = copy(&mut file, &mut connection) {
+                eprintln!("Dropped control connection while sending a file: {err}");
+                return;
+            }
+        }
+    }
+}
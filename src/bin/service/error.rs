@@ -0,0 +1,239 @@
+use crate::setup::ChannelWidth;
+
+use mesh_analyzer::{ExitCode, StreamAddr};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+};
+
+use libc::{EACCES, EOPNOTSUPP, EPERM};
+
+// where the kernel has the type information that the bpf program is relocated with
+pub const BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+// what can stop the service
+#[derive(Debug)]
+pub enum Error {
+    // loading the bpf program needs root
+    NotRoot,
+    // the kernel wasn't built with BTF
+    NoBtf,
+    // the bpf program couldn't be opened, loaded or attached
+    Bpf {
+        stage: &'static str,
+        source: libbpf_rs::Error,
+    },
+    // the ringbuffer couldn't be created or stopped working
+    Ringbuffer(libbpf_rs::Error),
+    // some stations don't have an interface for packet capture
+    NoCapture,
+    // the files of a station are already there
+    Overwrite(String),
+    // a network namespace that doesn't exist
+    Netns {
+        netns: String,
+        source: io::Error,
+    },
+    // a monitor interface couldn't be captured from
+    Capture {
+        interface: String,
+        source: pcap::Error,
+    },
+    // the capture filter isn't valid
+    Filter {
+        filter: String,
+        source: pcap::Error,
+    },
+    // the control socket couldn't be opened
+    Listen {
+        addr: StreamAddr,
+        source: io::Error,
+    },
+    // the port of the counters couldn't be opened
+    Exporter {
+        port: u16,
+        source: io::Error,
+    },
+    // a file of a station couldn't be written or correlated
+    File(mesh_analyzer::Error),
+    // the interface to set up the mesh on doesn't exist
+    NoInterface(String),
+    // the monitor interface is left over from before
+    InterfaceExists(String),
+    // the radio can't be a mesh point or a monitor
+    Unsupported {
+        iface: String,
+        mode: &'static str,
+    },
+    // the channel doesn't have the width that was asked for
+    Channel {
+        freq: u32,
+        width: ChannelWidth,
+    },
+    // nl80211 can't be used, to sample the paths or the neighbors of the kernel
+    Nl80211(io::Error),
+    // the namespace to sample doesn't have a name to enter it by
+    SampleNetns(u32),
+    // a step of setting up the mesh failed
+    Setup {
+        step: &'static str,
+        iface: String,
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // a file of a station couldn't be created or written
+    pub fn file(path: &Path, err: io::Error) -> Self {
+        Self::File(mesh_analyzer::Error::file(path, err))
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Self::NotRoot => ExitCode::NoPermission,
+            Self::Bpf {
+                source: libbpf_rs::Error::System(EPERM | EACCES),
+                ..
+            } => ExitCode::NoPermission,
+            Self::NoBtf | Self::Bpf { .. } => ExitCode::Unavailable,
+            Self::Ringbuffer(_) => ExitCode::Software,
+            Self::NoCapture => ExitCode::Usage,
+            Self::Overwrite(_) => ExitCode::CantCreate,
+            Self::Netns { .. } => ExitCode::NoInput,
+            Self::Capture { .. } => ExitCode::Unavailable,
+            Self::Filter { .. } => ExitCode::Usage,
+            Self::Listen { .. } | Self::Exporter { .. } => ExitCode::Io,
+            Self::File(err) => err.exit_code(),
+            Self::NoInterface(_) => ExitCode::NoInput,
+            Self::InterfaceExists(_) => ExitCode::CantCreate,
+            Self::Unsupported { .. } => ExitCode::Unavailable,
+            Self::Channel { .. } => ExitCode::Usage,
+            Self::Nl80211(_) => ExitCode::Unavailable,
+            Self::SampleNetns(_) => ExitCode::Usage,
+            Self::Setup { source, .. } => match source.raw_os_error() {
+                Some(EPERM | EACCES) => ExitCode::NoPermission,
+                Some(EOPNOTSUPP) => ExitCode::Unavailable,
+                _ => ExitCode::Io,
+            },
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotRoot => write!(
+                f,
+                "the eBPF program can only be loaded by root (run the service with sudo)"
+            ),
+            Self::NoBtf => write!(
+                f,
+                "the kernel doesn't have BTF ({BTF_PATH} is missing), which the eBPF program \
+                 needs (use a kernel built with CONFIG_DEBUG_INFO_BTF=y)"
+            ),
+            Self::Bpf {
+                stage,
+                source: libbpf_rs::Error::System(EPERM | EACCES),
+            } => write!(
+                f,
+                "not allowed to {stage} the eBPF program (run the service with sudo, \
+                 or check if lockdown or a security module forbids eBPF)"
+            ),
+            Self::Bpf { stage, source } => write!(
+                f,
+                "can't {stage} the eBPF program: {source} (the kernel might be too old, \
+                 or not have mac80211 loaded)"
+            ),
+            Self::Ringbuffer(err) => write!(f, "the ring buffer failed: {err}"),
+            Self::NoCapture => write!(
+                f,
+                "some interfaces don't have an interface for packet capture \
+                 (use INTERFACE, --kernel-capture or 'IFACE=STATION:CAPTURE')"
+            ),
+            Self::Overwrite(station) => write!(
+                f,
+                "the files of station {station} would overwrite existing ones \
+                 (use --force to overwrite them)"
+            ),
+            Self::Netns { netns, source } => write!(
+                f,
+                "network namespace {netns} doesn't exist: {source} \
+                 (use a name from 'ip netns list' or an inode)"
+            ),
+            Self::Capture { interface, source } => write!(
+                f,
+                "can't capture packets on {interface}: {source} \
+                 (it should be a monitor interface that's up)"
+            ),
+            Self::Filter { filter, source } => write!(
+                f,
+                "capture filter '{filter}' isn't valid: {source} (see 'man pcap-filter')"
+            ),
+            Self::Listen { addr, source } => write!(
+                f,
+                "can't listen for control connections on {addr}: {source} \
+                 (is another service already using it?)"
+            ),
+            Self::Exporter { port, source } => write!(
+                f,
+                "can't serve the counters on localhost:{port}: {source} \
+                 (is another service already using the port?)"
+            ),
+            Self::File(err) => write!(f, "{err}"),
+            Self::NoInterface(iface) => write!(
+                f,
+                "interface {iface} doesn't exist (see the wireless ones with 'iw dev')"
+            ),
+            Self::InterfaceExists(iface) => write!(
+                f,
+                "interface {iface} already exists, maybe from a service that was killed \
+                 (remove it with 'iw dev {iface} del')"
+            ),
+            Self::Unsupported { iface, mode } => write!(
+                f,
+                "the radio of {iface} can't be a {mode} interface \
+                 (see the supported modes with 'iw phy')"
+            ),
+            Self::Channel { freq, width } => write!(
+                f,
+                "{freq} MHz doesn't have a channel {width} MHz wide \
+                 (80 and 160 are only at 5 GHz)"
+            ),
+            Self::Nl80211(source) => write!(
+                f,
+                "can't sample the mesh through nl80211: {source} (is cfg80211 loaded?)"
+            ),
+            Self::SampleNetns(netns) => write!(
+                f,
+                "can't sample the mesh in network namespace {netns}, only the one of the service \
+                 and the ones named by 'ip netns' can be entered \
+                 (name it with 'ip netns attach NAME PID')"
+            ),
+            Self::Setup {
+                step,
+                iface,
+                source,
+            } if source.raw_os_error() == Some(EOPNOTSUPP) => write!(
+                f,
+                "can't {step} {iface}: {source} (the driver doesn't support it)"
+            ),
+            Self::Setup {
+                step,
+                iface,
+                source,
+            } => write!(f, "can't {step} {iface}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mesh_analyzer::Error> for Error {
+    fn from(err: mesh_analyzer::Error) -> Self {
+        Self::File(err)
+    }
+}
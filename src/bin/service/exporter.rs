@@ -0,0 +1,190 @@
+use mesh_analyzer::RawEvent;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::metadata,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+// how long a scrape can take to send its request
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// counters of the service, so that long traces can be followed without opening their files
+#[derive(Default)]
+pub struct Stats {
+    // by action and interface
+    events: Mutex<BTreeMap<(&'static str, String), u64>>,
+    // by interface (the radio for frames from the kernel)
+    packets: Mutex<BTreeMap<String, u64>>,
+    drops: AtomicU64,
+    // stations that were finished but aren't correlated yet
+    backlog: AtomicUsize,
+    // every file that was opened for writing
+    files: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl Stats {
+    pub fn count_event(&self, raw: &RawEvent) {
+        let iface = String::from_utf8_lossy(&raw.iface)
+            .trim_matches('\0')
+            .to_owned();
+        *self
+            .events
+            .lock()
+            .expect("lock event counters")
+            .entry((raw.action.name(), iface))
+            .or_default() += 1;
+    }
+
+    pub fn count_packet(&self, interface: &str) {
+        let mut packets = self.packets.lock().expect("lock packet counters");
+        match packets.get_mut(interface) {
+            Some(count) => *count += 1,
+            None => {
+                packets.insert(interface.to_string(), 1);
+            }
+        }
+    }
+
+    // (the kernel keeps the count, this is the last one that was read)
+    #[inline]
+    pub fn set_drops(&self, drops: u64) {
+        self.drops.store(drops, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn queue_correlations(&self, stations: usize) {
+        self.backlog.fetch_add(stations, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn correlated(&self) {
+        self.backlog.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add_files(&self, files: impl IntoIterator<Item = PathBuf>) {
+        self.files.lock().expect("lock file list").extend(files);
+    }
+
+    // the counters in the text format of prometheus
+    // (the sizes of the files are read now, leaving out the ones that aren't there yet)
+    fn render(&self) -> String {
+        let mut out = help(
+            "mesh_analyzer_events_total",
+            "counter",
+            "Events from the kernel, by action and interface.",
+        );
+        for ((action, iface), count) in self.events.lock().expect("lock event counters").iter() {
+            out += &format!(
+                "mesh_analyzer_events_total{{action=\"{action}\",interface=\"{}\"}} {count}\n",
+                escape(iface)
+            );
+        }
+
+        out += &help(
+            "mesh_analyzer_ringbuf_drops_total",
+            "counter",
+            "Events and frames the kernel dropped because the ring buffers were full.",
+        );
+        out += &format!(
+            "mesh_analyzer_ringbuf_drops_total {}\n",
+            self.drops.load(Ordering::Relaxed)
+        );
+
+        out += &help(
+            "mesh_analyzer_packets_captured_total",
+            "counter",
+            "Packets written to the capture files, by interface.",
+        );
+        for (interface, count) in self.packets.lock().expect("lock packet counters").iter() {
+            out += &format!(
+                "mesh_analyzer_packets_captured_total{{interface=\"{}\"}} {count}\n",
+                escape(interface)
+            );
+        }
+
+        out += &help(
+            "mesh_analyzer_correlation_backlog",
+            "gauge",
+            "Stations waiting for their events to be correlated.",
+        );
+        out += &format!(
+            "mesh_analyzer_correlation_backlog {}\n",
+            self.backlog.load(Ordering::Relaxed)
+        );
+
+        out += &help(
+            "mesh_analyzer_file_size_bytes",
+            "gauge",
+            "Size of the files written by the service.",
+        );
+        for file in self.files.lock().expect("lock file list").iter() {
+            if let Ok(meta) = metadata(file) {
+                out += &format!(
+                    "mesh_analyzer_file_size_bytes{{file=\"{}\"}} {}\n",
+                    escape(&file.to_string_lossy()),
+                    meta.len()
+                );
+            }
+        }
+        out
+    }
+}
+
+fn help(name: &str, kind: &str, help: &str) -> String {
+    format!("# HELP {name} {help}\n# TYPE {name} {kind}\n")
+}
+
+// (label values are quoted, so quotes, backslashes and newlines are escaped)
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// serve the counters over http, one scrape at a time
+pub fn start_exporter(listener: TcpListener, stats: Arc<Stats>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let served = stream.and_then(|stream| handle_scrape(stream, &stats));
+            if let Err(err) = served {
+                eprintln!("Can't serve the counters: {err}");
+            }
+        }
+    });
+}
+
+// answer a single request and close the connection
+// (only the request line matters, the headers are skipped)
+fn handle_scrape(mut stream: TcpStream, stats: &Stats) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/" | "/metrics" => ("200 OK", stats.render()),
+        _ => ("404 Not Found", "only /metrics is served\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
@@ -1,29 +1,49 @@
+mod daemon;
+mod error;
+mod exporter;
+mod netlink;
+mod nl80211;
+mod output;
+mod setup;
 mod util;
 
-use crate::util::{
-    create_ringbuffer, event_matches_packet, get_collected_data, initialize_events_file,
-    load_bpf_program, setup_cli_arg_parser, start_packet_capture,
+use crate::{
+    daemon::{start_control, Daemon},
+    error::{Error, Result},
+    exporter::{start_exporter, Stats},
+    output::{OutputConfig, Outputs, Split},
+    setup::{ChannelWidth, MeshConfig, MeshSetup},
+    util::{
+        check_requirements, correlate, create_ringbuffer, load_bpf_program, netns_inode,
+        read_drops, setup_cli_arg_parser, start_stream, CaptureOptions, Failure, IfaceStation,
+        Samplers,
+    },
 };
 
 use std::{
-    fs::File,
-    io::Write,
-    path::Path,
+    net::{Ipv4Addr, TcpListener},
+    path::PathBuf,
     process::exit,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
 
-use libbpf_rs::Error::System;
+use libbpf_rs::{Error::System, RingBuffer};
 use libc::EINTR;
-use pcap::{Error::NoMorePackets, Linktype};
-use serde_json::to_string_pretty;
+use mesh_analyzer::{EventFormat, StreamAddr, CORRELATION_WINDOW};
 use signal_hook::{consts::TERM_SIGNALS, flag::register};
 
 fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err}");
+        exit(err.exit_code().code());
+    }
+}
+
+fn run() -> Result<()> {
     // handle termination signals
     let stop = Arc::new(AtomicBool::new(false));
     for signal in TERM_SIGNALS {
@@ -32,105 +52,273 @@ fn main() {
 
     // get cli arguments
     let cli_args = setup_cli_arg_parser().get_matches();
+    let mut interfaces = cli_args
+        .get_many::<String>("INTERFACE")
+        .map_or_else(Vec::new, |interfaces| interfaces.cloned().collect());
+    let netns = cli_args
+        .get_one::<String>("netns")
+        .map(|netns| netns_inode(netns))
+        .transpose()?;
+    let split = if let Some(stations) = cli_args.get_one::<Vec<IfaceStation>>("split-iface") {
+        Split::Iface(stations.clone())
+    } else if cli_args.contains_id("split-netns") {
+        Split::Netns
+    } else {
+        Split::Nothing
+    };
+    let kernel_capture = cli_args.contains_id("kernel-capture");
+
+    // every station needs a capture, either its own, a shared one or one from the kernel
+    // (--setup shares the capture of the monitor interface it creates later)
+    if let Split::Iface(stations) = &split {
+        if interfaces.is_empty()
+            && !cli_args.contains_id("setup")
+            && !kernel_capture
+            && stations.iter().any(|sta| sta.capture.is_none())
+        {
+            return Err(Error::NoCapture);
+        }
+    }
+
+    // check if the airtime link metric should be sampled
+    let metric_interval = cli_args
+        .get_one::<u64>("metrics")
+        .map(|ms| Duration::from_millis(*ms));
+
+    // check if the path tables of the kernel should be dumped, and the neighbors sampled
+    // (nl80211 is opened right away, so that it fails before tracing)
+    let path_interval = cli_args
+        .get_one::<u64>("paths")
+        .map(|ms| Duration::from_millis(*ms));
+    let neighbor_interval = cli_args
+        .get_one::<u64>("neighbors")
+        .map(|ms| Duration::from_millis(*ms));
+    let samplers = Samplers::open(path_interval, neighbor_interval, netns)?;
+
+    let config = OutputConfig {
+        split,
+        netns,
+        force: cli_args.contains_id("force"),
+        kernel_capture,
+        metric_interval,
+        path_interval,
+        neighbor_interval,
+        format: *cli_args
+            .get_one::<EventFormat>("format")
+            .expect("get format from cli arguments"),
+        capture: CaptureOptions::new(
+            cli_args.get_one::<String>("filter").map(String::as_str),
+            cli_args.get_one::<String>("bssid").map(String::as_str),
+            cli_args.get_one::<u32>("snaplen").copied(),
+            cli_args.get_one::<String>("mesh-id").cloned(),
+        ),
+        window: cli_args
+            .get_one::<u64>("window")
+            .map_or(CORRELATION_WINDOW, |ms| Duration::from_millis(*ms)),
+    };
+
+    // check verbose
+    let verbose = cli_args.contains_id("verbose");
+
+    // live streaming of the events
+    let (stream, stream_thread) = match cli_args.get_one::<StreamAddr>("stream") {
+        Some(addr) => {
+            let (sender, handle) = start_stream(addr.clone());
+            (Some(sender), Some(handle))
+        }
+        None => (None, None),
+    };
+
+    // counters for monitoring, served only on localhost
+    let stats = Arc::new(Stats::default());
+    if let Some(port) = cli_args.get_one::<u16>("exporter") {
+        let listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, *port)).map_err(|source| Error::Exporter {
+                port: *port,
+                source,
+            })?;
+        start_exporter(listener, stats.clone());
+    }
+
+    // bpf initialization
+    check_requirements()?;
+    let skeleton = load_bpf_program(
+        cli_args.contains_id("debug"),
+        metric_interval,
+        kernel_capture,
+    )?;
+
+    // as a daemon, the sessions are started and stopped through the control socket
+    if let Some(addr) = cli_args.get_one::<StreamAddr>("daemon") {
+        let listener = addr.listen().map_err(|source| Error::Listen {
+            addr: addr.clone(),
+            source,
+        })?;
+        let daemon = Arc::new(Mutex::new(Daemon::new(
+            config,
+            stream,
+            stats.clone(),
+            verbose,
+        )));
+        // (the daemon keeps going if the files of a session fail, so this never stops it)
+        let failure = Failure::new(&stop);
+        let ringbuf = create_ringbuffer(
+            &skeleton,
+            daemon.clone(),
+            stats.clone(),
+            failure.clone(),
+            kernel_capture,
+            metric_interval.is_some(),
+            verbose,
+        )?;
+        start_control(listener, daemon.clone());
+        let samplers = samplers.start(daemon.clone(), &stop, &failure, verbose);
+
+        println!("Ready! (control socket at {addr})");
+        let polled = poll_until_stopped(&ringbuf, &stop, || {
+            let drops = read_drops(&skeleton);
+            stats.set_drops(drops);
+            daemon
+                .lock()
+                .expect("lock daemon to update drops")
+                .set_drops(drops);
+        });
+        println!("\rStopping...");
+
+        // the sessions that are still going get finished right away
+        // (the samplers also stop if polling failed, and are waited for)
+        stop.store(true, Ordering::Relaxed);
+        drop(ringbuf);
+        for handle in samplers {
+            handle.join().expect("join sampler thread");
+        }
+        daemon
+            .lock()
+            .expect("lock daemon to stop sessions")
+            .stop_all();
+        polled?;
+        println!("Done!");
+        return Ok(());
+    }
+
     let station = cli_args
         .get_one::<String>("STATION")
         .expect("get station name from cli arguments")
         .clone();
-    let interface = cli_args
-        .get_one::<String>("INTERFACE")
-        .expect("get interface from cli arguments")
-        .clone();
-
-    // create the filenames and filepaths
-    let events_filename = format!("./{station}.json");
-    let capture_filename = format!("./{station}.pcap");
-    let events_filepath = Path::new(&events_filename);
-    let capture_filepath = Path::new(&capture_filename);
 
     // stop by default if the files already exist
-    if (events_filepath.exists() || capture_filepath.exists()) && !cli_args.contains_id("force") {
-        println!(
-            "Can't continue because the output files would overwrite existing ones. \
-             Use --force to overwrite them."
-        );
-        exit(1);
+    let dir = PathBuf::from(".");
+    if Outputs::exists(&dir, &station, &config) && !config.force {
+        return Err(Error::Overwrite(station));
     }
 
-    // check verbose
-    let verbose = cli_args.contains_id("verbose");
-
-    // bpf initialization
-    let events_file = initialize_events_file(events_filepath);
-    let skeleton = load_bpf_program(cli_args.contains_id("debug"));
-    let ringbuf = create_ringbuffer(&skeleton, events_file.clone(), verbose);
+    // join the mesh, capturing from a monitor interface of its radio
+    // (held until the end, so that it's undone however the service stops)
+    let _mesh = match cli_args.get_one::<String>("setup") {
+        Some(iface) => {
+            let config = MeshConfig {
+                iface: iface.clone(),
+                mesh_id: cli_args
+                    .get_one::<String>("mesh-id")
+                    .expect("get mesh id from cli arguments")
+                    .clone(),
+                freq: *cli_args
+                    .get_one::<u32>("freq")
+                    .expect("get frequency from cli arguments"),
+                width: *cli_args
+                    .get_one::<ChannelWidth>("width")
+                    .expect("get channel width from cli arguments"),
+                address: cli_args.get_one::<(Ipv4Addr, u8)>("ip").copied(),
+            };
+            let mesh = MeshSetup::new(&config)?;
+            println!("Joined mesh {} on {iface}", config.mesh_id);
+            interfaces.push(mesh.monitor().to_string());
+            Some(mesh)
+        }
+        None => None,
+    };
 
     // packet capture
-    start_packet_capture(stop.clone(), capture_filepath.to_path_buf(), interface);
+    // (either from monitor interfaces, or from the kernel through the ringbuffer)
+    let window = config.window;
+    let mut outputs = Outputs::new(dir, station, config, stream, stats.clone())?;
+    outputs.start_captures(&stop, &interfaces)?;
+    let outputs = Arc::new(Mutex::new(outputs));
+    let failure = Failure::new(&stop);
+    let ringbuf = create_ringbuffer(
+        &skeleton,
+        outputs.clone(),
+        stats.clone(),
+        failure.clone(),
+        kernel_capture,
+        metric_interval.is_some(),
+        verbose,
+    )?;
+    let samplers = samplers.start(outputs.clone(), &stop, &failure, verbose);
 
     // bpf loop
+    // (the files are still finished if polling or writing fails, so that nothing is lost)
     println!("Ready!");
-    while !stop.load(Ordering::Relaxed) {
-        match ringbuf.poll(Duration::from_millis(100)) {
-            Ok(()) => {}
-            Err(System(EINTR)) => break, // man 2 epoll_wait | ERRORS section
-            Err(err) => panic!("{err:?}"),
-        }
-    }
+    let polled = poll_until_stopped(&ringbuf, &stop, || {
+        stats.set_drops(read_drops(&skeleton));
+    });
     println!("\rStopping...");
+    let drops = read_drops(&skeleton);
+    if drops > 0 {
+        eprintln!("The kernel dropped {drops} events/frames because the ring buffers were full.");
+    }
 
-    // finish the events file
-    events_file
-        .lock()
-        .expect("lock events file to write the tail")
-        .write_all(b"]\n")
-        .expect("write events file tail");
-
-    // get collected data
-    let (mut events, mut packets) = get_collected_data(events_filepath, capture_filepath);
-    let datalinktype = packets.get_datalink();
-    assert_eq!(
-        datalinktype,
-        Linktype::IEEE802_11_RADIOTAP,
-        "datalink is not ieee80211 radiotap (127), but is instead {datalinktype:?}"
-    );
+    // finish the files of every station
+    // (the capture threads also stop if polling failed, and are waited for)
+    stop.store(true, Ordering::Relaxed);
+    drop(ringbuf);
+    for handle in samplers {
+        handle.join().expect("join sampler thread");
+    }
+    let finished = Arc::try_unwrap(outputs)
+        .unwrap_or_else(|_| panic!("get outputs back from the ringbuffer"))
+        .into_inner()
+        .expect("get outputs from their lock")
+        .finish(drops);
 
-    // relate events and packets
-    let mut counter = 0usize;
-    loop {
-        counter += 1;
-        match packets.next() {
-            Ok(pkt) => {
-                if pkt.header.caplen != pkt.header.len {
-                    eprintln!(
-                        "packet {counter} skipped because not all of its contents are available"
-                    );
-                    continue;
-                }
-                for evt in &mut events {
-                    if event_matches_packet(evt, pkt.data, pkt.header.caplen as usize) {
-                        evt.push_packet(counter);
-                        if verbose {
-                            println!("Associated packet {} with event {}", counter, evt.id());
-                        }
-                    }
-                }
-            }
-            Err(NoMorePackets) => break,
-            Err(err) => {
-                eprintln!("error on packet {counter}: {err:?}");
-            }
-        }
+    // wait for the last events to be streamed
+    if let Some(handle) = stream_thread {
+        handle.join().expect("join streaming thread");
     }
 
-    // rewrite the events file
-    File::create(events_filepath)
-        .expect("recreate events file")
-        .write_all(
-            to_string_pretty(&events)
-                .expect("pretty serialize events")
-                .as_bytes(),
-        )
-        .expect("2nd write of events file");
+    // (a file that couldn't be written is what stopped the service,
+    //  and files that aren't complete aren't correlated)
+    if let Some(err) = failure.take() {
+        return Err(err);
+    }
+    let outputs = finished?;
 
+    // relate events and packets
+    stats.queue_correlations(outputs.len());
+    for output in &outputs {
+        correlate(output, window, verbose)?;
+        stats.correlated();
+    }
+
+    polled?;
     println!("Done!");
+    Ok(())
+}
+
+// poll the ringbuffer until a termination signal arrives
+// ('tick' runs after every poll)
+fn poll_until_stopped(
+    ringbuf: &RingBuffer,
+    stop: &AtomicBool,
+    mut tick: impl FnMut(),
+) -> Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        match ringbuf.poll(Duration::from_millis(100)) {
+            Ok(()) => {}
+            Err(System(EINTR)) => break, // man 2 epoll_wait | ERRORS section
+            Err(err) => return Err(Error::Ringbuffer(err)),
+        }
+        tick();
+    }
+    Ok(())
 }
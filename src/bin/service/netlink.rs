@@ -0,0 +1,257 @@
+use std::{
+    ffi::CString,
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, zeroed},
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::{
+    bind, c_int, if_nametoindex, recv, send, sockaddr, sockaddr_nl, socket, AF_INET, AF_NETLINK,
+    IFA_ADDRESS, IFA_LOCAL, IFF_UP, NETLINK_ROUTE, NLA_TYPE_MASK, NLMSG_DONE, NLMSG_ERROR,
+    NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST, RTM_DELADDR, RTM_NEWADDR, RTM_NEWLINK,
+    SOCK_CLOEXEC, SOCK_RAW,
+};
+
+// size of the header of every message, and of every attribute
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+
+// big enough for the largest messages of a dump
+const RECV_BUFFER: usize = 64 * 1024;
+
+// everything in netlink is aligned to 32 bits
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// netlink socket, for requests to the kernel and their replies
+pub struct Socket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Socket {
+    pub fn new(protocol: c_int) -> Result<Self> {
+        let fd = unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // the kernel picks the port of the socket
+        let mut addr: sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        let bound = unsafe {
+            bind(
+                fd.as_raw_fd(),
+                (&addr as *const sockaddr_nl).cast::<sockaddr>(),
+                size_of::<sockaddr_nl>() as u32,
+            )
+        };
+        if bound < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self { fd, seq: 0 })
+    }
+
+    // send a request (with flags besides the usual ones) and get the payloads of the replies
+    // (a dump ends with a message of its own, anything else with an acknowledgement)
+    pub fn request(&mut self, kind: u16, flags: c_int, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+        let flags = flags | NLM_F_REQUEST | NLM_F_ACK;
+
+        let mut message = Vec::with_capacity(NLMSG_HDRLEN + payload.len());
+        message.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&(flags as u16).to_ne_bytes());
+        message.extend_from_slice(&self.seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(payload);
+        let sent = unsafe {
+            send(
+                self.fd.as_raw_fd(),
+                message.as_ptr().cast(),
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut replies = vec![];
+        let mut buffer = vec![0u8; RECV_BUFFER];
+        loop {
+            let len = unsafe {
+                recv(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut data = &buffer[..len as usize];
+            while data.len() >= NLMSG_HDRLEN {
+                let len =
+                    u32::from_ne_bytes(data[0..4].try_into().expect("message length")) as usize;
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "netlink message cut short",
+                    ));
+                }
+                let kind = u16::from_ne_bytes(data[4..6].try_into().expect("message type"));
+                let seq = u32::from_ne_bytes(data[8..12].try_into().expect("message sequence"));
+                let payload = &data[NLMSG_HDRLEN..len];
+                data = data.get(align(len)..).unwrap_or_default();
+                if seq != self.seq {
+                    continue;
+                }
+
+                // errors (and acknowledgements, which are errors of 0) have a negative errno,
+                // and a dump that failed halfway has one at its end
+                let errno = || {
+                    payload
+                        .get(0..4)
+                        .map_or(0, |e| i32::from_ne_bytes(e.try_into().expect("errno")))
+                };
+                match c_int::from(kind) {
+                    NLMSG_ERROR | NLMSG_DONE if errno() < 0 => {
+                        return Err(Error::from_raw_os_error(-errno()));
+                    }
+                    NLMSG_ERROR | NLMSG_DONE => return Ok(replies),
+                    _ => replies.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+// attributes of a request, appended after its fixed header
+pub struct Attrs(Vec<u8>);
+
+impl Attrs {
+    pub fn new(header: &[u8]) -> Self {
+        Self(header.to_vec())
+    }
+
+    pub fn bytes(mut self, kind: u16, value: &[u8]) -> Self {
+        self.0
+            .extend_from_slice(&((NLA_HDRLEN + value.len()) as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        self.0.resize(align(self.0.len()), 0);
+        self
+    }
+
+    #[inline]
+    pub fn u32(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_ne_bytes())
+    }
+
+    #[inline]
+    pub fn flag(self, kind: u16) -> Self {
+        self.bytes(kind, &[])
+    }
+
+    // strings are null-terminated
+    pub fn str(self, kind: u16, value: &str) -> Self {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        self.bytes(kind, &value)
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// attributes of a reply, by their type (without the nested flag)
+pub fn attrs(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < NLA_HDRLEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK as u16;
+        let value = data.get(NLA_HDRLEN..len)?;
+        data = data.get(align(len)..).unwrap_or_default();
+        Some((kind, value))
+    })
+}
+
+// first attribute of a type
+pub fn attr(data: &[u8], kind: u16) -> Option<&[u8]> {
+    attrs(data)
+        .find(|(k, _)| *k == kind)
+        .map(|(_, value)| value)
+}
+
+pub fn attr_u8(data: &[u8], kind: u16) -> Option<u8> {
+    attr(data, kind)?.first().copied()
+}
+
+pub fn attr_u16(data: &[u8], kind: u16) -> Option<u16> {
+    Some(u16::from_ne_bytes(
+        attr(data, kind)?.get(0..2)?.try_into().ok()?,
+    ))
+}
+
+pub fn attr_u32(data: &[u8], kind: u16) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        attr(data, kind)?.get(0..4)?.try_into().ok()?,
+    ))
+}
+
+// index of a network interface ('None' if it doesn't exist)
+pub fn ifindex(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+// bring an interface up or down, through rtnetlink
+pub fn set_link_up(route: &mut Socket, ifindex: u32, up: bool) -> Result<()> {
+    // family, padding, type, index, flags and the flags to change
+    let mut header = vec![0u8; 4];
+    header.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+    header.extend_from_slice(&(if up { IFF_UP as u32 } else { 0 }).to_ne_bytes());
+    header.extend_from_slice(&(IFF_UP as u32).to_ne_bytes());
+    route.request(RTM_NEWLINK, 0, &header).map(|_| ())
+}
+
+// add or remove an IPv4 address of an interface, through rtnetlink
+pub fn set_address(
+    route: &mut Socket,
+    ifindex: u32,
+    addr: Ipv4Addr,
+    prefix: u8,
+    add: bool,
+) -> Result<()> {
+    // family, prefix length, flags, scope and index
+    let mut header = vec![AF_INET as u8, prefix, 0, 0];
+    header.extend_from_slice(&ifindex.to_ne_bytes());
+    let attrs = Attrs::new(&header)
+        .bytes(IFA_LOCAL, &addr.octets())
+        .bytes(IFA_ADDRESS, &addr.octets());
+    let (kind, flags) = if add {
+        (RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL)
+    } else {
+        (RTM_DELADDR, 0)
+    };
+    route.request(kind, flags, attrs.as_slice()).map(|_| ())
+}
+
+// socket for rtnetlink
+#[inline]
+pub fn route_socket() -> Result<Socket> {
+    Socket::new(NETLINK_ROUTE)
+}
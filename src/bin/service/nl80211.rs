@@ -0,0 +1,347 @@
+use crate::netlink::{attr, attr_u16, attr_u32, attr_u8, attrs, Attrs, Socket};
+
+use mesh_analyzer::{MacAddr, MeshPath, NeighborSample, PlinkState, ProxyPath};
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread,
+    time::SystemTime,
+};
+
+use libc::{
+    c_int, setns, CLONE_NEWNET, CTRL_ATTR_FAMILY_ID, CTRL_ATTR_FAMILY_NAME, CTRL_CMD_GETFAMILY,
+    ENOENT, GENL_ID_CTRL, NETLINK_GENERIC, NLM_F_DUMP,
+};
+
+// commands (from 'include/uapi/linux/nl80211.h')
+const CMD_GET_WIPHY: u8 = 1;
+const CMD_GET_INTERFACE: u8 = 5;
+const CMD_SET_INTERFACE: u8 = 6;
+const CMD_NEW_INTERFACE: u8 = 7;
+const CMD_DEL_INTERFACE: u8 = 8;
+const CMD_GET_STATION: u8 = 17;
+const CMD_GET_MPATH: u8 = 21;
+const CMD_JOIN_MESH: u8 = 68;
+const CMD_LEAVE_MESH: u8 = 69;
+const CMD_GET_MPP: u8 = 107;
+
+// attributes
+const ATTR_WIPHY: u16 = 1;
+const ATTR_IFINDEX: u16 = 3;
+const ATTR_IFNAME: u16 = 4;
+const ATTR_IFTYPE: u16 = 5;
+const ATTR_MAC: u16 = 6;
+const ATTR_STA_INFO: u16 = 21;
+const ATTR_MESH_ID: u16 = 24;
+const ATTR_MPATH_NEXT_HOP: u16 = 26;
+const ATTR_MPATH_INFO: u16 = 27;
+const ATTR_SUPPORTED_IFTYPES: u16 = 32;
+const ATTR_WIPHY_FREQ: u16 = 38;
+const ATTR_SOFTWARE_IFTYPES: u16 = 121;
+const ATTR_CHANNEL_WIDTH: u16 = 159;
+const ATTR_CENTER_FREQ1: u16 = 160;
+const ATTR_SPLIT_WIPHY_DUMP: u16 = 174;
+
+// attributes of the information of a mesh path
+const MPATH_INFO_SN: u16 = 2;
+const MPATH_INFO_METRIC: u16 = 3;
+const MPATH_INFO_EXPTIME: u16 = 4;
+const MPATH_INFO_FLAGS: u16 = 5;
+const MPATH_INFO_HOP_COUNT: u16 = 8;
+
+// attributes of the information of a station, and of its bitrate
+const STA_INFO_INACTIVE_TIME: u16 = 1;
+const STA_INFO_PLINK_STATE: u16 = 6;
+const STA_INFO_SIGNAL: u16 = 7;
+const STA_INFO_TX_BITRATE: u16 = 8;
+const STA_INFO_TX_RETRIES: u16 = 11;
+const STA_INFO_TX_FAILED: u16 = 12;
+const STA_INFO_SIGNAL_AVG: u16 = 13;
+const STA_INFO_AIRTIME_LINK_METRIC: u16 = 41;
+const RATE_INFO_BITRATE: u16 = 1;
+const RATE_INFO_BITRATE32: u16 = 5;
+
+// interface types
+pub const IFTYPE_MONITOR: u32 = 6;
+pub const IFTYPE_MESH_POINT: u32 = 7;
+
+// version of the family in the generic netlink header
+const NL80211_VERSION: u8 = 1;
+
+// name of an interface type, for errors
+pub fn iftype_name(iftype: u32) -> &'static str {
+    match iftype {
+        1 => "ad-hoc",
+        2 => "managed",
+        3 => "AP",
+        IFTYPE_MONITOR => "monitor",
+        IFTYPE_MESH_POINT => "mesh point",
+        _ => "other",
+    }
+}
+
+// wireless interface, as the kernel knows it
+#[derive(Debug, Clone, Copy)]
+pub struct Interface {
+    pub wiphy: u32,
+    pub iftype: u32,
+}
+
+// mesh interface of the namespace of the service
+#[derive(Debug, Clone)]
+pub struct MeshInterface {
+    pub ifindex: u32,
+    pub name: String,
+    pub mac: MacAddr,
+}
+
+// client of nl80211, through generic netlink
+pub struct Nl80211 {
+    socket: Socket,
+    family: u16,
+}
+
+impl Nl80211 {
+    pub fn new() -> Result<Self> {
+        let mut socket = Socket::new(NETLINK_GENERIC)?;
+
+        // the id of the family is given by the controller of generic netlink
+        let request = Attrs::new(&[CTRL_CMD_GETFAMILY as u8, 1, 0, 0])
+            .str(CTRL_ATTR_FAMILY_NAME as u16, "nl80211");
+        // (the family isn't there without cfg80211)
+        let missing = || Error::new(ErrorKind::NotFound, "nl80211 isn't available");
+        let family = socket
+            .request(GENL_ID_CTRL as u16, 0, request.as_slice())
+            .map_err(|err| match err.raw_os_error() {
+                Some(ENOENT) => missing(),
+                _ => err,
+            })?
+            .iter()
+            .find_map(|reply| attr_u16(reply.get(4..)?, CTRL_ATTR_FAMILY_ID as u16))
+            .ok_or_else(missing)?;
+        Ok(Self { socket, family })
+    }
+
+    // nl80211 in another network namespace, from its file (like the ones in /run/netns)
+    // (a netlink socket stays in the namespace it was created in, so it's created by a thread
+    //  that enters the namespace and ends right after, leaving the others where they were)
+    pub fn in_netns(netns: &Path) -> Result<Self> {
+        let file = File::open(netns)?;
+        thread::spawn(move || {
+            if unsafe { setns(file.as_raw_fd(), CLONE_NEWNET) } < 0 {
+                return Err(Error::last_os_error());
+            }
+            Self::new()
+        })
+        .join()
+        .expect("join thread entering a network namespace")
+    }
+
+    #[inline]
+    fn message(cmd: u8) -> Attrs {
+        Attrs::new(&[cmd, NL80211_VERSION, 0, 0])
+    }
+
+    // attributes of the replies (without the generic netlink header)
+    fn request(&mut self, message: Attrs, flags: c_int) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .socket
+            .request(self.family, flags, message.as_slice())?
+            .into_iter()
+            .map(|mut reply| reply.split_off(4.min(reply.len())))
+            .collect())
+    }
+
+    pub fn interface(&mut self, ifindex: u32) -> Result<Interface> {
+        let message = Self::message(CMD_GET_INTERFACE).u32(ATTR_IFINDEX, ifindex);
+        let replies = self.request(message, 0)?;
+        let reply = replies
+            .first()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no reply for the interface"))?;
+        match (attr_u32(reply, ATTR_WIPHY), attr_u32(reply, ATTR_IFTYPE)) {
+            (Some(wiphy), Some(iftype)) => Ok(Interface { wiphy, iftype }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "interface without a wiphy",
+            )),
+        }
+    }
+
+    // interface types that a radio supports, and the ones that don't take up a radio
+    // (a split dump, since the information of a radio doesn't fit in a single message)
+    pub fn iftypes(&mut self, wiphy: u32) -> Result<(Vec<u32>, Vec<u32>)> {
+        let message = Self::message(CMD_GET_WIPHY)
+            .u32(ATTR_WIPHY, wiphy)
+            .flag(ATTR_SPLIT_WIPHY_DUMP);
+        let mut supported = vec![];
+        let mut software = vec![];
+        for reply in self.request(message, NLM_F_DUMP)? {
+            if attr_u32(&reply, ATTR_WIPHY) != Some(wiphy) {
+                continue;
+            }
+            // each type is a flag inside of the nested attribute
+            for (kind, nested) in attrs(&reply) {
+                let types = match kind {
+                    ATTR_SUPPORTED_IFTYPES => &mut supported,
+                    ATTR_SOFTWARE_IFTYPES => &mut software,
+                    _ => continue,
+                };
+                types.extend(attrs(nested).map(|(iftype, _)| u32::from(iftype)));
+            }
+        }
+        Ok((supported, software))
+    }
+
+    // returns the index of the new interface
+    pub fn new_interface(&mut self, wiphy: u32, name: &str, iftype: u32) -> Result<Option<u32>> {
+        let message = Self::message(CMD_NEW_INTERFACE)
+            .u32(ATTR_WIPHY, wiphy)
+            .str(ATTR_IFNAME, name)
+            .u32(ATTR_IFTYPE, iftype);
+        let replies = self.request(message, 0)?;
+        Ok(replies
+            .iter()
+            .find_map(|reply| attr_u32(reply, ATTR_IFINDEX)))
+    }
+
+    pub fn del_interface(&mut self, ifindex: u32) -> Result<()> {
+        let message = Self::message(CMD_DEL_INTERFACE).u32(ATTR_IFINDEX, ifindex);
+        self.request(message, 0).map(|_| ())
+    }
+
+    // (the interface has to be down)
+    pub fn set_iftype(&mut self, ifindex: u32, iftype: u32) -> Result<()> {
+        let message = Self::message(CMD_SET_INTERFACE)
+            .u32(ATTR_IFINDEX, ifindex)
+            .u32(ATTR_IFTYPE, iftype);
+        self.request(message, 0).map(|_| ())
+    }
+
+    pub fn mesh_interfaces(&mut self) -> Result<Vec<MeshInterface>> {
+        let message = Self::message(CMD_GET_INTERFACE);
+        Ok(self
+            .request(message, NLM_F_DUMP)?
+            .iter()
+            .filter(|reply| attr_u32(reply, ATTR_IFTYPE) == Some(IFTYPE_MESH_POINT))
+            .filter_map(|reply| {
+                Some(MeshInterface {
+                    ifindex: attr_u32(reply, ATTR_IFINDEX)?,
+                    name: attr_str(reply, ATTR_IFNAME)?,
+                    mac: attr_mac(reply, ATTR_MAC)?,
+                })
+            })
+            .collect())
+    }
+
+    // mesh path table of an interface
+    pub fn mesh_paths(&mut self, ifindex: u32) -> Result<Vec<MeshPath>> {
+        let message = Self::message(CMD_GET_MPATH).u32(ATTR_IFINDEX, ifindex);
+        Ok(self
+            .request(message, NLM_F_DUMP)?
+            .iter()
+            .filter_map(|reply| {
+                let info = attr(reply, ATTR_MPATH_INFO).unwrap_or_default();
+                Some(MeshPath {
+                    dst: attr_mac(reply, ATTR_MAC)?,
+                    // (a path being resolved doesn't have a nexthop yet, so it's all zeros)
+                    next_hop: attr_mac(reply, ATTR_MPATH_NEXT_HOP)
+                        .filter(|nh| *nh != MacAddr::from([0; 6])),
+                    sn: attr_u32(info, MPATH_INFO_SN).unwrap_or_default(),
+                    metric: attr_u32(info, MPATH_INFO_METRIC).unwrap_or_default(),
+                    exptime: attr_u32(info, MPATH_INFO_EXPTIME).unwrap_or_default(),
+                    flags: attr_u8(info, MPATH_INFO_FLAGS).unwrap_or_default(),
+                    hop_count: attr_u8(info, MPATH_INFO_HOP_COUNT),
+                })
+            })
+            .collect())
+    }
+
+    // proxy path table of an interface
+    pub fn proxy_paths(&mut self, ifindex: u32) -> Result<Vec<ProxyPath>> {
+        let message = Self::message(CMD_GET_MPP).u32(ATTR_IFINDEX, ifindex);
+        Ok(self
+            .request(message, NLM_F_DUMP)?
+            .iter()
+            .filter_map(|reply| {
+                Some(ProxyPath {
+                    dst: attr_mac(reply, ATTR_MAC)?,
+                    proxy: attr_mac(reply, ATTR_MPATH_NEXT_HOP)?,
+                })
+            })
+            .collect())
+    }
+
+    // statistics of the neighbors of a mesh interface
+    pub fn stations(
+        &mut self,
+        iface: &MeshInterface,
+        ts: SystemTime,
+        netns: u32,
+    ) -> Result<Vec<NeighborSample>> {
+        let message = Self::message(CMD_GET_STATION).u32(ATTR_IFINDEX, iface.ifindex);
+        Ok(self
+            .request(message, NLM_F_DUMP)?
+            .iter()
+            .filter_map(|reply| {
+                let info = attr(reply, ATTR_STA_INFO).unwrap_or_default();
+                // (in units of 100 kbps, older kernels only have the 16 bits one)
+                let bitrate = attr(info, STA_INFO_TX_BITRATE).and_then(|rate| {
+                    attr_u32(rate, RATE_INFO_BITRATE32)
+                        .or_else(|| attr_u16(rate, RATE_INFO_BITRATE).map(u32::from))
+                });
+                Some(NeighborSample {
+                    ts,
+                    mac: iface.mac.clone(),
+                    iface: iface.name.clone(),
+                    netns,
+                    peer: attr_mac(reply, ATTR_MAC)?,
+                    signal: attr_u8(info, STA_INFO_SIGNAL).map(|s| s as i8),
+                    signal_avg: attr_u8(info, STA_INFO_SIGNAL_AVG).map(|s| s as i8),
+                    tx_bitrate: bitrate.map(|rate| rate * 100),
+                    tx_retries: attr_u32(info, STA_INFO_TX_RETRIES),
+                    tx_failed: attr_u32(info, STA_INFO_TX_FAILED),
+                    plink_state: attr_u8(info, STA_INFO_PLINK_STATE).and_then(PlinkState::from_raw),
+                    airtime_metric: attr_u32(info, STA_INFO_AIRTIME_LINK_METRIC),
+                    inactive_ms: attr_u32(info, STA_INFO_INACTIVE_TIME),
+                })
+            })
+            .collect())
+    }
+
+    pub fn join_mesh(
+        &mut self,
+        ifindex: u32,
+        mesh_id: &str,
+        freq: u32,
+        width: u32,
+        center_freq: u32,
+    ) -> Result<()> {
+        let message = Self::message(CMD_JOIN_MESH)
+            .u32(ATTR_IFINDEX, ifindex)
+            .bytes(ATTR_MESH_ID, mesh_id.as_bytes())
+            .u32(ATTR_WIPHY_FREQ, freq)
+            .u32(ATTR_CHANNEL_WIDTH, width)
+            .u32(ATTR_CENTER_FREQ1, center_freq);
+        self.request(message, 0).map(|_| ())
+    }
+
+    pub fn leave_mesh(&mut self, ifindex: u32) -> Result<()> {
+        let message = Self::message(CMD_LEAVE_MESH).u32(ATTR_IFINDEX, ifindex);
+        self.request(message, 0).map(|_| ())
+    }
+}
+
+fn attr_mac(data: &[u8], kind: u16) -> Option<MacAddr> {
+    Some(MacAddr::from(
+        <[u8; 6]>::try_from(attr(data, kind)?.get(0..6)?).ok()?,
+    ))
+}
+
+fn attr_str(data: &[u8], kind: u16) -> Option<String> {
+    let value = attr(data, kind)?;
+    let end = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+    Some(String::from_utf8_lossy(&value[..end]).into_owned())
+}
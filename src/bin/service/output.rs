@@ -0,0 +1,724 @@
+use crate::{
+    error::{Error, Result},
+    exporter::Stats,
+    util::{
+        create_kernel_capture_file, iface_phy, netns_name, open_capture, own_netns,
+        start_packet_capture, write_frame, CaptureOptions, CaptureSink, IfaceStation,
+    },
+};
+
+use mesh_analyzer::{
+    CaptureConfig, Event, EventFormat, EventLog, Frame, Header, InterfaceInfo, LinkRetries,
+    Manifest, ManifestFiles, Message, Metric, NeighborSample, PathSnapshot, PcapngWriter,
+    RadioInfo, RawEvent, StationInfo,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{copy, remove_file, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use pcap::Savefile;
+
+// threads capturing from monitor devices into the same file
+// (more than one device is written as pcapng, with an interface for each)
+struct CaptureThreads {
+    radios: Vec<RadioInfo>,
+    handles: Vec<JoinHandle<Result<usize>>>,
+}
+
+// where everything that comes from the kernel goes to
+// (it fails if the files of a station can't be created or written)
+pub trait Sink {
+    fn write_event(&mut self, raw: RawEvent) -> Result<Option<Event>>;
+    fn write_frame(&mut self, frame: &Frame) -> Result<()>;
+    fn push_metric(&mut self, metric: Metric) -> Result<()>;
+    fn push_paths(&mut self, snapshot: PathSnapshot) -> Result<()>;
+    fn push_neighbor(&mut self, sample: NeighborSample) -> Result<()>;
+}
+
+// how the events get separated into stations
+#[derive(Clone)]
+pub enum Split {
+    Nothing,
+    Netns,
+    Iface(Vec<IfaceStation>),
+}
+
+// files of a single station
+pub struct Output {
+    events_path: PathBuf,
+    capture_path: PathBuf,
+    metrics_path: PathBuf,
+    manifest_path: PathBuf,
+    manifest: Manifest,
+    events_log: EventLog,
+    counter: usize,
+    frames: Option<Savefile>,
+    metrics: Option<File>,
+    paths: Option<File>,
+    neighbors: Option<File>,
+    own_capture: bool,
+}
+
+impl Output {
+    fn new(
+        dir: &Path,
+        station: &str,
+        config: &OutputConfig,
+        own_capture: bool,
+        stats: &Stats,
+    ) -> Result<Self> {
+        let (events_path, capture_path, metrics_path) = Self::paths(dir, station, config.format);
+        let info = StationInfo::local(station);
+        let manifest_path = Manifest::path(dir, station);
+        let manifest = Manifest::new(
+            info.clone(),
+            config.manifest_config(),
+            ManifestFiles {
+                events: file_name(&events_path),
+                capture: file_name(&capture_path),
+                metrics: config.metric_interval.map(|_| file_name(&metrics_path)),
+                retries: Some(file_name(&LinkRetries::path(&events_path))),
+                paths: config
+                    .path_interval
+                    .map(|_| file_name(&PathSnapshot::path(&events_path))),
+                neighbors: config
+                    .neighbor_interval
+                    .map(|_| file_name(&NeighborSample::path(&events_path))),
+            },
+        );
+        manifest.write(&manifest_path)?;
+        let create = |path: PathBuf| File::create(&path).map_err(|err| Error::file(&path, err));
+        let output = Self {
+            events_log: EventLog::create(&events_path, &Header::new(info), config.format)?,
+            metrics: config
+                .metric_interval
+                .map(|_| create(metrics_path.clone()))
+                .transpose()?,
+            paths: config
+                .path_interval
+                .map(|_| create(PathSnapshot::path(&events_path)))
+                .transpose()?,
+            neighbors: config
+                .neighbor_interval
+                .map(|_| create(NeighborSample::path(&events_path)))
+                .transpose()?,
+            frames: if config.kernel_capture {
+                Some(create_kernel_capture_file(&capture_path)?)
+            } else {
+                None
+            },
+            events_path,
+            capture_path,
+            metrics_path,
+            manifest_path,
+            manifest,
+            counter: 0,
+            own_capture,
+        };
+        stats.add_files(output.all_files());
+        Ok(output)
+    }
+
+    // events, capture and metrics files of a station
+    pub fn paths(dir: &Path, station: &str, format: EventFormat) -> (PathBuf, PathBuf, PathBuf) {
+        (
+            dir.join(format!("{station}.{}", format.extension())),
+            dir.join(format!("{station}.pcap")),
+            dir.join(format!("{station}.metrics")),
+        )
+    }
+
+    // check if any of the files of a station would be overwritten
+    pub fn exists(dir: &Path, station: &str, config: &OutputConfig) -> bool {
+        let (events_path, capture_path, metrics_path) = Self::paths(dir, station, config.format);
+        events_path.exists()
+            || capture_path.exists()
+            || (config.metric_interval.is_some() && metrics_path.exists())
+            || LinkRetries::path(&events_path).exists()
+            || (config.path_interval.is_some() && PathSnapshot::path(&events_path).exists())
+            || (config.neighbor_interval.is_some() && NeighborSample::path(&events_path).exists())
+            || Manifest::path(dir, station).exists()
+    }
+
+    fn write_event(&mut self, raw: RawEvent, phy: Option<String>) -> Result<Event> {
+        self.counter += 1;
+        let event_struct = Event::from_raw(raw, self.counter);
+        self.events_log
+            .append(&event_struct)
+            .map_err(|err| Error::file(&self.events_path, err))?;
+        self.manifest.add_interface(InterfaceInfo {
+            name: event_struct.iface().to_string(),
+            mac: event_struct.mac().clone(),
+            netns: *event_struct.netns(),
+            ifindex: *event_struct.ifindex(),
+            phy,
+        });
+        Ok(event_struct)
+    }
+
+    // close the files of the station and write its manifest
+    // (with the packets and radios of its capture, which is copied if it's shared)
+    fn finish(
+        &mut self,
+        config: &OutputConfig,
+        drops: u64,
+        capture: Option<&(usize, Vec<RadioInfo>)>,
+        shared_capture: Option<&Path>,
+    ) -> Result<()> {
+        let events_path = &self.events_path;
+        self.events_log
+            .sync()
+            .map_err(|err| Error::file(events_path, err))?;
+        self.frames.take();
+        if let Some(paths) = self.paths.take() {
+            paths
+                .sync_data()
+                .map_err(|err| Error::file(&PathSnapshot::path(events_path), err))?;
+        }
+        if let Some(neighbors) = self.neighbors.take() {
+            neighbors
+                .sync_data()
+                .map_err(|err| Error::file(&NeighborSample::path(events_path), err))?;
+        }
+        if let Some(metrics) = self.metrics.take() {
+            metrics
+                .sync_data()
+                .map_err(|err| Error::file(&self.metrics_path, err))?;
+        }
+
+        let manifest = &mut self.manifest;
+        manifest.stop = Some(SystemTime::now());
+        manifest.counters.events = self.counter;
+        manifest.counters.drops = drops;
+        manifest.capture.kernel = config.kernel_capture;
+        if let Some((packets, radios)) = capture {
+            manifest.counters.frames = *packets;
+            manifest.capture.interface = Some(
+                radios
+                    .iter()
+                    .map(|radio| radio.interface.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            manifest.capture.radios = radios.clone();
+        }
+        if !config.kernel_capture {
+            let options = &config.capture;
+            manifest.capture.filter = options.filter.clone();
+            manifest.capture.snaplen = options.snaplen;
+            manifest.capture.mesh_id = options.mesh_id.clone();
+        }
+        manifest.write(&self.manifest_path)?;
+
+        if let Some(shared_capture) = shared_capture {
+            if !self.own_capture && shared_capture != self.capture_path {
+                copy(shared_capture, &self.capture_path)
+                    .map_err(|err| Error::file(&self.capture_path, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn events_path(&self) -> &Path {
+        &self.events_path
+    }
+
+    #[inline]
+    pub fn capture_path(&self) -> &Path {
+        &self.capture_path
+    }
+
+    #[inline]
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    // files that were written for this station
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.all_files()
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    // (some of them only if their options were used)
+    fn all_files(&self) -> [PathBuf; 7] {
+        [
+            self.manifest_path.clone(),
+            self.events_path.clone(),
+            self.capture_path.clone(),
+            self.metrics_path.clone(),
+            LinkRetries::path(&self.events_path),
+            PathSnapshot::path(&self.events_path),
+            NeighborSample::path(&self.events_path),
+        ]
+    }
+}
+
+// what every output of a run (or a session of the daemon) has in common
+#[derive(Clone)]
+pub struct OutputConfig {
+    pub split: Split,
+    pub netns: Option<u32>,
+    pub force: bool,
+    pub kernel_capture: bool,
+    pub metric_interval: Option<Duration>,
+    pub path_interval: Option<Duration>,
+    pub neighbor_interval: Option<Duration>,
+    pub format: EventFormat,
+    pub capture: CaptureOptions,
+    // how far apart a packet and an event can be to be related, when correlating
+    pub window: Duration,
+}
+
+impl OutputConfig {
+    // how the config is described in the manifest of each station
+    fn manifest_config(&self) -> CaptureConfig {
+        CaptureConfig {
+            split: match self.split {
+                Split::Nothing => "none",
+                Split::Netns => "netns",
+                Split::Iface(_) => "iface",
+            }
+            .to_string(),
+            netns: self.netns,
+            metric_interval_ms: self.metric_interval.map(|i| i.as_millis() as u64),
+            path_interval_ms: self.path_interval.map(|i| i.as_millis() as u64),
+            neighbor_interval_ms: self.neighbor_interval.map(|i| i.as_millis() as u64),
+            format: self.format.to_string(),
+        }
+    }
+}
+
+// name of a file, without its folder
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("output file has a name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// all the stations being written by this service
+pub struct Outputs {
+    dir: PathBuf,
+    station: String,
+    config: OutputConfig,
+    shared_capture: Option<PathBuf>,
+    // threads capturing from the monitor interfaces, by the file they write to
+    captures: HashMap<PathBuf, CaptureThreads>,
+    outputs: HashMap<String, Output>,
+    skipped: HashSet<String>,
+    netns_names: HashMap<u32, String>,
+    phys: HashMap<String, String>,
+    // radios of the traced interfaces, as they show up
+    iface_phys: HashMap<String, Option<String>>,
+    own_netns: Option<u32>,
+    stream: Option<Sender<Message>>,
+    stats: Arc<Stats>,
+}
+
+impl Outputs {
+    pub fn new(
+        dir: PathBuf,
+        station: String,
+        config: OutputConfig,
+        stream: Option<Sender<Message>>,
+        stats: Arc<Stats>,
+    ) -> Result<Self> {
+        let mut outputs = HashMap::new();
+        let mut phys = HashMap::new();
+
+        // without splitting, or when splitting by interface, the stations are already known,
+        // so create them right away
+        match &config.split {
+            Split::Nothing => {
+                outputs.insert(
+                    station.clone(),
+                    Output::new(&dir, &station, &config, false, &stats)?,
+                );
+            }
+            Split::Netns => {}
+            Split::Iface(stations) => {
+                for sta in stations {
+                    outputs.insert(
+                        sta.station.clone(),
+                        Output::new(&dir, &sta.station, &config, sta.capture.is_some(), &stats)?,
+                    );
+
+                    // frames from the kernel only know their radio
+                    if config.kernel_capture {
+                        match iface_phy(&sta.iface) {
+                            Some(phy) => {
+                                phys.insert(phy, sta.iface.clone());
+                            }
+                            None => eprintln!("Can't find the radio of interface {}", sta.iface),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            dir,
+            station,
+            config,
+            shared_capture: None,
+            captures: HashMap::new(),
+            outputs,
+            skipped: HashSet::new(),
+            netns_names: HashMap::new(),
+            phys,
+            iface_phys: HashMap::new(),
+            own_netns: own_netns(),
+            stream,
+            stats,
+        })
+    }
+
+    // check if any of the files that are known from the start would be overwritten
+    // (when splitting by namespace, the files of each station are checked as they show up)
+    pub fn exists(dir: &Path, station: &str, config: &OutputConfig) -> bool {
+        let mut exists = Output::exists(dir, station, config);
+        if let Split::Iface(stations) = &config.split {
+            exists |= stations
+                .iter()
+                .any(|sta| Output::exists(dir, &sta.station, config));
+        }
+        exists
+    }
+
+    // capture packets from the monitor interfaces, until 'stop' is set
+    // (the shared interfaces go to the file of the main station, unless they come from the kernel)
+    // (if one of them can't be started, the ones that were are stopped)
+    pub fn start_captures(&mut self, stop: &Arc<AtomicBool>, interfaces: &[String]) -> Result<()> {
+        let started = self.start_capture_threads(stop, interfaces);
+        if started.is_err() {
+            stop.store(true, Ordering::Relaxed);
+        }
+        started
+    }
+
+    fn start_capture_threads(
+        &mut self,
+        stop: &Arc<AtomicBool>,
+        interfaces: &[String],
+    ) -> Result<()> {
+        if self.config.kernel_capture {
+            return Ok(());
+        }
+        if !interfaces.is_empty() {
+            let (_, capture_path, _) = Output::paths(&self.dir, &self.station, self.config.format);
+            self.start_capture(stop, capture_path.clone(), interfaces)?;
+            self.shared_capture = Some(capture_path);
+        }
+        let own = match &self.config.split {
+            Split::Iface(stations) => stations
+                .iter()
+                .filter_map(|sta| Some((sta.station.clone(), sta.capture.clone()?)))
+                .collect(),
+            _ => vec![],
+        };
+        for (station, capture) in own {
+            let (_, capture_path, _) = Output::paths(&self.dir, &station, self.config.format);
+            self.start_capture(stop, capture_path, &[capture])?;
+        }
+        Ok(())
+    }
+
+    // capture from some devices into the same file
+    // (it keeps the name of a pcap file even as pcapng, since readers tell them apart
+    //  from their first bytes)
+    fn start_capture(
+        &mut self,
+        stop: &Arc<AtomicBool>,
+        capture_path: PathBuf,
+        interfaces: &[String],
+    ) -> Result<()> {
+        let options = &self.config.capture;
+        let pcaps = interfaces
+            .iter()
+            .map(|interface| open_capture(interface, options))
+            .collect::<Result<Vec<_>>>()?;
+        let sinks = if let [pcap] = pcaps.as_slice() {
+            let file = pcap
+                .savefile(&capture_path)
+                .map_err(|source| Error::Capture {
+                    interface: interfaces[0].clone(),
+                    source,
+                })?;
+            vec![CaptureSink::Pcap(file)]
+        } else {
+            let file_error = |err| Error::file(&capture_path, err);
+            let mut writer = PcapngWriter::new(File::create(&capture_path).map_err(file_error)?)
+                .map_err(file_error)?;
+            let mut indexes = vec![];
+            for (interface, pcap) in interfaces.iter().zip(&pcaps) {
+                let linktype = pcap.get_datalink().0 as u16;
+                let snaplen = options.snaplen.unwrap_or(0);
+                indexes.push(
+                    writer
+                        .add_interface(interface, linktype, snaplen)
+                        .map_err(file_error)?,
+                );
+            }
+            let writer = Arc::new(Mutex::new(writer));
+            indexes
+                .into_iter()
+                .map(|interface| CaptureSink::Pcapng {
+                    writer: writer.clone(),
+                    interface,
+                    path: capture_path.clone(),
+                })
+                .collect()
+        };
+
+        let radios = interfaces
+            .iter()
+            .map(|interface| RadioInfo {
+                interface: interface.clone(),
+                phy: iface_phy(interface),
+            })
+            .collect();
+        let handles = pcaps
+            .into_iter()
+            .zip(sinks)
+            .zip(interfaces)
+            .map(|((pcap, sink), interface)| {
+                start_packet_capture(
+                    stop.clone(),
+                    pcap,
+                    sink,
+                    interface.clone(),
+                    options,
+                    self.stats.clone(),
+                )
+            })
+            .collect();
+        self.stats.add_files([capture_path.clone()]);
+        self.captures
+            .insert(capture_path, CaptureThreads { radios, handles });
+        Ok(())
+    }
+
+    // number of events written so far, in all stations
+    pub fn events(&self) -> usize {
+        self.outputs.values().map(|output| output.counter).sum()
+    }
+
+    // name of the station something from a network namespace and interface belongs to
+    // ('None' if it's being filtered out)
+    fn station_for(&mut self, netns: u32, iface: Option<&str>) -> Option<String> {
+        if self.config.netns.map_or(false, |n| n != netns) {
+            return None;
+        }
+        match &self.config.split {
+            Split::Nothing => Some(self.station.clone()),
+            Split::Netns => {
+                let name = self
+                    .netns_names
+                    .entry(netns)
+                    .or_insert_with(|| netns_name(netns));
+                Some(format!("{}_{name}", self.station))
+            }
+            Split::Iface(stations) => stations
+                .iter()
+                .find(|sta| Some(sta.iface.as_str()) == iface)
+                .map(|sta| sta.station.clone()),
+        }
+    }
+
+    // get the output of a station, creating it if it's the first time it shows up
+    fn output(&mut self, station: String) -> Result<Option<&mut Output>> {
+        if !self.outputs.contains_key(&station) {
+            if self.skipped.contains(&station) {
+                return Ok(None);
+            }
+            if Output::exists(&self.dir, &station, &self.config) && !self.config.force {
+                eprintln!(
+                    "Ignoring station {station} because its output files would overwrite \
+                     existing ones. Use --force to overwrite them."
+                );
+                self.skipped.insert(station);
+                return Ok(None);
+            }
+            let output = Output::new(&self.dir, &station, &self.config, false, &self.stats)?;
+            self.outputs.insert(station.clone(), output);
+        }
+        Ok(self.outputs.get_mut(&station))
+    }
+
+    // close the files of all stations, with the drops of the kernel while they were traced
+    // (the capture from the monitor interface is shared, so it gets copied to all of them,
+    //  except the ones that had their own capture interface)
+    // ('stop' must already be set, since the capture threads are waited for
+    //  so that their files are complete before being copied or correlated)
+    // (if a file can't be written, the other stations are still finished,
+    //  and the first error is returned)
+    // (a capture thread that panicked left its file incomplete, so that's an error too)
+    pub fn finish(self, drops: u64) -> Result<Vec<Output>> {
+        let mut failed = None;
+        let captures = self
+            .captures
+            .into_iter()
+            .map(|(path, threads)| {
+                let mut packets = 0;
+                for handle in threads.handles {
+                    let joined = handle.join().unwrap_or_else(|_| {
+                        let err = io::Error::other("the capture panicked");
+                        Err(Error::file(&path, err))
+                    });
+                    match joined {
+                        Ok(count) => packets += count,
+                        Err(err) => {
+                            failed.get_or_insert(err);
+                        }
+                    }
+                }
+                (path, (packets, threads.radios))
+            })
+            .collect::<HashMap<_, _>>();
+        let capture_path = self.shared_capture.as_deref();
+        let mut outputs = self.outputs.into_values().collect::<Vec<_>>();
+        for output in &mut outputs {
+            let capture = captures.get(&output.capture_path).or_else(|| {
+                capture_path
+                    .filter(|_| !output.own_capture)
+                    .and_then(|path| captures.get(path))
+            });
+            if let Err(err) = output.finish(&self.config, drops, capture, capture_path) {
+                failed.get_or_insert(err);
+            }
+        }
+        if let Some(capture_path) = capture_path {
+            if outputs.iter().all(|o| o.capture_path != capture_path) {
+                if let Err(err) = remove_file(capture_path) {
+                    failed.get_or_insert(Error::file(capture_path, err));
+                }
+            }
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(outputs),
+        }
+    }
+}
+
+impl Sink for Outputs {
+    fn write_event(&mut self, raw: RawEvent) -> Result<Option<Event>> {
+        let iface = String::from_utf8_lossy(&raw.iface)
+            .trim_matches('\0')
+            .to_owned();
+        let station = match self.station_for(raw.netns, Some(&iface)) {
+            Some(station) => station,
+            None => return Ok(None),
+        };
+
+        // (sysfs only has the interfaces of the namespace of the service)
+        let phy = if Some(raw.netns) == self.own_netns {
+            self.iface_phys
+                .entry(iface.clone())
+                .or_insert_with(|| iface_phy(&iface))
+                .clone()
+        } else {
+            None
+        };
+        let event = match self.output(station.clone())? {
+            Some(output) => output.write_event(raw, phy)?,
+            None => return Ok(None),
+        };
+
+        // the streaming thread only ends if the connection failed, so stop sending
+        if let Some(stream) = &self.stream {
+            let message = Message::Event {
+                station,
+                event: event.clone(),
+            };
+            if stream.send(message).is_err() {
+                self.stream = None;
+            }
+        }
+        Ok(Some(event))
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let iface = self.phys.get(frame.phy()).cloned();
+        if let Some(station) = self.station_for(*frame.netns(), iface.as_deref()) {
+            if let Some(Output {
+                frames: Some(frames),
+                manifest,
+                ..
+            }) = self.output(station)?
+            {
+                write_frame(frames, frame);
+                manifest.counters.frames += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_metric(&mut self, metric: Metric) -> Result<()> {
+        if let Some(station) = self.station_for(*metric.netns(), Some(metric.iface())) {
+            if let Some(Output {
+                metrics: Some(metrics),
+                metrics_path,
+                manifest,
+                ..
+            }) = self.output(station)?
+            {
+                metric
+                    .append(metrics)
+                    .map_err(|err| Error::file(metrics_path, err))?;
+                manifest.counters.metrics += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_paths(&mut self, snapshot: PathSnapshot) -> Result<()> {
+        if let Some(station) = self.station_for(snapshot.netns, Some(&snapshot.iface)) {
+            if let Some(Output {
+                paths: Some(paths),
+                events_path,
+                manifest,
+                ..
+            }) = self.output(station)?
+            {
+                snapshot
+                    .append(paths)
+                    .map_err(|err| Error::file(&PathSnapshot::path(events_path), err))?;
+                manifest.counters.snapshots += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_neighbor(&mut self, sample: NeighborSample) -> Result<()> {
+        if let Some(station) = self.station_for(sample.netns, Some(&sample.iface)) {
+            if let Some(Output {
+                neighbors: Some(neighbors),
+                events_path,
+                manifest,
+                ..
+            }) = self.output(station)?
+            {
+                sample
+                    .append(neighbors)
+                    .map_err(|err| Error::file(&NeighborSample::path(events_path), err))?;
+                manifest.counters.neighbor_samples += 1;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{
+    error::{Error, Result},
+    netlink::{ifindex, route_socket, set_address, set_link_up, Socket},
+    nl80211::{iftype_name, Nl80211, IFTYPE_MESH_POINT, IFTYPE_MONITOR},
+};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::read_to_string,
+    io,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use libc::{IFF_UP, IFNAMSIZ};
+
+// centers of the 80 and 160 MHz channels at 5 GHz
+const CENTERS_80: [u32; 7] = [5210, 5290, 5530, 5610, 5690, 5775, 5855];
+const CENTERS_160: [u32; 3] = [5250, 5570, 5815];
+
+// width of the channel of the mesh, like 'iw mesh join' takes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelWidth {
+    NoHt,
+    Ht20,
+    Ht40Plus,
+    Ht40Minus,
+    Vht80,
+    Vht160,
+}
+
+impl FromStr for ChannelWidth {
+    type Err = String;
+
+    fn from_str(width: &str) -> std::result::Result<Self, Self::Err> {
+        match width.to_lowercase().as_str() {
+            "20-noht" | "noht" => Ok(Self::NoHt),
+            "20" | "ht20" => Ok(Self::Ht20),
+            "40+" | "ht40+" => Ok(Self::Ht40Plus),
+            "40-" | "ht40-" => Ok(Self::Ht40Minus),
+            "80" | "80mhz" => Ok(Self::Vht80),
+            "160" | "160mhz" => Ok(Self::Vht160),
+            _ => Err(format!(
+                "'{width}' isn't one of '20-noht', '20', '40+', '40-', '80' or '160'"
+            )),
+        }
+    }
+}
+
+impl Display for ChannelWidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let width = match self {
+            Self::NoHt => "20-noht",
+            Self::Ht20 => "20",
+            Self::Ht40Plus => "40+",
+            Self::Ht40Minus => "40-",
+            Self::Vht80 => "80",
+            Self::Vht160 => "160",
+        };
+        write!(f, "{width}")
+    }
+}
+
+impl ChannelWidth {
+    // width and center frequency, as nl80211 takes them
+    // ('None' if the channel doesn't have that width)
+    fn chandef(self, freq: u32) -> Option<(u32, u32)> {
+        let within = |centers: &[u32], half: u32| {
+            centers
+                .iter()
+                .find(|center| freq.abs_diff(**center) < half)
+                .copied()
+        };
+        match self {
+            Self::NoHt => Some((0, freq)),
+            Self::Ht20 => Some((1, freq)),
+            Self::Ht40Plus => Some((2, freq.checked_add(10)?)),
+            Self::Ht40Minus => Some((2, freq.checked_sub(10)?)),
+            Self::Vht80 => Some((3, within(&CENTERS_80, 40)?)),
+            Self::Vht160 => Some((5, within(&CENTERS_160, 80)?)),
+        }
+    }
+}
+
+// how the station joins the mesh
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    pub iface: String,
+    pub mesh_id: String,
+    pub freq: u32,
+    pub width: ChannelWidth,
+    pub address: Option<(Ipv4Addr, u8)>,
+}
+
+impl MeshConfig {
+    // name of the monitor interface that's created next to the mesh one
+    // (cut short to fit the names that the kernel allows, which are counted in bytes,
+    //  without splitting a character)
+    pub fn monitor(&self) -> String {
+        let mut name = format!("{}-mon", self.iface);
+        if name.len() >= IFNAMSIZ {
+            let mut cut = self.iface.len() - (name.len() - IFNAMSIZ + 1);
+            while !self.iface.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            name = format!("{}-mon", &self.iface[..cut]);
+        }
+        name
+    }
+}
+
+// what was done to the interfaces, so it can be undone
+#[derive(Debug)]
+enum Step {
+    Monitor(u32),
+    MonitorUp(u32),
+    MeshDown(u32),
+    Iftype {
+        ifindex: u32,
+        original: u32,
+    },
+    Address {
+        ifindex: u32,
+        addr: Ipv4Addr,
+        prefix: u8,
+    },
+    MeshUp(u32),
+    Joined(u32),
+}
+
+// mesh and monitor interfaces, set up like 'tester.sh' used to with 'iw' and 'ifconfig'
+// (everything is undone when it's dropped, whatever way the service ends)
+pub struct MeshSetup {
+    nl80211: Nl80211,
+    route: Socket,
+    monitor: String,
+    steps: Vec<Step>,
+}
+
+impl MeshSetup {
+    pub fn new(config: &MeshConfig) -> Result<Self> {
+        let fail = |step: &'static str, iface: &str| {
+            let iface = iface.to_string();
+            move |source: io::Error| Error::Setup {
+                step,
+                iface,
+                source,
+            }
+        };
+
+        let mesh =
+            ifindex(&config.iface).ok_or_else(|| Error::NoInterface(config.iface.clone()))?;
+        let monitor = config.monitor();
+        if ifindex(&monitor).is_some() {
+            return Err(Error::InterfaceExists(monitor));
+        }
+        let (width, center) = config.width.chandef(config.freq).ok_or(Error::Channel {
+            freq: config.freq,
+            width: config.width,
+        })?;
+
+        let mut setup = Self {
+            nl80211: Nl80211::new().map_err(fail("talk to nl80211 for", &config.iface))?,
+            route: route_socket().map_err(fail("talk to rtnetlink for", &config.iface))?,
+            monitor: monitor.clone(),
+            steps: vec![],
+        };
+
+        // check that the radio can do both
+        let interface = setup
+            .nl80211
+            .interface(mesh)
+            .map_err(fail("get the radio of", &config.iface))?;
+        let (supported, software) = setup
+            .nl80211
+            .iftypes(interface.wiphy)
+            .map_err(fail("get the capabilities of", &config.iface))?;
+        for iftype in [IFTYPE_MESH_POINT, IFTYPE_MONITOR] {
+            if !supported.contains(&iftype) && !software.contains(&iftype) {
+                return Err(Error::Unsupported {
+                    iface: config.iface.clone(),
+                    mode: iftype_name(iftype),
+                });
+            }
+        }
+
+        // monitor interface on the same radio
+        let created = setup
+            .nl80211
+            .new_interface(interface.wiphy, &monitor, IFTYPE_MONITOR)
+            .map_err(fail("create", &monitor))?;
+        let monitor_idx = created
+            .or_else(|| ifindex(&monitor))
+            .ok_or_else(|| Error::NoInterface(monitor.clone()))?;
+        setup.steps.push(Step::Monitor(monitor_idx));
+        set_link_up(&mut setup.route, monitor_idx, true).map_err(fail("bring up", &monitor))?;
+        setup.steps.push(Step::MonitorUp(monitor_idx));
+
+        // the type can only be changed while the interface is down
+        if is_up(&config.iface) {
+            set_link_up(&mut setup.route, mesh, false)
+                .map_err(fail("bring down", &config.iface))?;
+            setup.steps.push(Step::MeshDown(mesh));
+        }
+        if interface.iftype != IFTYPE_MESH_POINT {
+            setup
+                .nl80211
+                .set_iftype(mesh, IFTYPE_MESH_POINT)
+                .map_err(fail("switch to mesh point", &config.iface))?;
+            setup.steps.push(Step::Iftype {
+                ifindex: mesh,
+                original: interface.iftype,
+            });
+        }
+        if let Some((addr, prefix)) = config.address {
+            set_address(&mut setup.route, mesh, addr, prefix, true)
+                .map_err(fail("add the address to", &config.iface))?;
+            setup.steps.push(Step::Address {
+                ifindex: mesh,
+                addr,
+                prefix,
+            });
+        }
+        set_link_up(&mut setup.route, mesh, true).map_err(fail("bring up", &config.iface))?;
+        setup.steps.push(Step::MeshUp(mesh));
+
+        setup
+            .nl80211
+            .join_mesh(mesh, &config.mesh_id, config.freq, width, center)
+            .map_err(fail("join the mesh with", &config.iface))?;
+        setup.steps.push(Step::Joined(mesh));
+
+        Ok(setup)
+    }
+
+    // where packets are captured from
+    pub fn monitor(&self) -> &str {
+        &self.monitor
+    }
+
+    fn undo(&mut self, step: &Step) -> io::Result<()> {
+        match *step {
+            Step::Monitor(ifindex) => self.nl80211.del_interface(ifindex),
+            Step::MonitorUp(ifindex) | Step::MeshUp(ifindex) => {
+                set_link_up(&mut self.route, ifindex, false)
+            }
+            Step::MeshDown(ifindex) => set_link_up(&mut self.route, ifindex, true),
+            Step::Iftype { ifindex, original } => self.nl80211.set_iftype(ifindex, original),
+            Step::Address {
+                ifindex,
+                addr,
+                prefix,
+            } => set_address(&mut self.route, ifindex, addr, prefix, false),
+            Step::Joined(ifindex) => self.nl80211.leave_mesh(ifindex),
+        }
+    }
+}
+
+impl Drop for MeshSetup {
+    // undo the steps backwards, carrying on if one of them fails
+    fn drop(&mut self) {
+        while let Some(step) = self.steps.pop() {
+            if let Err(err) = self.undo(&step) {
+                eprintln!("Couldn't undo {step:?}: {err}");
+            }
+        }
+    }
+}
+
+// check if an interface is up, from its flags in sysfs
+fn is_up(iface: &str) -> bool {
+    read_to_string(format!("/sys/class/net/{iface}/flags"))
+        .ok()
+        .and_then(|flags| u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok())
+        .is_some_and(|flags| flags & IFF_UP as u32 != 0)
+}
@@ -1,38 +1,81 @@
+use crate::{
+    error::{Error, Result, BTF_PATH},
+    exporter::Stats,
+    nl80211::Nl80211,
+    output::{Output, Sink},
+    setup::ChannelWidth,
+};
+
 use mesh_analyzer::{
-    Event, RawEvent, TracerSkel, TracerSkelBuilder, CHECK_QOS, HAS_ADDR4, HAS_QOS, HDR_SIZE_3ADDR,
-    HDR_SIZE_4ADDR,
+    correlate_files, is_other_network, kernel_time, write_message, EventFormat, Frame, LinkLayer,
+    Message, Metric, NeighborSample, PathSnapshot, PcapngWriter, Radios, RawEvent, RawFrame,
+    RawMetric, StreamAddr, TracerSkel, TracerSkelBuilder,
 };
 
 use std::{
-    fs::File,
-    io::{Read, Write},
+    collections::{btree_map::Entry, BTreeMap, HashSet},
+    fs::{metadata, read_to_string, File},
+    io,
+    net::Ipv4Addr,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
     ptr::read,
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
         Arc, Mutex,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use byteorder::{ByteOrder, LittleEndian};
-use clap::{App, Arg, Command};
-use libbpf_rs::{RingBuffer, RingBufferBuilder};
-use pcap::{Capture, Offline};
-use serde_json::{from_slice, to_string};
+use clap::{value_parser, App, Arg, Command};
+use libbpf_rs::{MapFlags, RingBuffer, RingBufferBuilder};
+use libc::{geteuid, timeval, EOPNOTSUPP};
+use pcap::{Active, Capture, Error::TimeoutExpired, Linktype, Packet, PacketHeader, Savefile};
+
+// where 'ip netns' keeps the named network namespaces
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+// symbols of the kernel, to know which functions can be traced
+const KALLSYMS_PATH: &str = "/proc/kallsyms";
+
+// where frames that don't go through a TXQ are handed to the driver
+const NONQ_TX_FUNCTION: &str = "__ieee80211_tx";
+
+// static functions of the TSF adjustments and the power modes of peers, which can be inlined
+const TSF_FUNCTION: &str = "mesh_sync_adjust_tsf";
+const PEER_PM_FUNCTION: &str = "ieee80211_mps_rx_h_sta_process";
+
+// how long reading a packet waits before checking if the capture should stop
+const CAPTURE_TIMEOUT_MS: i32 = 100;
+
+// what correlation needs from an 802.11s mesh (control frames never match an event)
+const DEFAULT_CAPTURE_FILTER: &str = "type mgt or type data";
+
+// how often the samplers check if they should stop, while waiting for the next sample
+const SAMPLER_TICK: Duration = Duration::from_millis(100);
+
+// smallest snap length that still fits the radiotap header and the 802.11 header
+const MIN_SNAPLEN: i64 = 128;
 
 pub fn setup_cli_arg_parser() -> App<'static> {
     Command::new("Mesh Analyzer - Service")
         .author("Carlos Pinto <up201606191@up.pt>")
         .arg(
             Arg::new("STATION")
-                .required(true)
+                .required_unless_present("daemon")
                 .help("Name to be used for the output files."),
         )
         .arg(
             Arg::new("INTERFACE")
-                .required(true)
-                .help("Network interface to use for packet capture."),
+                .required_unless_present_any(&["kernel-capture", "split-iface", "daemon", "setup"])
+                .multiple_values(true)
+                .help(
+                    "Network interfaces to use for packet capture (one for each radio, \
+                     more than one are written to the same pcapng file).",
+                ),
         )
         .arg(
             Arg::new("force")
@@ -52,142 +95,1077 @@ pub fn setup_cli_arg_parser() -> App<'static> {
                 .long("verbose")
                 .help("Show verbose output."),
         )
+        .arg(
+            Arg::new("metrics")
+                .short('m')
+                .long("metrics")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("Sample the airtime link metric of each neighbor every <MS> milliseconds."),
+        )
+        .arg(
+            Arg::new("paths")
+                .long("paths")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64).range(1..))
+                .help(
+                    "Dump the mesh path tables of the kernel every <MS> milliseconds, \
+                     for central to check the events against.",
+                ),
+        )
+        .arg(
+            Arg::new("neighbors")
+                .long("neighbors")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64).range(1..))
+                .help(
+                    "Sample the signal, bitrate, retries and peer link of the neighbors \
+                     every <MS> milliseconds.",
+                ),
+        )
+        .arg(
+            Arg::new("kernel-capture")
+                .short('k')
+                .long("kernel-capture")
+                .conflicts_with("INTERFACE")
+                .help("Capture the frames from the kernel instead of using a monitor interface."),
+        )
+        .arg(
+            Arg::new("netns")
+                .short('n')
+                .long("netns")
+                .takes_value(true)
+                .value_name("NETNS")
+                .help(
+                    "Only trace the network namespace with this name (from 'ip netns') or inode.",
+                ),
+        )
+        .arg(
+            Arg::new("split-netns")
+                .short('s')
+                .long("split-netns")
+                .help("Write a different station for each network namespace."),
+        )
+        .arg(
+            Arg::new("split-iface")
+                .short('i')
+                .long("split-iface")
+                .takes_value(true)
+                .value_name("MAP")
+                .value_parser(parse_iface_map)
+                .conflicts_with("split-netns")
+                .help(
+                    "Write a different station for each interface, named with a map like \
+                     'wlan0=nodeA,wlan1=nodeB'. Each one can use its own interface for \
+                     packet capture with 'wlan0=nodeA:mon0'.",
+                ),
+        )
+        .arg(
+            Arg::new("stream")
+                .short('t')
+                .long("stream")
+                .takes_value(true)
+                .value_name("ADDR")
+                .value_parser(StreamAddr::from_str)
+                .help(
+                    "Also send the events to central while tracing, \
+                     to '<host>:<port>' or 'unix:<path>'.",
+                ),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .value_parser(EventFormat::from_str)
+                .default_value("json")
+                .help(
+                    "Format of the events file, 'json' or 'binary' \
+                     (smaller and faster for long traces).",
+                ),
+        )
+        .arg(
+            Arg::new("window")
+                .short('w')
+                .long("window")
+                .takes_value(true)
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .help(
+                    "How far apart in milliseconds a packet and an event can be \
+                     and still be related (5000 if omitted).",
+                ),
+        )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .takes_value(true)
+                .value_name("EXPR")
+                .default_value(DEFAULT_CAPTURE_FILTER)
+                .help(
+                    "Capture filter for the monitor interfaces, in the syntax of \
+                     'man pcap-filter' (an empty one keeps every frame).",
+                ),
+        )
+        .arg(
+            Arg::new("snaplen")
+                .long("snaplen")
+                .takes_value(true)
+                .value_name("BYTES")
+                .value_parser(value_parser!(u32).range(MIN_SNAPLEN..))
+                .conflicts_with("kernel-capture")
+                .help(
+                    "Only keep the first <BYTES> bytes of each packet \
+                     (enough for the headers, that correlation needs).",
+                ),
+        )
+        .arg(
+            Arg::new("mesh-id")
+                .long("mesh-id")
+                .takes_value(true)
+                .value_name("ID")
+                .conflicts_with("kernel-capture")
+                .help(
+                    "ID of the mesh, to leave out the beacons and probe responses \
+                     of other networks (and to join it with --setup).",
+                ),
+        )
+        .arg(
+            Arg::new("bssid")
+                .long("bssid")
+                .takes_value(true)
+                .value_name("MAC")
+                .value_parser(parse_mac)
+                .conflicts_with("kernel-capture")
+                .help("Only keep the frames to, from or through this address."),
+        )
+        .arg(
+            Arg::new("setup")
+                .long("setup")
+                .takes_value(true)
+                .value_name("IFACE")
+                .requires("mesh-id")
+                .conflicts_with("daemon")
+                .help(
+                    "Switch this interface to a mesh point and join the mesh with --mesh-id, \
+                     also capturing from a monitor interface created on the same radio \
+                     (everything is undone when the service stops).",
+                ),
+        )
+        .arg(
+            Arg::new("freq")
+                .long("freq")
+                .takes_value(true)
+                .value_name("MHZ")
+                .value_parser(value_parser!(u32))
+                .default_value("2412")
+                .help("Frequency of the channel of the mesh, with --setup."),
+        )
+        .arg(
+            Arg::new("width")
+                .long("width")
+                .takes_value(true)
+                .value_name("WIDTH")
+                .value_parser(ChannelWidth::from_str)
+                .default_value("40+")
+                .help(
+                    "Width of the channel of the mesh, with --setup \
+                     ('20-noht', '20', '40+', '40-', '80' or '160').",
+                ),
+        )
+        .arg(
+            Arg::new("ip")
+                .long("ip")
+                .takes_value(true)
+                .value_name("ADDR")
+                .value_parser(parse_ip)
+                .requires("setup")
+                .help("Address for the mesh interface, like '192.168.50.1/24', with --setup."),
+        )
+        .arg(
+            Arg::new("daemon")
+                .short('D')
+                .long("daemon")
+                .takes_value(true)
+                .value_name("ADDR")
+                .value_parser(StreamAddr::from_str)
+                .conflicts_with_all(&["STATION", "INTERFACE", "split-iface"])
+                .help(
+                    "Keep the eBPF program loaded and wait for capture sessions to be \
+                     started and stopped through a control socket at '<host>:<port>' \
+                     or 'unix:<path>'.",
+                ),
+        )
+        .arg(
+            Arg::new("exporter")
+                .long("exporter")
+                .takes_value(true)
+                .value_name("PORT")
+                .value_parser(value_parser!(u16).range(1..))
+                .help(
+                    "Serve counters of the service in the Prometheus text format \
+                     at http://localhost:<PORT>/metrics.",
+                ),
+        )
+}
+
+// station to be written for a traced interface
+#[derive(Debug, Clone)]
+pub struct IfaceStation {
+    pub iface: String,
+    pub station: String,
+    pub capture: Option<String>,
+}
+
+// parse a map like 'wlan0=nodeA:mon0,wlan1=nodeB' (the capture interface is optional)
+fn parse_iface_map(map: &str) -> std::result::Result<Vec<IfaceStation>, String> {
+    let mut stations = vec![];
+    for entry in map.split(',') {
+        let (iface, rest) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{entry}' isn't in the format 'IFACE=STATION[:CAPTURE]'"))?;
+        let (station, capture) = match rest.split_once(':') {
+            Some((station, capture)) => (station, Some(capture.to_string())),
+            None => (rest, None),
+        };
+        if iface.is_empty() || station.is_empty() || capture.as_deref() == Some("") {
+            return Err(format!("'{entry}' has an empty field"));
+        }
+        if stations.iter().any(|s: &IfaceStation| s.iface == iface) {
+            return Err(format!("interface {iface} shows up more than once"));
+        }
+        stations.push(IfaceStation {
+            iface: iface.to_string(),
+            station: station.to_string(),
+            capture,
+        });
+    }
+    Ok(stations)
+}
+
+// check if an address is in the format '00:11:22:33:44:55'
+fn parse_mac(addr: &str) -> std::result::Result<String, String> {
+    let octets = addr.split(':').collect::<Vec<_>>();
+    if octets.len() != 6
+        || octets
+            .iter()
+            .any(|o| o.len() != 2 || u8::from_str_radix(o, 16).is_err())
+    {
+        return Err(format!(
+            "'{addr}' isn't a MAC address like '00:11:22:33:44:55'"
+        ));
+    }
+    Ok(addr.to_lowercase())
+}
+
+// parse an address like '192.168.50.1/24'
+fn parse_ip(addr: &str) -> std::result::Result<(Ipv4Addr, u8), String> {
+    let invalid = || format!("'{addr}' isn't an address like '192.168.50.1/24'");
+    let (ip, prefix) = addr.split_once('/').ok_or_else(invalid)?;
+    let ip = ip.parse().map_err(|_| invalid())?;
+    match prefix.parse() {
+        Ok(prefix) if prefix <= 32 => Ok((ip, prefix)),
+        _ => Err(invalid()),
+    }
+}
+
+// what gets kept from the monitor interfaces
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+    pub filter: Option<String>,
+    pub snaplen: Option<u32>,
+    pub mesh_id: Option<String>,
+}
+
+impl CaptureOptions {
+    // the filter is narrowed down to an address, if there's one
+    pub fn new(
+        filter: Option<&str>,
+        bssid: Option<&str>,
+        snaplen: Option<u32>,
+        mesh_id: Option<String>,
+    ) -> Self {
+        let filter = filter.filter(|f| !f.trim().is_empty());
+        let filter = match (filter, bssid) {
+            (Some(filter), Some(bssid)) => Some(format!("({filter}) and wlan host {bssid}")),
+            (None, Some(bssid)) => Some(format!("wlan host {bssid}")),
+            (filter, None) => filter.map(str::to_string),
+        };
+        Self {
+            filter,
+            snaplen,
+            mesh_id,
+        }
+    }
+}
+
+// name of the radio (phy) of a network interface
+pub fn iface_phy(iface: &str) -> Option<String> {
+    read_to_string(format!("/sys/class/net/{iface}/phy80211/name"))
+        .ok()
+        .map(|phy| phy.trim().to_string())
 }
 
-pub fn initialize_events_file(filepath: &Path) -> Arc<Mutex<File>> {
-    let file = Arc::new(Mutex::new(
-        File::create(filepath).expect("create event file"),
-    ));
-    file.lock()
-        .expect("lock events file to write the head")
-        .write_all(b"[")
-        .expect("write events file head");
-    file
+// inode of a network namespace, from its name (from 'ip netns') or the inode itself
+pub fn netns_inode(netns: &str) -> Result<u32> {
+    if let Ok(inode) = netns.parse() {
+        return Ok(inode);
+    }
+    metadata(Path::new(NETNS_RUN_DIR).join(netns))
+        .map(|meta| meta.ino() as u32)
+        .map_err(|source| Error::Netns {
+            netns: netns.to_string(),
+            source,
+        })
+}
+
+// inode of the network namespace of the service
+pub fn own_netns() -> Option<u32> {
+    metadata("/proc/self/ns/net")
+        .ok()
+        .map(|meta| meta.ino() as u32)
+}
+
+// name of a network namespace (from 'ip netns'), or its inode if it doesn't have one
+pub fn netns_name(inode: u32) -> String {
+    named_netns()
+        .into_iter()
+        .find(|(netns, _)| *netns == inode)
+        .and_then(|(_, path)| Some(path.file_name()?.to_string_lossy().to_string()))
+        .unwrap_or_else(|| inode.to_string())
+}
+
+// network namespaces with a name from 'ip netns', by their inode
+fn named_netns() -> Vec<(u32, PathBuf)> {
+    let entries = match Path::new(NETNS_RUN_DIR).read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let meta = metadata(entry.path()).ok()?;
+            Some((meta.ino() as u32, entry.path()))
+        })
+        .collect()
+}
+
+// kernel functions that don't have a symbol (like static ones that were inlined),
+// so their probes can't be attached
+// (nothing is missing if the symbols can't be read, then libbpf says what fails)
+fn missing_functions<'a>(functions: &[&'a str]) -> Vec<&'a str> {
+    let symbols = match read_to_string(KALLSYMS_PATH) {
+        Ok(symbols) => symbols,
+        Err(_) => return vec![],
+    };
+    let present = symbols
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .collect::<HashSet<_>>();
+    functions
+        .iter()
+        .filter(|function| !present.contains(*function))
+        .copied()
+        .collect()
+}
+
+// check what loading the bpf program needs, to say what's missing before libbpf fails
+pub fn check_requirements() -> Result<()> {
+    if unsafe { geteuid() } != 0 {
+        return Err(Error::NotRoot);
+    }
+    if !Path::new(BTF_PATH).exists() {
+        return Err(Error::NoBtf);
+    }
+    Ok(())
 }
 
 // load the bpf program
-pub fn load_bpf_program(verbose: bool) -> TracerSkel<'static> {
+// (the metric probes are only loaded if there's an interval for them,
+//  and the frame probes only if the frames are captured from the kernel,
+//  while the probes of functions the kernel inlined are left out, saying what's missing)
+pub fn load_bpf_program(
+    verbose: bool,
+    metric_interval: Option<Duration>,
+    kernel_capture: bool,
+) -> Result<TracerSkel<'static>> {
     let mut skel_builder = TracerSkelBuilder::default();
     skel_builder.obj_builder.debug(verbose);
-    let skel_opened = skel_builder.open().expect("open the skeleton");
-    let mut skel = skel_opened.load().expect("load the skeleton");
-    skel.attach().expect("attach the skeleton");
-    skel
+    let mut skel_opened = skel_builder.open().map_err(|source| Error::Bpf {
+        stage: "open",
+        source,
+    })?;
+    match metric_interval {
+        Some(interval) => skel_opened.rodata().metric_interval = interval.as_nanos() as u64,
+        None => {
+            skel_opened
+                .progs_mut()
+                .throughput()
+                .set_autoload(false)
+                .expect("disable throughput probe");
+            skel_opened
+                .progs_mut()
+                .metric()
+                .set_autoload(false)
+                .expect("disable metric probe");
+        }
+    }
+    if !kernel_capture {
+        skel_opened
+            .progs_mut()
+            .frame_tx()
+            .set_autoload(false)
+            .expect("disable frame tx probe");
+        skel_opened
+            .progs_mut()
+            .frame_rx()
+            .set_autoload(false)
+            .expect("disable frame rx probe");
+    }
+    let missing = missing_functions(&[NONQ_TX_FUNCTION, TSF_FUNCTION, PEER_PM_FUNCTION]);
+    if missing.contains(&TSF_FUNCTION) {
+        eprintln!(
+            "TSF adjustments won't be traced ({TSF_FUNCTION} can't be traced in this kernel)"
+        );
+        skel_opened
+            .progs_mut()
+            .tsf_in()
+            .set_autoload(false)
+            .expect("disable tsf entry probe");
+        skel_opened
+            .progs_mut()
+            .tsf_out()
+            .set_autoload(false)
+            .expect("disable tsf exit probe");
+    }
+    if missing.contains(&PEER_PM_FUNCTION) {
+        eprintln!(
+            "Power mode changes of peers won't be traced \
+             ({PEER_PM_FUNCTION} can't be traced in this kernel)"
+        );
+        skel_opened
+            .progs_mut()
+            .peer_pm_in()
+            .set_autoload(false)
+            .expect("disable peer power mode entry probe");
+        skel_opened
+            .progs_mut()
+            .peer_pm_out()
+            .set_autoload(false)
+            .expect("disable peer power mode exit probe");
+    }
+    let nonq_tx = kernel_capture && !missing.contains(&NONQ_TX_FUNCTION);
+    if kernel_capture && !nonq_tx {
+        eprintln!(
+            "Frames of drivers without TXQs won't be captured \
+             ({NONQ_TX_FUNCTION} can't be traced in this kernel)"
+        );
+    }
+    if !nonq_tx {
+        skel_opened
+            .progs_mut()
+            .frame_tx_nonq()
+            .set_autoload(false)
+            .expect("disable frame tx probe without txqs");
+    }
+    let mut skel = skel_opened.load().map_err(|source| Error::Bpf {
+        stage: "load",
+        source,
+    })?;
+    skel.attach().map_err(|source| Error::Bpf {
+        stage: "attach",
+        source,
+    })?;
+    Ok(skel)
+}
+
+// number of submissions the kernel couldn't fit in the ring buffers so far
+pub fn read_drops(skel: &TracerSkel) -> u64 {
+    skel.maps()
+        .drops()
+        .lookup(&0u32.to_ne_bytes(), MapFlags::ANY)
+        .expect("read drops counter")
+        .map_or(0, |count| {
+            u64::from_ne_bytes(count[..8].try_into().expect("drops counter size"))
+        })
+}
+
+// the first error writing what comes from the kernel, which stops the service
+// (whatever comes after it is dropped, but the files are still finished)
+#[derive(Clone)]
+pub struct Failure {
+    error: Arc<Mutex<Option<Error>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Failure {
+    pub fn new(stop: &Arc<AtomicBool>) -> Self {
+        Self {
+            error: Arc::new(Mutex::new(None)),
+            stop: stop.clone(),
+        }
+    }
+
+    fn set(&self, err: Error) {
+        let mut error = self.error.lock().expect("lock failure");
+        if error.is_none() {
+            eprintln!("Stopping because of an error: {err}");
+            *error = Some(err);
+        }
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn take(&self) -> Option<Error> {
+        self.error.lock().expect("lock failure").take()
+    }
 }
 
 // create the ringbuffer to get data from the kernel
-pub fn create_ringbuffer(skel: &TracerSkel, events: Arc<Mutex<File>>, verbose: bool) -> RingBuffer {
-    let counter = Arc::new(AtomicUsize::new(1));
+// (everything gets handed to the sink, which decides the station it belongs to)
+pub fn create_ringbuffer<S: Sink + Send + 'static>(
+    skel: &TracerSkel,
+    outputs: Arc<Mutex<S>>,
+    stats: Arc<Stats>,
+    failure: Failure,
+    kernel_capture: bool,
+    metrics: bool,
+    verbose: bool,
+) -> Result<RingBuffer> {
     let mut ringbuf_builder = RingBufferBuilder::new();
+
+    let events = outputs.clone();
+    let event_stats = stats.clone();
+    let event_failure = failure.clone();
     ringbuf_builder
         .add(skel.maps().ringbuf(), move |data| {
             // callback function
             // parse raw bytes into an Event struct and save in the events file
-            let count = counter.fetch_add(1, Ordering::SeqCst);
-            let event_struct =
-                Event::from_raw(unsafe { read(data.as_ptr().cast::<RawEvent>()) }, count);
-            let event_serialized = if count == 1 {
-                to_string(&event_struct).expect("serialize event")
-            } else {
-                format!(",{}", to_string(&event_struct).expect("serialize event"))
-            };
-            events
+            // (counted even if no station keeps it)
+            let raw = unsafe { read(data.as_ptr().cast::<RawEvent>()) };
+            event_stats.count_event(&raw);
+            let written = events
                 .lock()
-                .expect("lock events file to write event")
-                .write_all(event_serialized.as_bytes())
-                .expect("write event to events file");
-            if verbose {
-                println!("Caught event ({})", event_struct.action());
+                .expect("lock outputs to write event")
+                .write_event(raw);
+            match written {
+                Ok(Some(event_struct)) if verbose => {
+                    println!("Caught event ({})", event_struct.action());
+                }
+                Ok(_) => {}
+                Err(err) => event_failure.set(err),
             }
             0
         })
-        .expect("add callback for ringbuffer");
-    ringbuf_builder.build().expect("create ringbuffer")
+        .map_err(Error::Ringbuffer)?;
+    if metrics {
+        let metrics = outputs.clone();
+        let metric_failure = failure.clone();
+        ringbuf_builder
+            .add(skel.maps().metric_ringbuf(), move |data| {
+                // callback function
+                // parse raw bytes into a Metric struct and append it to the metrics file of its station
+                let metric_struct =
+                    Metric::from_raw(unsafe { read(data.as_ptr().cast::<RawMetric>()) });
+                if verbose {
+                    println!(
+                        "Caught metric ({} -> {}: {})",
+                        metric_struct.mac(),
+                        metric_struct.peer(),
+                        metric_struct.metric()
+                    );
+                }
+                let pushed = metrics
+                    .lock()
+                    .expect("lock outputs to save metric")
+                    .push_metric(metric_struct);
+                if let Err(err) = pushed {
+                    metric_failure.set(err);
+                }
+                0
+            })
+            .map_err(Error::Ringbuffer)?;
+    }
+    if kernel_capture {
+        let frames = outputs;
+        ringbuf_builder
+            .add(skel.maps().frame_ringbuf(), move |data| {
+                // callback function
+                // parse raw bytes into a Frame struct and save it in the capture file
+                let frame_struct =
+                    Frame::from_raw(unsafe { read(data.as_ptr().cast::<RawFrame>()) });
+                stats.count_packet(frame_struct.phy());
+                let written = frames
+                    .lock()
+                    .expect("lock outputs to write frame")
+                    .write_frame(&frame_struct);
+                if let Err(err) = written {
+                    failure.set(err);
+                }
+                0
+            })
+            .map_err(Error::Ringbuffer)?;
+    }
+    ringbuf_builder.build().map_err(Error::Ringbuffer)
+}
+
+// send messages to central in another thread, so that the ringbuffer doesn't wait for the network
+// (if the connection fails, the thread ends and so does the streaming)
+pub fn start_stream(addr: StreamAddr) -> (Sender<Message>, JoinHandle<()>) {
+    let (sender, receiver) = channel::<Message>();
+    let handle = thread::spawn(move || {
+        let mut stream = match addr.connect() {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Can't stream events to {addr}: {err}");
+                return;
+            }
+        };
+        for message in receiver {
+            if let Err(err) = write_message(&mut stream, &message) {
+                eprintln!("Stopped streaming events to {addr}: {err}");
+                return;
+            }
+        }
+    });
+    (sender, handle)
+}
+
+// create the capture file for the frames that come from the kernel
+// (pcap only says why it couldn't be created in its message)
+pub fn create_kernel_capture_file(capture_file: &Path) -> Result<Savefile> {
+    Capture::dead(Linktype::IEEE802_11_RADIOTAP)
+        .and_then(|capture| capture.savefile(capture_file))
+        .map_err(|err| Error::file(capture_file, io::Error::other(err.to_string())))
+}
+
+// write a frame from the kernel with a synthetic radiotap header
+pub fn write_frame(file: &mut Savefile, frame: &Frame) {
+    let mut data = frame.radiotap();
+    let radiotap_len = data.len() as u32;
+    data.extend_from_slice(frame.data());
+
+    let ts = frame
+        .ts()
+        .duration_since(UNIX_EPOCH)
+        .expect("get frame timestamp");
+    let header = PacketHeader {
+        ts: timeval {
+            tv_sec: ts.as_secs() as _,
+            tv_usec: ts.subsec_micros() as _,
+        },
+        caplen: data.len() as u32,
+        len: radiotap_len + frame.frame_len(),
+    };
+    file.write(&Packet::new(&header, &data));
+}
+
+// where the packets of a capture thread get written to
+pub enum CaptureSink {
+    Pcap(Savefile),
+    // (shared by the threads of all the devices, each with its own interface)
+    Pcapng {
+        writer: Arc<Mutex<PcapngWriter<File>>>,
+        interface: u32,
+        path: PathBuf,
+    },
+}
+
+impl CaptureSink {
+    // (pcap doesn't say if writing failed, only pcapng does)
+    fn write(&mut self, pkt: &Packet) -> Result<()> {
+        match self {
+            Self::Pcap(file) => file.write(pkt),
+            Self::Pcapng {
+                writer,
+                interface,
+                path,
+            } => {
+                let ts = UNIX_EPOCH
+                    + Duration::new(
+                        pkt.header.ts.tv_sec as u64,
+                        pkt.header.ts.tv_usec as u32 * 1000,
+                    );
+                writer
+                    .lock()
+                    .expect("lock capture file to write packet")
+                    .write_packet(*interface, ts, pkt.header.len, pkt.data)
+                    .map_err(|err| Error::file(path, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Self::Pcapng { writer, path, .. } = self {
+            writer
+                .lock()
+                .expect("lock capture file to flush it")
+                .flush()
+                .map_err(|err| Error::file(path, err))?;
+        }
+        Ok(())
+    }
+}
+
+// open a monitor interface for capture, with the filter and snap length of the options
+// (done before the thread starts, so that a wrong interface or filter is reported before tracing)
+pub fn open_capture(interface: &str, options: &CaptureOptions) -> Result<Capture<Active>> {
+    let capture_error = |source| Error::Capture {
+        interface: interface.to_string(),
+        source,
+    };
+    let mut capture = Capture::from_device(interface)
+        .map_err(capture_error)?
+        .immediate_mode(true)
+        .timeout(CAPTURE_TIMEOUT_MS)
+        .promisc(true);
+    if let Some(snaplen) = options.snaplen {
+        capture = capture.snaplen(snaplen as i32);
+    }
+    let mut pcap = capture.open().map_err(capture_error)?;
+    if let Some(filter) = &options.filter {
+        pcap.filter(filter, true).map_err(|source| Error::Filter {
+            filter: filter.clone(),
+            source,
+        })?;
+    }
+    Ok(pcap)
+}
+
+// capture network packets in another thread, until 'stop' is set
+// (reading blocks until a packet arrives or the timeout expires, so that the thread sleeps
+//  while there's no traffic and still sees 'stop' in time, and the capture file is closed
+//  when the last thread writing to it ends, which is what joining them waits for)
+// (if the capture file can't be written, the thread ends with the error)
+pub fn start_packet_capture(
+    stop: Arc<AtomicBool>,
+    mut pcap: Capture<Active>,
+    mut sink: CaptureSink,
+    interface: String,
+    options: &CaptureOptions,
+    stats: Arc<Stats>,
+) -> JoinHandle<Result<usize>> {
+    // the mesh id is only in the frame, after whatever the interface puts in front of it
+    let link = LinkLayer::from_linktype(pcap.get_datalink().0 as u16);
+    let mesh_id = options.mesh_id.clone().map(String::into_bytes);
+
+    thread::spawn(move || {
+        let mut packets = 0;
+        while !stop.load(Ordering::Relaxed) {
+            match pcap.next() {
+                Ok(pkt) => {
+                    if let (Some(mesh_id), Some(link)) = (&mesh_id, link) {
+                        let other = link
+                            .preamble_len(pkt.data)
+                            .is_some_and(|len| is_other_network(&pkt.data[len..], mesh_id));
+                        if other {
+                            continue;
+                        }
+                    }
+                    if let Err(err) = sink.write(&pkt) {
+                        eprintln!("Stopped capturing packets on {interface}: {err}");
+                        return Err(err);
+                    }
+                    packets += 1;
+                    stats.count_packet(&interface);
+                }
+                Err(TimeoutExpired) => {}
+                Err(err) => {
+                    eprintln!("Stopped capturing packets on {interface}: {err}");
+                    break;
+                }
+            }
+        }
+        if let Ok(stats) = pcap.stats() {
+            if stats.dropped > 0 || stats.if_dropped > 0 {
+                eprintln!(
+                    "Capture on {interface} dropped {} packets ({} by the interface).",
+                    stats.dropped, stats.if_dropped
+                );
+            }
+        }
+        sink.flush()?;
+        Ok(packets)
+    })
 }
 
-// capture network packets in another thread
-pub fn start_packet_capture(stop: Arc<AtomicBool>, capture_file: PathBuf, interface: String) {
+// take a sample every 'interval' in its own thread, until 'stop' is set
+fn start_sampler(
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    mut sample: impl FnMut() + Send + 'static,
+) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut pcap = Capture::from_device(interface.as_str())
-            .expect("create capture")
-            .immediate_mode(true)
-            .timeout(100)
-            .promisc(true)
-            .open()
-            .expect("activate capture")
-            .setnonblock()
-            .expect("set capture to nonblock");
-        let mut file = pcap.savefile(capture_file).expect("create capture file");
+        let mut next = Instant::now();
         while !stop.load(Ordering::Relaxed) {
-            if let Ok(pkt) = pcap.next() {
-                file.write(&pkt);
+            let now = Instant::now();
+            if now < next {
+                thread::sleep((next - now).min(SAMPLER_TICK));
+                continue;
             }
+            // (a sample that took longer than the interval isn't made up for)
+            next = (next + interval).max(now);
+            sample();
         }
-    });
+    })
 }
 
-// read data from files
-// (we save in files while capturing just in case something crashes)
-pub fn get_collected_data(
-    events_file: &Path,
-    capture_file: &Path,
-) -> (Vec<Event>, Capture<Offline>) {
-    let mut events = vec![];
-    File::open(events_file)
-        .expect("open events file")
-        .read_to_end(&mut events)
-        .expect("read events file");
-    let events = from_slice::<Vec<Event>>(&events).expect("deserialize events file");
-    let capture = Capture::from_file(capture_file).expect("open packet capture file");
-    (events, capture)
-}
-
-// match packets to events
-pub fn event_matches_packet(event: &Event, data: &[u8], len: usize) -> bool {
-    if len < 4 {
-        return false;
-    }
-
-    // get the length of layer 1 and check that
-    // the first two bytes of layer 2 can be read
-    let layer1_len = LittleEndian::read_u16(&data[2..=3]) as usize;
-    if len < layer1_len + 2 {
-        return false;
-    }
-
-    // reposition the 'data' "pointer"
-    let data = &data[layer1_len..];
-
-    // get the length of layer 2 and
-    // make sure we have access to everything we need
-    let frm_ctrl = LittleEndian::read_u16(&data[0..=1]);
-    let layer2_len = if frm_ctrl & HAS_ADDR4 == HAS_ADDR4 {
-        HDR_SIZE_4ADDR
-    } else {
-        HDR_SIZE_3ADDR
-    } as usize
-        + if frm_ctrl & CHECK_QOS == HAS_QOS {
-            2 // qos section is 2 bytes
-        } else {
-            0 // no qos
+// nl80211 in every network namespace being traced, opened as the namespaces show up
+// (besides the one of the service, only the ones named by 'ip netns' can be entered,
+//  and the socket of a namespace that goes away is closed, so that it doesn't keep it alive)
+struct NetnsSockets {
+    // the only namespace being traced
+    netns: Option<u32>,
+    own: u32,
+    // ('None' if it couldn't be entered, so that it isn't tried again)
+    sockets: BTreeMap<u32, Option<Nl80211>>,
+}
+
+impl NetnsSockets {
+    // (the namespace being traced, or the one of the service, must be there right away)
+    fn open(netns: Option<u32>) -> Result<Self> {
+        let own = own_netns().unwrap_or_default();
+        let mut sockets = BTreeMap::new();
+        match netns {
+            Some(netns) if netns != own => {
+                let (_, path) = named_netns()
+                    .into_iter()
+                    .find(|(named, _)| *named == netns)
+                    .ok_or(Error::SampleNetns(netns))?;
+                sockets.insert(
+                    netns,
+                    Some(Nl80211::in_netns(&path).map_err(Error::Nl80211)?),
+                );
+            }
+            _ => {
+                sockets.insert(own, Some(Nl80211::new().map_err(Error::Nl80211)?));
+            }
+        }
+        let mut sockets = Self {
+            netns,
+            own,
+            sockets,
         };
-    if len < layer1_len + layer2_len {
-        return false;
+        sockets.refresh();
+        Ok(sockets)
     }
 
-    // finally safe, get everything else
-    let seq_ctrl = LittleEndian::read_u16(&data[22..=23]);
-    let qos_ctrl = if frm_ctrl & CHECK_QOS == HAS_QOS {
-        Some(LittleEndian::read_u16(&data[(layer2_len - 2)..layer2_len]))
-    } else {
-        None
-    };
-    let addr1 = &data[4..=9];
-    let addr2 = &data[10..=15];
-
-    frm_ctrl == *event.frm_ctrl()
-        && seq_ctrl == *event.seq_ctrl()
-        && qos_ctrl == *event.qos_ctrl()
-        && event.compare_addr1(addr1)
-        && event.compare_addr2(addr2)
+    // open sockets in the namespaces named since the last time,
+    // and close the ones of the namespaces that aren't there anymore
+    fn refresh(&mut self) {
+        if self.netns.is_some() {
+            return;
+        }
+        let named = named_netns();
+        let own = self.own;
+        self.sockets
+            .retain(|netns, _| *netns == own || named.iter().any(|(named, _)| named == netns));
+        for (netns, path) in named {
+            if let Entry::Vacant(entry) = self.sockets.entry(netns) {
+                let nl80211 = Nl80211::in_netns(&path)
+                    .map_err(|err| {
+                        eprintln!("Can't sample network namespace {}: {err}", path.display())
+                    })
+                    .ok();
+                entry.insert(nl80211);
+            }
+        }
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (u32, &mut Nl80211)> {
+        self.sockets
+            .iter_mut()
+            .filter_map(|(netns, nl80211)| Some((*netns, nl80211.as_mut()?)))
+    }
+}
+
+// samplers of the kernel through nl80211, each with its own sockets and interval
+// (opened before tracing, so that they fail right away)
+pub struct Samplers {
+    paths: Option<(NetnsSockets, Duration)>,
+    neighbors: Option<(NetnsSockets, Duration)>,
+}
+
+impl Samplers {
+    pub fn open(
+        paths: Option<Duration>,
+        neighbors: Option<Duration>,
+        netns: Option<u32>,
+    ) -> Result<Self> {
+        Ok(Self {
+            paths: paths
+                .map(|interval| NetnsSockets::open(netns).map(|sockets| (sockets, interval)))
+                .transpose()?,
+            neighbors: neighbors
+                .map(|interval| NetnsSockets::open(netns).map(|sockets| (sockets, interval)))
+                .transpose()?,
+        })
+    }
+
+    // the threads stop once 'stop' is set
+    pub fn start<S: Sink + Send + 'static>(
+        self,
+        sink: Arc<Mutex<S>>,
+        stop: &Arc<AtomicBool>,
+        failure: &Failure,
+        verbose: bool,
+    ) -> Vec<JoinHandle<()>> {
+        let mut handles = vec![];
+        if let Some((sockets, interval)) = self.paths {
+            handles.push(start_path_sampler(
+                sockets,
+                sink.clone(),
+                interval,
+                stop.clone(),
+                failure.clone(),
+                verbose,
+            ));
+        }
+        if let Some((sockets, interval)) = self.neighbors {
+            handles.push(start_neighbor_sampler(
+                sockets,
+                sink,
+                interval,
+                stop.clone(),
+                failure.clone(),
+                verbose,
+            ));
+        }
+        handles
+    }
+}
+
+// dump the mesh path tables of the kernel every 'interval', until 'stop' is set
+// (in every namespace being traced)
+fn start_path_sampler<S: Sink + Send + 'static>(
+    mut sockets: NetnsSockets,
+    sink: Arc<Mutex<S>>,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    failure: Failure,
+    verbose: bool,
+) -> JoinHandle<()> {
+    start_sampler(interval, stop, move || {
+        sockets.refresh();
+        for (netns, nl80211) in sockets.iter_mut() {
+            match snapshot_paths(nl80211, netns) {
+                Ok(snapshots) => {
+                    if verbose {
+                        for snapshot in &snapshots {
+                            println!(
+                                "Dumped mesh paths ({}: {} paths, {} proxies)",
+                                snapshot.iface,
+                                snapshot.paths.len(),
+                                snapshot.proxies.len()
+                            );
+                        }
+                    }
+                    let mut sink = sink.lock().expect("lock outputs to save path snapshot");
+                    for snapshot in snapshots {
+                        if let Err(err) = sink.push_paths(snapshot) {
+                            failure.set(err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => eprintln!(
+                    "Can't dump the mesh paths of network namespace {}: {err}",
+                    netns_name(netns)
+                ),
+            }
+        }
+    })
+}
+
+// sample the statistics of the neighbors of every mesh interface every 'interval',
+// until 'stop' is set (in every namespace being traced, like the paths)
+fn start_neighbor_sampler<S: Sink + Send + 'static>(
+    mut sockets: NetnsSockets,
+    sink: Arc<Mutex<S>>,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    failure: Failure,
+    verbose: bool,
+) -> JoinHandle<()> {
+    start_sampler(interval, stop, move || {
+        sockets.refresh();
+        for (netns, nl80211) in sockets.iter_mut() {
+            match sample_neighbors(nl80211, netns) {
+                Ok(samples) => {
+                    if verbose {
+                        println!("Sampled {} neighbors", samples.len());
+                    }
+                    let mut sink = sink.lock().expect("lock outputs to save neighbor sample");
+                    for sample in samples {
+                        if let Err(err) = sink.push_neighbor(sample) {
+                            failure.set(err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => eprintln!(
+                    "Can't dump the neighbors of network namespace {}: {err}",
+                    netns_name(netns)
+                ),
+            }
+        }
+    })
+}
+
+// path tables of every mesh interface
+// (each one is timestamped right before it's dumped, and kernels without the proxy table
+//  just don't have any proxies)
+fn snapshot_paths(nl80211: &mut Nl80211, netns: u32) -> io::Result<Vec<PathSnapshot>> {
+    let mut snapshots = vec![];
+    for iface in nl80211.mesh_interfaces()? {
+        let ts = kernel_time();
+        let paths = nl80211.mesh_paths(iface.ifindex)?;
+        let proxies = match nl80211.proxy_paths(iface.ifindex) {
+            Err(err) if err.raw_os_error() == Some(EOPNOTSUPP) => vec![],
+            proxies => proxies?,
+        };
+        snapshots.push(PathSnapshot {
+            ts,
+            iface: iface.name,
+            mac: iface.mac,
+            netns,
+            paths,
+            proxies,
+        });
+    }
+    Ok(snapshots)
+}
+
+// station dumps of every mesh interface
+// (timestamped right before each one, like the path tables)
+fn sample_neighbors(nl80211: &mut Nl80211, netns: u32) -> io::Result<Vec<NeighborSample>> {
+    let mut samples = vec![];
+    for iface in nl80211.mesh_interfaces()? {
+        let ts = kernel_time();
+        samples.extend(nl80211.stations(&iface, ts, netns)?);
+    }
+    Ok(samples)
+}
+
+// relate the events of a station to the packets of its capture,
+// and rewrite its events file with the result
+pub fn correlate(output: &Output, window: Duration, verbose: bool) -> Result<()> {
+    let events_file = output.events_path();
+    let correlation = correlate_files(
+        events_file,
+        output.capture_path(),
+        events_file,
+        &Radios::from_manifest(output.manifest()),
+        window,
+        verbose,
+    )?;
+    println!(
+        "Correlated {}: {}",
+        events_file.display(),
+        correlation.stats
+    );
+    Ok(())
 }
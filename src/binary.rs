@@ -0,0 +1,220 @@
+use crate::{
+    schema::{EventsFile, Header},
+    Action, Event, MacAddr, PowerMode,
+};
+
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_json::{from_slice, to_vec};
+
+// binary events files start with these bytes followed by the version of the encoding,
+// then every record is prefixed by its length (the header as JSON, and then the events)
+// 1: first version
+// 2: packets of events with their score
+// 3: retries of events
+pub const BINARY_MAGIC: &[u8; 4] = b"MEVB";
+const BINARY_VERSION: u16 = 3;
+
+pub fn write_binary_head<W: Write + ?Sized>(writer: &mut W, header: &Header) -> Result<()> {
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_u16::<LittleEndian>(BINARY_VERSION)?;
+    write_record(writer, &to_vec(header)?)
+}
+
+pub fn write_binary_event<W: Write + ?Sized>(writer: &mut W, event: &Event) -> Result<()> {
+    let mut data = Vec::with_capacity(128);
+    event.encode(&mut data)?;
+    write_record(writer, &data)
+}
+
+fn write_record<W: Write + ?Sized>(writer: &mut W, data: &[u8]) -> Result<()> {
+    let mut record = Vec::with_capacity(data.len() + 4);
+    record.write_u32::<LittleEndian>(data.len() as u32)?;
+    record.extend_from_slice(data);
+    writer.write_all(&record)
+}
+
+// read a binary events file
+// (a record that was cut short is ignored, since that's what a crash leaves behind)
+pub fn read_binary(data: &[u8]) -> Result<EventsFile> {
+    let mut cursor = Cursor::new(data);
+    let mut magic = [0; 4];
+    cursor.read_exact(&mut magic)?;
+    if &magic != BINARY_MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "not a binary events file",
+        ));
+    }
+    let version = cursor.read_u16::<LittleEndian>()?;
+    if version == 0 || version > BINARY_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported binary events version {version} (up to {BINARY_VERSION})"),
+        ));
+    }
+
+    let header = match read_record(&mut cursor) {
+        Some(record) => from_slice::<Header>(record)?,
+        None => return Err(Error::new(ErrorKind::InvalidData, "header is cut short")),
+    };
+    let mut events = vec![];
+    while let Some(record) = read_record(&mut cursor) {
+        events.push(Event::decode(&mut Cursor::new(record), version)?);
+    }
+    Ok(EventsFile { header, events })
+}
+
+fn read_record<'a>(cursor: &mut Cursor<&'a [u8]>) -> Option<&'a [u8]> {
+    let len = cursor.read_u32::<LittleEndian>().ok()? as usize;
+    let data = *cursor.get_ref();
+    let start = cursor.position() as usize;
+    let record = data.get(start..start + len)?;
+    cursor.set_position((start + len) as u64);
+    Some(record)
+}
+
+// the pieces events are made of
+
+pub(crate) fn write_mac(out: &mut Vec<u8>, mac: &MacAddr) -> Result<()> {
+    out.extend_from_slice(&mac.0);
+    Ok(())
+}
+
+pub(crate) fn read_mac(cursor: &mut Cursor<&[u8]>) -> Result<MacAddr> {
+    let mut mac = [0; 6];
+    cursor.read_exact(&mut mac)?;
+    Ok(MacAddr(mac))
+}
+
+pub(crate) fn write_str(out: &mut Vec<u8>, value: &str) -> Result<()> {
+    out.write_u16::<LittleEndian>(value.len() as u16)?;
+    out.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+pub(crate) fn read_str(cursor: &mut Cursor<&[u8]>) -> Result<String> {
+    let mut value = vec![0; cursor.read_u16::<LittleEndian>()? as usize];
+    cursor.read_exact(&mut value)?;
+    String::from_utf8(value).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+// optional values are a byte saying if there's a value, followed by the value
+pub(crate) fn write_opt<T>(
+    out: &mut Vec<u8>,
+    value: &Option<T>,
+    write: impl FnOnce(&mut Vec<u8>, &T) -> Result<()>,
+) -> Result<()> {
+    match value {
+        Some(value) => {
+            out.push(1);
+            write(out, value)
+        }
+        None => {
+            out.push(0);
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn read_opt<'a, T>(
+    cursor: &mut Cursor<&'a [u8]>,
+    read: impl FnOnce(&mut Cursor<&'a [u8]>) -> Result<T>,
+) -> Result<Option<T>> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        _ => read(cursor).map(Some),
+    }
+}
+
+// actions and power modes are saved by name, like in JSON
+pub(crate) fn write_action(out: &mut Vec<u8>, action: &Action) -> Result<()> {
+    write_str(out, action.name())
+}
+
+pub(crate) fn read_action(cursor: &mut Cursor<&[u8]>) -> Result<Action> {
+    let name = read_str(cursor)?;
+    Action::from_name(&name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown action {name}")))
+}
+
+pub(crate) fn write_pm(out: &mut Vec<u8>, pm: &PowerMode) -> Result<()> {
+    write_str(out, pm.name())
+}
+
+pub(crate) fn read_pm(cursor: &mut Cursor<&[u8]>) -> Result<PowerMode> {
+    let name = read_str(cursor)?;
+    PowerMode::from_name(&name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown power mode {name}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::schema::StationInfo;
+
+    use serde_json::{from_value, json, to_value};
+
+    fn event(pkts: &[usize]) -> Event {
+        from_value(json!({
+            "id": 7,
+            "ts": 1_650_000_000_123_456_789u64,
+            "pkts": pkts.iter().map(|pkt| json!({ "pkt": pkt, "score": 0.5 })).collect::<Vec<_>>(),
+            "retries": 0,
+            "action": "RX_ADD",
+            "mac": [2, 0, 0, 0, 0, 1],
+            "iface": "mesh0",
+            "netns": 4026531840u32,
+            "ifindex": 3,
+            "dst": [2, 0, 0, 0, 0, 4],
+            "frm_ctrl": 0x0088,
+            "seq_ctrl": 0x10,
+            "qos_ctrl": 5,
+            "addr1": [2, 0, 0, 0, 0, 2],
+            "addr2": [2, 0, 0, 0, 0, 1],
+            "addr3": [2, 0, 0, 0, 0, 4],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let station = StationInfo {
+            name: "nodeA".to_string(),
+            ..StationInfo::default()
+        };
+        let events = [event(&[]), event(&[3, 5])];
+        let mut data = vec![];
+        write_binary_head(&mut data, &Header::new(station)).unwrap();
+        for event in &events {
+            write_binary_event(&mut data, event).unwrap();
+        }
+
+        let file = read_binary(&data).unwrap();
+        assert_eq!(file.header.station.name, "nodeA");
+        assert_eq!(to_value(&file.events).unwrap(), to_value(&events).unwrap());
+    }
+
+    // (packets were only their number and there were no retries)
+    #[test]
+    fn packets_of_version_1() {
+        let mut record = vec![];
+        event(&[]).encode(&mut record).unwrap();
+        let mut old = record[..16].to_vec();
+        old.write_u32::<LittleEndian>(2).unwrap();
+        old.write_u64::<LittleEndian>(3).unwrap();
+        old.write_u64::<LittleEndian>(5).unwrap();
+        old.extend_from_slice(&record[24..]);
+
+        let decoded = Event::decode(&mut Cursor::new(&old[..]), 1).unwrap();
+        let scores = decoded
+            .pkts()
+            .iter()
+            .map(|pkt| (pkt.pkt, pkt.score))
+            .collect::<Vec<_>>();
+        assert_eq!(scores, [(3, 1.0), (5, 1.0)]);
+        assert_eq!(*decoded.retries(), 0);
+    }
+}
@@ -1,19 +1,33 @@
 use super::BOOT_TIME;
 
-use crate::{Action, MacAddr, RawEvent, CHECK_QOS, HAS_ADDR4, HAS_QOS};
+use crate::{
+    binary::{
+        read_action, read_mac, read_opt, read_pm, read_str, write_action, write_mac, write_opt,
+        write_pm, write_str,
+    },
+    Action, MacAddr, PowerMode, RawEvent, CHECK_QOS, HAS_ADDR4, HAS_QOS,
+};
 
-use std::time::{Duration, SystemTime};
+use std::{
+    io::{Cursor, Error, ErrorKind, Result},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     id: usize,
+    #[serde(with = "crate::schema::ts_nanos")]
     ts: SystemTime,
-    pkts: Vec<usize>,
+    pkts: Vec<PacketMatch>,
+    retries: u32,
     action: Action,
     mac: MacAddr,
     iface: String,
+    netns: u32,
+    ifindex: i32,
     dst: MacAddr,
     old_nh: Option<MacAddr>,
     new_nh: Option<MacAddr>,
@@ -24,8 +38,26 @@ pub struct Event {
     addr2: MacAddr,
     addr3: MacAddr,
     addr4: Option<MacAddr>,
+    tsf_drift: Option<i64>,
+    tsf_adjust: Option<i64>,
+    peer: Option<MacAddr>,
+    old_pm: Option<PowerMode>,
+    new_pm: Option<PowerMode>,
 }
 
+// packet of the capture that might have caused an event
+// (the score is how likely it is to be the one on its own, from 0 to 1,
+//  so that a single weak match doesn't look certain)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PacketMatch {
+    pub pkt: usize,
+    pub score: f32,
+}
+
+// score of the packets from files written before there were scores
+// (they matched, but how likely they were isn't known)
+pub(crate) const LEGACY_SCORE: f32 = 1.0;
+
 impl Event {
     // transform a `RawEvent` into an `Event`
     pub fn from_raw(raw: RawEvent, count: usize) -> Self {
@@ -51,6 +83,20 @@ impl Event {
                 },
                 None,
             ),
+            Action::ACT_KR_TSF | Action::ACT_KR_LOCAL_PM | Action::ACT_KR_PEER_PM => (None, None),
+        };
+
+        let (tsf_drift, tsf_adjust) = if raw.action == Action::ACT_KR_TSF {
+            (Some(raw.tsf_drift), Some(raw.tsf_adjust))
+        } else {
+            (None, None)
+        };
+
+        let (peer, old_pm, new_pm) = match raw.action {
+            Action::ACT_KR_LOCAL_PM | Action::ACT_KR_PEER_PM => {
+                (Some(MacAddr(raw.peer)), Some(raw.old_pm), Some(raw.new_pm))
+            }
+            _ => (None, None, None),
         };
 
         let qos_ctrl = if raw.frm_ctrl & CHECK_QOS == HAS_QOS {
@@ -69,6 +115,7 @@ impl Event {
             id: count,
             ts: *BOOT_TIME + Duration::from_nanos(raw.ts),
             pkts: Vec::with_capacity(1),
+            retries: 0,
             action: raw.action,
             mac: MacAddr(raw.mac),
             iface: unsafe {
@@ -76,6 +123,8 @@ impl Event {
                     .trim_matches('\0')
                     .to_owned()
             },
+            netns: raw.netns,
+            ifindex: raw.ifindex,
             dst: MacAddr(raw.dst),
             old_nh,
             new_nh,
@@ -86,12 +135,130 @@ impl Event {
             addr2: MacAddr(raw.addr2),
             addr3: MacAddr(raw.addr3),
             addr4,
+            tsf_drift,
+            tsf_adjust,
+            peer,
+            old_pm,
+            new_pm,
         }
     }
 
+    // binary encoding of an event, with the fields in the same order as the struct
+    // (the packets only had their number before version 2, and there were no retries before 3)
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        let ts = self
+            .ts
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "event from before the epoch"))?;
+        out.write_u64::<LittleEndian>(self.id as u64)?;
+        out.write_u64::<LittleEndian>(ts.as_nanos() as u64)?;
+        out.write_u32::<LittleEndian>(self.pkts.len() as u32)?;
+        for pkt in &self.pkts {
+            out.write_u64::<LittleEndian>(pkt.pkt as u64)?;
+            out.write_f32::<LittleEndian>(pkt.score)?;
+        }
+        out.write_u32::<LittleEndian>(self.retries)?;
+        write_action(out, &self.action)?;
+        write_mac(out, &self.mac)?;
+        write_str(out, &self.iface)?;
+        out.write_u32::<LittleEndian>(self.netns)?;
+        out.write_i32::<LittleEndian>(self.ifindex)?;
+        write_mac(out, &self.dst)?;
+        write_opt(out, &self.old_nh, write_mac)?;
+        write_opt(out, &self.new_nh, write_mac)?;
+        out.write_u16::<LittleEndian>(self.frm_ctrl)?;
+        out.write_u16::<LittleEndian>(self.seq_ctrl)?;
+        write_opt(out, &self.qos_ctrl, |out, qos| {
+            out.write_u16::<LittleEndian>(*qos)
+        })?;
+        write_mac(out, &self.addr1)?;
+        write_mac(out, &self.addr2)?;
+        write_mac(out, &self.addr3)?;
+        write_opt(out, &self.addr4, write_mac)?;
+        write_opt(out, &self.tsf_drift, |out, v| {
+            out.write_i64::<LittleEndian>(*v)
+        })?;
+        write_opt(out, &self.tsf_adjust, |out, v| {
+            out.write_i64::<LittleEndian>(*v)
+        })?;
+        write_opt(out, &self.peer, write_mac)?;
+        write_opt(out, &self.old_pm, write_pm)?;
+        write_opt(out, &self.new_pm, write_pm)
+    }
+
+    pub(crate) fn decode(data: &mut Cursor<&[u8]>, version: u16) -> Result<Self> {
+        let id = data.read_u64::<LittleEndian>()? as usize;
+        let ts = UNIX_EPOCH + Duration::from_nanos(data.read_u64::<LittleEndian>()?);
+        let count = data.read_u32::<LittleEndian>()?;
+        let mut pkts = vec![];
+        for _ in 0..count {
+            let pkt = data.read_u64::<LittleEndian>()? as usize;
+            let score = if version >= 2 {
+                data.read_f32::<LittleEndian>()?
+            } else {
+                LEGACY_SCORE
+            };
+            pkts.push(PacketMatch { pkt, score });
+        }
+        let retries = if version >= 3 {
+            data.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        Ok(Self {
+            id,
+            ts,
+            pkts,
+            retries,
+            action: read_action(data)?,
+            mac: read_mac(data)?,
+            iface: read_str(data)?,
+            netns: data.read_u32::<LittleEndian>()?,
+            ifindex: data.read_i32::<LittleEndian>()?,
+            dst: read_mac(data)?,
+            old_nh: read_opt(data, read_mac)?,
+            new_nh: read_opt(data, read_mac)?,
+            frm_ctrl: data.read_u16::<LittleEndian>()?,
+            seq_ctrl: data.read_u16::<LittleEndian>()?,
+            qos_ctrl: read_opt(data, |data| data.read_u16::<LittleEndian>())?,
+            addr1: read_mac(data)?,
+            addr2: read_mac(data)?,
+            addr3: read_mac(data)?,
+            addr4: read_opt(data, read_mac)?,
+            tsf_drift: read_opt(data, |data| data.read_i64::<LittleEndian>())?,
+            tsf_adjust: read_opt(data, |data| data.read_i64::<LittleEndian>())?,
+            peer: read_opt(data, read_mac)?,
+            old_pm: read_opt(data, read_pm)?,
+            new_pm: read_opt(data, read_pm)?,
+        })
+    }
+
+    // 'score' is how likely the packet is to have caused the event
+    #[inline]
+    pub fn push_packet(&mut self, counter: usize, score: f32) {
+        self.pkts.push(PacketMatch {
+            pkt: counter,
+            score,
+        });
+    }
+
+    // place the best match first (the earliest packet wins a tie)
+    pub fn rank_packets(&mut self) {
+        self.pkts
+            .sort_by(|a, b| b.score.total_cmp(&a.score).then(a.pkt.cmp(&b.pkt)));
+    }
+
+    // a retransmission of a packet the event already has
+    #[inline]
+    pub fn push_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    // forget the packets and retries of the event, to correlate it again
     #[inline]
-    pub fn push_packet(&mut self, counter: usize) {
-        self.pkts.push(counter);
+    pub fn clear_packets(&mut self) {
+        self.pkts.clear();
+        self.retries = 0;
     }
 
     #[inline]
@@ -104,11 +271,22 @@ impl Event {
         &self.ts
     }
 
+    // packets that might have caused the event, from best to worst
     #[inline]
-    pub fn pkts(&self) -> &[usize] {
+    pub fn pkts(&self) -> &[PacketMatch] {
         &self.pkts
     }
 
+    #[inline]
+    pub fn best_packet(&self) -> Option<&PacketMatch> {
+        self.pkts.first()
+    }
+
+    #[inline]
+    pub const fn retries(&self) -> &u32 {
+        &self.retries
+    }
+
     #[inline]
     pub const fn action(&self) -> &Action {
         &self.action
@@ -124,6 +302,17 @@ impl Event {
         &self.iface
     }
 
+    // inode of the network namespace of the interface
+    #[inline]
+    pub const fn netns(&self) -> &u32 {
+        &self.netns
+    }
+
+    #[inline]
+    pub const fn ifindex(&self) -> &i32 {
+        &self.ifindex
+    }
+
     #[inline]
     pub const fn dst(&self) -> &MacAddr {
         &self.dst
@@ -154,6 +343,37 @@ impl Event {
         &self.qos_ctrl
     }
 
+    #[inline]
+    pub const fn tsf_drift(&self) -> &Option<i64> {
+        &self.tsf_drift
+    }
+
+    #[inline]
+    pub const fn tsf_adjust(&self) -> &Option<i64> {
+        &self.tsf_adjust
+    }
+
+    #[inline]
+    pub const fn peer(&self) -> &Option<MacAddr> {
+        &self.peer
+    }
+
+    #[inline]
+    pub const fn old_pm(&self) -> &Option<PowerMode> {
+        &self.old_pm
+    }
+
+    #[inline]
+    pub const fn new_pm(&self) -> &Option<PowerMode> {
+        &self.new_pm
+    }
+
+    // transmitter of the packet that caused the event (all zeros if it's unknown)
+    #[inline]
+    pub const fn addr2(&self) -> &MacAddr {
+        &self.addr2
+    }
+
     pub fn compare_addr1(&self, rhs: &[u8]) -> bool {
         Self::compare_addrs(&self.addr1.0, rhs)
     }
@@ -188,4 +408,13 @@ impl Event {
                 | Action::ACT_RX_UNKNOWN
         )
     }
+
+    // check if an event is about a mesh path with the `action` field
+    // (the ones that aren't don't have a destination)
+    pub const fn from_path(&self) -> bool {
+        !matches!(
+            self.action,
+            Action::ACT_KR_TSF | Action::ACT_KR_LOCAL_PM | Action::ACT_KR_PEER_PM
+        )
+    }
 }
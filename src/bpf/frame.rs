@@ -0,0 +1,98 @@
+use super::BOOT_TIME;
+
+use crate::RawFrame;
+
+use std::time::{Duration, SystemTime};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+// radiotap fields used in the synthetic header (bit in 'it_present')
+const RADIOTAP_CHANNEL: u32 = 1 << 3;
+const RADIOTAP_DBM_ANTSIGNAL: u32 = 1 << 5;
+const RADIOTAP_TX_FLAGS: u32 = 1 << 15;
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    ts: SystemTime,
+    phy: String,
+    netns: u32,
+    rx: bool,
+    signal: i8,
+    freq: u16,
+    len: u32,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    // transform a `RawFrame` into a `Frame`
+    pub fn from_raw(raw: RawFrame) -> Self {
+        Self {
+            ts: *BOOT_TIME + Duration::from_nanos(raw.ts),
+            phy: unsafe {
+                String::from_utf8_unchecked(raw.phy.to_vec())
+                    .trim_matches('\0')
+                    .to_owned()
+            },
+            netns: raw.netns,
+            rx: raw.rx,
+            signal: raw.signal,
+            freq: raw.freq,
+            len: raw.len,
+            data: raw.data[..raw.caplen as usize].to_vec(),
+        }
+    }
+
+    #[inline]
+    pub const fn ts(&self) -> &SystemTime {
+        &self.ts
+    }
+
+    #[inline]
+    pub fn phy(&self) -> &str {
+        &self.phy
+    }
+
+    // inode of the network namespace of the radio
+    #[inline]
+    pub const fn netns(&self) -> &u32 {
+        &self.netns
+    }
+
+    #[inline]
+    pub const fn rx(&self) -> &bool {
+        &self.rx
+    }
+
+    // length of the whole frame, not only of what was captured
+    #[inline]
+    pub const fn frame_len(&self) -> &u32 {
+        &self.len
+    }
+
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // radiotap header to place before the frame, so that the capture file
+    // looks the same as one from a monitor interface
+    // (received frames get the channel and signal, transmitted ones the tx flags)
+    pub fn radiotap(&self) -> Vec<u8> {
+        let mut header = if self.rx {
+            let mut header = vec![0; 13];
+            LittleEndian::write_u32(&mut header[4..8], RADIOTAP_CHANNEL | RADIOTAP_DBM_ANTSIGNAL);
+            LittleEndian::write_u16(&mut header[8..10], self.freq);
+            LittleEndian::write_u16(&mut header[10..12], 0); // channel flags
+            header[12] = self.signal as u8;
+            header
+        } else {
+            let mut header = vec![0; 10];
+            LittleEndian::write_u32(&mut header[4..8], RADIOTAP_TX_FLAGS);
+            LittleEndian::write_u16(&mut header[8..10], 0); // tx flags
+            header
+        };
+        let header_len = header.len() as u16;
+        LittleEndian::write_u16(&mut header[2..4], header_len);
+        header
+    }
+}
@@ -0,0 +1,190 @@
+use super::BOOT_TIME;
+
+use crate::{
+    error,
+    ndjson::{append_line, read_lines},
+    MacAddr, RawMetric,
+};
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metric {
+    #[serde(with = "crate::schema::ts_nanos")]
+    ts: SystemTime,
+    mac: MacAddr,
+    iface: String,
+    netns: u32,
+    peer: MacAddr,
+    metric: u32,
+    fail_avg: u32,
+    tx_rate: u32,
+}
+
+impl Metric {
+    // transform a `RawMetric` into a `Metric`
+    pub fn from_raw(raw: RawMetric) -> Self {
+        Self {
+            ts: *BOOT_TIME + Duration::from_nanos(raw.ts),
+            mac: MacAddr(raw.mac),
+            iface: unsafe {
+                String::from_utf8_unchecked(raw.iface.to_vec())
+                    .trim_matches('\0')
+                    .to_owned()
+            },
+            netns: raw.netns,
+            peer: MacAddr(raw.peer),
+            metric: raw.metric,
+            fail_avg: raw.fail_avg,
+            tx_rate: raw.tx_rate * 100, // the kernel uses units of 100 kbps
+        }
+    }
+
+    // (one metric per line, so that a long run doesn't keep them all)
+    pub fn append(&self, out: &mut impl Write) -> io::Result<()> {
+        append_line(self, out)
+    }
+
+    #[inline]
+    pub const fn ts(&self) -> &SystemTime {
+        &self.ts
+    }
+
+    #[inline]
+    pub const fn mac(&self) -> &MacAddr {
+        &self.mac
+    }
+
+    #[inline]
+    pub fn iface(&self) -> &str {
+        &self.iface
+    }
+
+    #[inline]
+    pub const fn netns(&self) -> &u32 {
+        &self.netns
+    }
+
+    #[inline]
+    pub const fn peer(&self) -> &MacAddr {
+        &self.peer
+    }
+
+    #[inline]
+    pub const fn metric(&self) -> &u32 {
+        &self.metric
+    }
+
+    #[inline]
+    pub const fn fail_avg(&self) -> &u32 {
+        &self.fail_avg
+    }
+
+    // in kbps
+    #[inline]
+    pub const fn tx_rate(&self) -> &u32 {
+        &self.tx_rate
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricSample {
+    #[serde(with = "crate::schema::ts_nanos")]
+    ts: SystemTime,
+    metric: u32,
+    fail_avg: u32,
+    tx_rate: u32,
+}
+
+impl MetricSample {
+    #[inline]
+    pub const fn ts(&self) -> &SystemTime {
+        &self.ts
+    }
+
+    #[inline]
+    pub const fn metric(&self) -> &u32 {
+        &self.metric
+    }
+
+    #[inline]
+    pub const fn fail_avg(&self) -> &u32 {
+        &self.fail_avg
+    }
+
+    // in kbps
+    #[inline]
+    pub const fn tx_rate(&self) -> &u32 {
+        &self.tx_rate
+    }
+}
+
+// time series of the metric samples of a neighbor, seen from one interface
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NeighborMetrics {
+    mac: MacAddr,
+    iface: String,
+    peer: MacAddr,
+    samples: Vec<MetricSample>,
+}
+
+impl NeighborMetrics {
+    // group metrics by interface and neighbor, keeping the samples sorted by time
+    pub fn from_metrics(mut metrics: Vec<Metric>) -> Vec<Self> {
+        metrics.sort_by_key(|a| a.ts);
+
+        let mut neighbors: Vec<Self> = vec![];
+        for metric in metrics {
+            let sample = MetricSample {
+                ts: metric.ts,
+                metric: metric.metric,
+                fail_avg: metric.fail_avg,
+                tx_rate: metric.tx_rate,
+            };
+            match neighbors
+                .iter_mut()
+                .find(|n| n.mac == metric.mac && n.peer == metric.peer)
+            {
+                Some(neighbor) => neighbor.samples.push(sample),
+                None => neighbors.push(Self {
+                    mac: metric.mac,
+                    iface: metric.iface,
+                    peer: metric.peer,
+                    samples: vec![sample],
+                }),
+            }
+        }
+        neighbors
+    }
+
+    // read a metrics file, grouping its metrics like 'from_metrics()'
+    pub fn read_file(path: &Path) -> error::Result<Vec<Self>> {
+        read_lines(path).map(Self::from_metrics)
+    }
+
+    #[inline]
+    pub const fn mac(&self) -> &MacAddr {
+        &self.mac
+    }
+
+    #[inline]
+    pub fn iface(&self) -> &str {
+        &self.iface
+    }
+
+    #[inline]
+    pub const fn peer(&self) -> &MacAddr {
+        &self.peer
+    }
+
+    #[inline]
+    pub fn samples(&self) -> &[MetricSample] {
+        &self.samples
+    }
+}
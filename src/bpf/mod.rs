@@ -1,24 +1,43 @@
 pub mod event;
+pub mod frame;
+pub mod metric;
 
-use crate::Action;
+use crate::{Action, PowerMode};
 
 use std::{
     fmt::{Display, Formatter, Result},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use lazy_static::lazy_static;
-use libc::ETH_ALEN;
+use libc::{clock_gettime, timespec, CLOCK_MONOTONIC, ETH_ALEN};
 use psutil::host::boot_time;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 
 lazy_static! {
     static ref BOOT_TIME: SystemTime = boot_time().expect("get system boot time");
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct MacAddr(pub(crate) [u8; ETH_ALEN as usize]);
 
+impl From<[u8; ETH_ALEN as usize]> for MacAddr {
+    fn from(addr: [u8; ETH_ALEN as usize]) -> Self {
+        Self(addr)
+    }
+}
+
+// current time on the clock of the events (the one of 'bpf_ktime_get_ns()'),
+// for what the service samples by itself and has to be compared with them
+pub fn kernel_time() -> SystemTime {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+    *BOOT_TIME + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
 impl Display for MacAddr {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         let mut out = format!("{:02x}", self.0[0]);
@@ -87,36 +106,138 @@ impl Action {
             }
             Self::ACT_US_DEL => "A command from user-space caused a mesh path to be deleted.",
             Self::ACT_KR_EXP => "A mesh path was deleted because it expired.",
+            Self::ACT_KR_TSF => {
+                "The mesh synchronization adjusted the TSF \
+                because of the clock drift to its neighbors."
+            }
+            Self::ACT_KR_LOCAL_PM => "The power mode used towards a peer changed.",
+            Self::ACT_KR_PEER_PM => "The power mode of a peer towards this station changed.",
+        }
+    }
+}
+
+// actions are saved by name, which doesn't change with the order of the enum in 'tracer.bpf.h'
+impl Action {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::ACT_TX_UNKNOWN => "TX_UNKNOWN",
+            Self::ACT_RX_UNKNOWN => "RX_UNKNOWN",
+            Self::ACT_US_UNKNOWN => "US_UNKNOWN",
+            Self::ACT_TX_ADD => "TX_ADD",
+            Self::ACT_TX_ADD_ASG => "TX_ADD_ASG",
+            Self::ACT_TX_ASG => "TX_ASG",
+            Self::ACT_TX_CHG => "TX_CHG",
+            Self::ACT_TX_DEL => "TX_DEL",
+            Self::ACT_RX_ADD => "RX_ADD",
+            Self::ACT_RX_ADD_ASG => "RX_ADD_ASG",
+            Self::ACT_RX_ASG => "RX_ASG",
+            Self::ACT_RX_CHG => "RX_CHG",
+            Self::ACT_RX_DEL => "RX_DEL",
+            Self::ACT_US_ADD => "US_ADD",
+            Self::ACT_US_ADD_ASG => "US_ADD_ASG",
+            Self::ACT_US_ASG => "US_ASG",
+            Self::ACT_US_CHG => "US_CHG",
+            Self::ACT_US_DEL => "US_DEL",
+            Self::ACT_KR_EXP => "KR_EXP",
+            Self::ACT_KR_TSF => "KR_TSF",
+            Self::ACT_KR_LOCAL_PM => "KR_LOCAL_PM",
+            Self::ACT_KR_PEER_PM => "KR_PEER_PM",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "TX_UNKNOWN" => Some(Self::ACT_TX_UNKNOWN),
+            "RX_UNKNOWN" => Some(Self::ACT_RX_UNKNOWN),
+            "US_UNKNOWN" => Some(Self::ACT_US_UNKNOWN),
+            "TX_ADD" => Some(Self::ACT_TX_ADD),
+            "TX_ADD_ASG" => Some(Self::ACT_TX_ADD_ASG),
+            "TX_ASG" => Some(Self::ACT_TX_ASG),
+            "TX_CHG" => Some(Self::ACT_TX_CHG),
+            "TX_DEL" => Some(Self::ACT_TX_DEL),
+            "RX_ADD" => Some(Self::ACT_RX_ADD),
+            "RX_ADD_ASG" => Some(Self::ACT_RX_ADD_ASG),
+            "RX_ASG" => Some(Self::ACT_RX_ASG),
+            "RX_CHG" => Some(Self::ACT_RX_CHG),
+            "RX_DEL" => Some(Self::ACT_RX_DEL),
+            "US_ADD" => Some(Self::ACT_US_ADD),
+            "US_ADD_ASG" => Some(Self::ACT_US_ADD_ASG),
+            "US_ASG" => Some(Self::ACT_US_ASG),
+            "US_CHG" => Some(Self::ACT_US_CHG),
+            "US_DEL" => Some(Self::ACT_US_DEL),
+            "KR_EXP" => Some(Self::ACT_KR_EXP),
+            "KR_TSF" => Some(Self::ACT_KR_TSF),
+            "KR_LOCAL_PM" => Some(Self::ACT_KR_LOCAL_PM),
+            "KR_PEER_PM" => Some(Self::ACT_KR_PEER_PM),
+            _ => None,
         }
     }
 }
 
 impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Self::from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown action {name}")))
+    }
+}
+
+impl Display for PowerMode {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(
             f,
             "{}",
             match self {
-                Self::ACT_TX_UNKNOWN => "TX_UNKNOWN",
-                Self::ACT_RX_UNKNOWN => "RX_UNKNOWN",
-                Self::ACT_US_UNKNOWN => "US_UNKNOWN",
-                Self::ACT_TX_ADD => "TX_ADD",
-                Self::ACT_TX_ADD_ASG => "TX_ADD_ASG",
-                Self::ACT_TX_ASG => "TX_ASG",
-                Self::ACT_TX_CHG => "TX_CHG",
-                Self::ACT_TX_DEL => "TX_DEL",
-                Self::ACT_RX_ADD => "RX_ADD",
-                Self::ACT_RX_ADD_ASG => "RX_ADD_ASG",
-                Self::ACT_RX_ASG => "RX_ASG",
-                Self::ACT_RX_CHG => "RX_CHG",
-                Self::ACT_RX_DEL => "RX_DEL",
-                Self::ACT_US_ADD => "US_ADD",
-                Self::ACT_US_ADD_ASG => "US_ADD_ASG",
-                Self::ACT_US_ASG => "US_ASG",
-                Self::ACT_US_CHG => "US_CHG",
-                Self::ACT_US_DEL => "US_DEL",
-                Self::ACT_KR_EXP => "KR_EXP",
+                Self::PM_UNKNOWN => "Unknown",
+                Self::PM_ACTIVE => "Active",
+                Self::PM_LIGHT_SLEEP => "Light Sleep",
+                Self::PM_DEEP_SLEEP => "Deep Sleep",
             }
         )
     }
 }
+
+impl PowerMode {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::PM_UNKNOWN => "UNKNOWN",
+            Self::PM_ACTIVE => "ACTIVE",
+            Self::PM_LIGHT_SLEEP => "LIGHT_SLEEP",
+            Self::PM_DEEP_SLEEP => "DEEP_SLEEP",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "UNKNOWN" => Some(Self::PM_UNKNOWN),
+            "ACTIVE" => Some(Self::PM_ACTIVE),
+            "LIGHT_SLEEP" => Some(Self::PM_LIGHT_SLEEP),
+            "DEEP_SLEEP" => Some(Self::PM_DEEP_SLEEP),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for PowerMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for PowerMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Self::from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown power mode {name}")))
+    }
+}
@@ -3,6 +3,19 @@
 char LICENSE[] SEC("license") = "GPL";
 
 
+///// HELPERS //////////////////////////////////////////////////////////////////////////////////////
+
+// a ring buffer was full, keep count so that user-space knows something was lost
+static __always_inline void count_drop(void)
+{
+    u32 key = 0;
+    u64 *count = bpf_map_lookup_elem(&drops, &key);
+    if (count != NULL) {
+        __sync_fetch_and_add(count, 1);
+    }
+}
+
+
 ///// IEEE80211_XMIT ///////////////////////////////////////////////////////////////////////////////
 
 // transmission action
@@ -22,12 +35,15 @@ int BPF_PROG(tx)
 
     Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
     if (pass == NULL) {
+        count_drop();
         bpf_map_delete_elem(&event_store, &tid);
         bpf_map_delete_elem(&situation_store, &tid);
         return 0;
     }
 
     pass->ts = event->ts;
+    pass->netns = event->netns;
+    pass->ifindex = event->ifindex;
     for (int i = 0; i < IFNAMSIZ; i++) pass->iface[i] = event->iface[i];
     for (int i = 0; i < ETH_ALEN; i++) {
         pass->mac[i] = event->mac[i];
@@ -106,12 +122,15 @@ int BPF_PROG (rx,
 
     Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
     if (pass == NULL) {
+        count_drop();
         bpf_map_delete_elem(&event_store, &tid);
         bpf_map_delete_elem(&situation_store, &tid);
         return 0;
     }
 
     pass->ts = event->ts;
+    pass->netns = event->netns;
+    pass->ifindex = event->ifindex;
     for (int i = 0; i < IFNAMSIZ; i++) pass->iface[i] = event->iface[i];
     for (int i = 0; i < ETH_ALEN; i++) {
         pass->mac[i] = event->mac[i];
@@ -186,12 +205,15 @@ int BPF_PROG(us)
 
     Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
     if (pass == NULL) {
+        count_drop();
         bpf_map_delete_elem(&event_store, &tid);
         bpf_map_delete_elem(&situation_store, &tid);
         return 0;
     }
 
     pass->ts = event->ts;
+    pass->netns = event->netns;
+    pass->ifindex = event->ifindex;
     for (int i = 0; i < IFNAMSIZ; i++) pass->iface[i] = event->iface[i];
     for (int i = 0; i < ETH_ALEN; i++) {
         pass->mac[i] = event->mac[i];
@@ -288,6 +310,8 @@ int BPF_PROG(add,
     BPF_CORE_READ_INTO(&event->dst, ret, dst);
     BPF_CORE_READ_INTO(&event->mac, sdata, vif.addr);
     BPF_CORE_READ_STR_INTO(&event->iface, sdata, name);
+    BPF_CORE_READ_INTO(&event->netns, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&event->ifindex, sdata, dev, ifindex);
 
     bpf_map_update_elem(&event_store, &tid, event, BPF_ANY);
     bpf_map_update_elem(&situation_store, &tid, situation, BPF_ANY);
@@ -337,6 +361,8 @@ int BPF_PROG(asg_chg,
         BPF_CORE_READ_INTO(&event->dst, mpath, dst);
         BPF_CORE_READ_INTO(&event->mac, mpath, sdata, vif.addr);
         BPF_CORE_READ_STR_INTO(&event->iface, mpath, sdata, name);
+        BPF_CORE_READ_INTO(&event->netns, mpath, sdata, dev, nd_net.net, ns.inum);
+        BPF_CORE_READ_INTO(&event->ifindex, mpath, sdata, dev, ifindex);
     }
 
     bpf_map_update_elem(&event_store, &tid, event, BPF_ANY);
@@ -368,6 +394,7 @@ int BPF_PROG(del_out,
     if (situation != NULL && *situation == SIT_EXP) {
         Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
         if (pass == NULL) {
+            count_drop();
             return 0;
         }
 
@@ -376,6 +403,8 @@ int BPF_PROG(del_out,
         BPF_CORE_READ_INTO(&pass->dst, mpath, dst);
         BPF_CORE_READ_INTO(&pass->mac, mpath, sdata, vif.addr);
         BPF_CORE_READ_STR_INTO(&pass->iface, mpath, sdata, name);
+        BPF_CORE_READ_INTO(&pass->netns, mpath, sdata, dev, nd_net.net, ns.inum);
+        BPF_CORE_READ_INTO(&pass->ifindex, mpath, sdata, dev, ifindex);
 
         if (mpath->next_hop != NULL) {
             pass->has_nh = true;
@@ -396,6 +425,8 @@ int BPF_PROG(del_out,
     BPF_CORE_READ_INTO(&event->dst, mpath, dst);
     BPF_CORE_READ_INTO(&event->mac, mpath, sdata, vif.addr);
     BPF_CORE_READ_STR_INTO(&event->iface, mpath, sdata, name);
+    BPF_CORE_READ_INTO(&event->netns, mpath, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&event->ifindex, mpath, sdata, dev, ifindex);
 
     if (mpath->next_hop != NULL) {
         event->has_nh = true;
@@ -423,3 +454,305 @@ int BPF_PROG(plink)
     bpf_map_delete_elem(&situation_store, &tid);
     return 0;
 }
+
+
+///// MESH_SYNC_ADJUST_TSF /////////////////////////////////////////////////////////////////////////
+
+// save the clock drift before it gets consumed by the adjustment
+// (the function is static, so these aren't loaded when the kernel inlined it)
+SEC("fentry/mesh_sync_adjust_tsf")
+int BPF_PROG(tsf_in,
+    struct ieee80211_sub_if_data *sdata
+) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    s64 drift = BPF_CORE_READ(sdata, u.mesh.sync_offset_clockdrift_max);
+
+    bpf_map_update_elem(&drift_store, &tid, &drift, BPF_ANY);
+    return 0;
+}
+
+// the kernel adjusts the tsf by the difference between the drift before and after
+SEC("fexit/mesh_sync_adjust_tsf")
+int BPF_PROG(tsf_out,
+    struct ieee80211_sub_if_data *sdata
+) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    u64 ts = bpf_ktime_get_ns();
+
+    s64 *drift = bpf_map_lookup_elem(&drift_store, &tid);
+    if (drift == NULL) {
+        return 0;
+    }
+    s64 before = *drift;
+    bpf_map_delete_elem(&drift_store, &tid);
+
+    Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
+    if (pass == NULL) {
+        count_drop();
+        return 0;
+    }
+
+    pass->ts = ts;
+    pass->action = ACT_KR_TSF;
+    BPF_CORE_READ_INTO(&pass->mac, sdata, vif.addr);
+    BPF_CORE_READ_STR_INTO(&pass->iface, sdata, name);
+    BPF_CORE_READ_INTO(&pass->netns, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&pass->ifindex, sdata, dev, ifindex);
+
+    pass->tsf_drift = before;
+    pass->tsf_adjust = BPF_CORE_READ(sdata, u.mesh.sync_offset_clockdrift_max) - before;
+
+    bpf_ringbuf_submit(pass, 0);
+    return 0;
+}
+
+
+///// IEEE80211_MPS_SET_STA_LOCAL_PM ///////////////////////////////////////////////////////////////
+
+// the new power mode is an argument, so only the entry is needed
+SEC("fentry/ieee80211_mps_set_sta_local_pm")
+int BPF_PROG(local_pm,
+    struct sta_info *sta,
+    enum nl80211_mesh_power_mode pm
+) {
+    u64 ts = bpf_ktime_get_ns();
+
+    PowerMode old_pm = (PowerMode)BPF_CORE_READ(sta, mesh, local_pm);
+    if (old_pm == (PowerMode)pm) {
+        return 0;
+    }
+
+    Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
+    if (pass == NULL) {
+        count_drop();
+        return 0;
+    }
+
+    pass->ts = ts;
+    pass->action = ACT_KR_LOCAL_PM;
+    BPF_CORE_READ_INTO(&pass->mac, sta, sdata, vif.addr);
+    BPF_CORE_READ_STR_INTO(&pass->iface, sta, sdata, name);
+    BPF_CORE_READ_INTO(&pass->netns, sta, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&pass->ifindex, sta, sdata, dev, ifindex);
+
+    BPF_CORE_READ_INTO(&pass->peer, sta, sta.addr);
+    pass->old_pm = old_pm;
+    pass->new_pm = (PowerMode)pm;
+
+    bpf_ringbuf_submit(pass, 0);
+    return 0;
+}
+
+
+///// IEEE80211_MPS_RX_H_STA_PROCESS ///////////////////////////////////////////////////////////////
+
+// the peer's power mode is read from the frame inside the function,
+// so save the old one here and compare it on the exit
+// (the function is static, so these aren't loaded when the kernel inlined it)
+SEC("fentry/ieee80211_mps_rx_h_sta_process")
+int BPF_PROG(peer_pm_in,
+    struct sta_info *sta
+) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    PowerMode pm = (PowerMode)BPF_CORE_READ(sta, mesh, peer_pm);
+
+    bpf_map_update_elem(&pm_store, &tid, &pm, BPF_ANY);
+    return 0;
+}
+
+SEC("fexit/ieee80211_mps_rx_h_sta_process")
+int BPF_PROG(peer_pm_out,
+    struct sta_info *sta
+) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    u64 ts = bpf_ktime_get_ns();
+
+    PowerMode *pm = bpf_map_lookup_elem(&pm_store, &tid);
+    if (pm == NULL) {
+        return 0;
+    }
+    PowerMode old_pm = *pm;
+    bpf_map_delete_elem(&pm_store, &tid);
+
+    PowerMode new_pm = (PowerMode)BPF_CORE_READ(sta, mesh, peer_pm);
+    if (old_pm == new_pm) {
+        return 0;
+    }
+
+    Event *pass = bpf_ringbuf_reserve(&ringbuf, sizeof(Event), 0);
+    if (pass == NULL) {
+        count_drop();
+        return 0;
+    }
+
+    pass->ts = ts;
+    pass->action = ACT_KR_PEER_PM;
+    BPF_CORE_READ_INTO(&pass->mac, sta, sdata, vif.addr);
+    BPF_CORE_READ_STR_INTO(&pass->iface, sta, sdata, name);
+    BPF_CORE_READ_INTO(&pass->netns, sta, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&pass->ifindex, sta, sdata, dev, ifindex);
+
+    BPF_CORE_READ_INTO(&pass->peer, sta, sta.addr);
+    pass->old_pm = old_pm;
+    pass->new_pm = new_pm;
+
+    bpf_ringbuf_submit(pass, 0);
+    return 0;
+}
+
+
+///// STA_GET_EXPECTED_THROUGHPUT //////////////////////////////////////////////////////////////////
+
+static __always_inline void neighbor_key(NeighborKey *key, struct sta_info *sta)
+{
+    BPF_CORE_READ_INTO(&key->netns, sta, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&key->ifindex, sta, sdata, dev, ifindex);
+    BPF_CORE_READ_INTO(&key->peer, sta, sta.addr);
+}
+
+// the airtime metric prefers the expected throughput from rate control,
+// but doesn't store it, so keep it for the exit of the metric calculation
+SEC("fexit/sta_get_expected_throughput")
+int BPF_PROG(throughput,
+    struct sta_info *sta,
+    u32 ret
+) {
+    NeighborKey key = {};
+    neighbor_key(&key, sta);
+
+    bpf_map_update_elem(&throughput_store, &key, &ret, BPF_ANY);
+    return 0;
+}
+
+
+///// AIRTIME_LINK_METRIC_GET //////////////////////////////////////////////////////////////////////
+
+// sampled, each neighbor is only submitted once every 'metric_interval'
+SEC("fexit/airtime_link_metric_get")
+int BPF_PROG(metric,
+    struct ieee80211_local *local,
+    struct sta_info *sta,
+    u32 ret
+) {
+    u64 ts = bpf_ktime_get_ns();
+
+    NeighborKey key = {};
+    neighbor_key(&key, sta);
+
+    u32 *throughput = bpf_map_lookup_elem(&throughput_store, &key);
+    u32 rate = throughput != NULL ? (*throughput + 99) / 100 : 0; // DIV_ROUND_UP, kbps to 100 kbps
+    bpf_map_delete_elem(&throughput_store, &key);
+
+    u64 *last = bpf_map_lookup_elem(&metric_store, &key);
+    if (last != NULL && ts - *last < metric_interval) {
+        return 0;
+    }
+    bpf_map_update_elem(&metric_store, &key, &ts, BPF_ANY);
+
+    Metric *pass = bpf_ringbuf_reserve(&metric_ringbuf, sizeof(Metric), 0);
+    if (pass == NULL) {
+        count_drop();
+        return 0;
+    }
+
+    pass->ts = ts;
+    BPF_CORE_READ_INTO(&pass->mac, sta, sdata, vif.addr);
+    BPF_CORE_READ_STR_INTO(&pass->iface, sta, sdata, name);
+    BPF_CORE_READ_INTO(&pass->netns, sta, sdata, dev, nd_net.net, ns.inum);
+    BPF_CORE_READ_INTO(&pass->peer, sta, sta.addr);
+
+    // same fallback as the kernel when rate control doesn't have an estimate
+    if (rate == 0) {
+        rate = BPF_CORE_READ(sta, mesh, tx_rate_avg.internal) >> EWMA_TX_RATE_PRECISION;
+    }
+    pass->metric = ret;
+    pass->fail_avg = BPF_CORE_READ(sta, mesh, fail_avg.internal) >> EWMA_FAIL_AVG_PRECISION;
+    pass->tx_rate = rate;
+
+    bpf_ringbuf_submit(pass, 0);
+    return 0;
+}
+
+
+///// FRAME CAPTURE ////////////////////////////////////////////////////////////////////////////////
+
+// copy the start of a frame to user-space, replacing the need for a monitor interface
+static __always_inline void capture_frame(struct ieee80211_hw *hw, struct sk_buff *skb, bool rx)
+{
+    Frame *pass = bpf_ringbuf_reserve(&frame_ringbuf, sizeof(Frame), 0);
+    if (pass == NULL) {
+        count_drop();
+        return;
+    }
+
+    pass->ts = bpf_ktime_get_ns();
+    pass->rx = rx;
+    bpf_probe_read_kernel_str(&pass->phy, IFNAMSIZ, BPF_CORE_READ(hw, wiphy, dev.kobj.name));
+    BPF_CORE_READ_INTO(&pass->netns, hw, wiphy, _net.net, ns.inum);
+
+    if (rx) {
+        struct ieee80211_rx_status *status = (struct ieee80211_rx_status *)skb->cb;
+        pass->signal = BPF_CORE_READ(status, signal);
+        pass->freq = BPF_CORE_READ_BITFIELD_PROBED(status, freq);
+    } else {
+        pass->signal = 0;
+        pass->freq = 0;
+    }
+
+    u32 len = BPF_CORE_READ(skb, len);
+    u32 caplen = len < FRAME_SNAP ? len : FRAME_SNAP;
+    pass->len = len;
+    pass->caplen = caplen;
+    if (caplen > FRAME_SNAP) { // never happens, but the verifier needs it
+        caplen = FRAME_SNAP;
+    }
+    bpf_probe_read_kernel(&pass->data, caplen, BPF_CORE_READ(skb, data));
+
+    bpf_ringbuf_submit(pass, 0);
+}
+
+// transmission, the frame is complete (sequence number included) when leaving the queue
+SEC("fexit/ieee80211_tx_dequeue")
+int BPF_PROG(frame_tx,
+    struct ieee80211_hw *hw,
+    struct ieee80211_txq *txq,
+    struct sk_buff *ret
+) {
+    if (ret != NULL) {
+        capture_frame(hw, ret, false);
+    }
+    return 0;
+}
+
+// transmission of frames that don't go through a TXQ (like with drivers without them),
+// the fragments of the list are complete and handed to the driver right after
+// (frames sent again from the pending queue were captured the first time)
+SEC("fentry/__ieee80211_tx")
+int BPF_PROG(frame_tx_nonq,
+    struct ieee80211_local *local,
+    struct sk_buff_head *skbs,
+    struct sta_info *sta,
+    bool txpending
+) {
+    if (txpending) {
+        return 0;
+    }
+
+    struct sk_buff *skb = BPF_CORE_READ(skbs, next);
+    for (int i = 0; i < FRAME_FRAGS && skb != (struct sk_buff *)skbs; i++) {
+        capture_frame(&local->hw, skb, false);
+        skb = BPF_CORE_READ(skb, next);
+    }
+    return 0;
+}
+
+// reception, the frame still has everything that came from the driver
+SEC("fentry/ieee80211_rx_list")
+int BPF_PROG(frame_rx,
+    struct ieee80211_hw *hw,
+    struct ieee80211_sta *pubsta,
+    struct sk_buff *skb
+) {
+    capture_frame(hw, skb, true);
+    return 0;
+}
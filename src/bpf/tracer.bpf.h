@@ -17,6 +17,10 @@
 #define IEEE80211_FTYPE_DATA     0x0008
 #define IEEE80211_STYPE_QOS_DATA 0x0080
 
+// <net/mac80211/sta_info.h> (precision of 'DECLARE_EWMA')
+#define EWMA_FAIL_AVG_PRECISION 20
+#define EWMA_TX_RATE_PRECISION  8
+
 // <linux/err.h>
 #define MAX_ERRNO       4095
 #define IS_ERR_VALUE(x) __builtin_expect(!!((x) >= (unsigned long)-MAX_ERRNO), 0)
@@ -79,15 +83,31 @@ typedef enum Action {
 
     /// Kernel
     ACT_KR_EXP,
+    ACT_KR_TSF,      // not a path action, mesh synchronization adjusted the TSF
+    ACT_KR_LOCAL_PM, // not a path action, our power mode towards a peer changed
+    ACT_KR_PEER_PM,  // not a path action, a peer's power mode towards us changed
 } Action;
 
 
+///// POWER MODE ///////////////////////////////////////////////////////////////////////////////////
+
+// same values as 'enum nl80211_mesh_power_mode' <uapi/linux/nl80211.h>
+typedef enum PowerMode {
+    PM_UNKNOWN,
+    PM_ACTIVE,
+    PM_LIGHT_SLEEP,
+    PM_DEEP_SLEEP,
+} PowerMode;
+
+
 ///// EVENT ////////////////////////////////////////////////////////////////////////////////////////
 
 typedef struct Event {
     /// Station Info
     u8 mac[ETH_ALEN];
     u8 iface[IFNAMSIZ];
+    u32 netns; // inode of the network namespace
+    s32 ifindex;
 
     /// Action Info
     u64 ts;
@@ -107,6 +127,15 @@ typedef struct Event {
     u8 addr2[ETH_ALEN];
     u8 addr3[ETH_ALEN];
     u8 addr4[ETH_ALEN];
+
+    /// Synchronization Info (only for tsf)
+    s64 tsf_drift;
+    s64 tsf_adjust;
+
+    /// Power Save Info (only for local/peer power mode)
+    u8 peer[ETH_ALEN];
+    PowerMode old_pm;
+    PowerMode new_pm;
 } Event;
 
 struct {
@@ -117,6 +146,95 @@ struct {
 } event_store SEC(".maps");
 
 
+// the kernel consumes the clock drift while adjusting the tsf,
+// so the value before the adjustment is kept here until the exit
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u32);
+    __type(value, s64);
+} drift_store SEC(".maps");
+
+// same as above, but for the power mode of a peer
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u32);
+    __type(value, PowerMode);
+} pm_store SEC(".maps");
+
+
+///// METRIC ///////////////////////////////////////////////////////////////////////////////////////
+
+typedef struct Metric {
+    /// Station Info
+    u8 mac[ETH_ALEN];
+    u8 iface[IFNAMSIZ];
+    u32 netns;
+
+    /// Sample Info
+    u64 ts;
+    u8 peer[ETH_ALEN];
+    u32 metric;
+    u32 fail_avg; // percentage of failed transmissions
+    u32 tx_rate;  // in units of 100 kbps, like the kernel uses for the calculation
+} Metric;
+
+// minimum time between samples of the same neighbor (set by user-space before loading)
+const volatile u64 metric_interval = 0;
+
+// neighbor of an interface
+// (the same peer can be a neighbor of several interfaces, even in different namespaces)
+typedef struct NeighborKey {
+    u32 netns;
+    int ifindex;
+    u8 peer[ETH_ALEN];
+    u8 pad[2]; // zeroed, since the whole key is hashed
+} NeighborKey;
+
+// when each neighbor was last sampled
+// (LRU, so that neighbors that went away make room for new ones)
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 1024);
+    __type(key, NeighborKey);
+    __type(value, u64);
+} metric_store SEC(".maps");
+
+// expected throughput of each neighbor calculated for the metric, since it's not stored anywhere
+// (it's also calculated for station dumps, which don't calculate the metric after it)
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 1024);
+    __type(key, NeighborKey);
+    __type(value, u32);
+} throughput_store SEC(".maps");
+
+
+///// FRAME ////////////////////////////////////////////////////////////////////////////////////////
+
+// only the start of each frame is kept, all that's needed are the headers
+#define FRAME_SNAP 256
+
+// most fragments of a frame handed to the driver at once, that are captured
+#define FRAME_FRAGS 4
+
+typedef struct Frame {
+    /// Radio Info
+    u8 phy[IFNAMSIZ];
+    u32 netns;
+    bool rx;
+    s8 signal; // only for rx
+    u16 freq;  // only for rx
+
+    /// Frame Info
+    u64 ts;
+    u32 len;
+    u32 caplen;
+    u8 data[FRAME_SNAP];
+} Frame;
+
+
 ///// RING BUFFER //////////////////////////////////////////////////////////////////////////////////
 
 struct {
@@ -124,5 +242,23 @@ struct {
     __uint(max_entries, 256 * 1024);
 } ringbuf SEC(".maps");
 
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 64 * 1024);
+} metric_ringbuf SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 4 * 1024 * 1024);
+} frame_ringbuf SEC(".maps");
+
+// number of submissions that didn't fit in the ring buffers
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, u64);
+} drops SEC(".maps");
+
 
 #endif // TRACER_BPF_H
@@ -0,0 +1,215 @@
+use crate::{error, pcapng::PcapngReader};
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Result},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use libc::timeval;
+use pcap::{Capture, Error::NoMorePackets, Offline};
+
+// link-layer types of the captures that have 802.11 frames
+// (http://www.tcpdump.org/linktypes.html)
+const LINKTYPE_IEEE802_11: u16 = 105;
+const LINKTYPE_IEEE802_11_RADIOTAP: u16 = 127;
+const LINKTYPE_IEEE802_11_AVS: u16 = 163;
+const LINKTYPE_PPI: u16 = 192;
+
+// management frames that announce a network, and where their elements start
+// (after the header, a timestamp, the beacon interval and the capabilities)
+const FRAME_TYPE: u16 = 0x00fc;
+const BEACON: u16 = 0x0080;
+const PROBE_RESPONSE: u16 = 0x0050;
+const ANNOUNCEMENT_ELEMENTS: usize = 24 + 12;
+const ELEMENT_MESH_ID: u8 = 114;
+
+// what comes before the 802.11 header of a frame in a capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLayer {
+    Ieee80211,
+    Radiotap,
+    Ppi,
+    Avs,
+}
+
+impl LinkLayer {
+    // 'None' for link-layer types without 802.11 frames
+    pub const fn from_linktype(linktype: u16) -> Option<Self> {
+        match linktype {
+            LINKTYPE_IEEE802_11 => Some(Self::Ieee80211),
+            LINKTYPE_IEEE802_11_RADIOTAP => Some(Self::Radiotap),
+            LINKTYPE_IEEE802_11_AVS => Some(Self::Avs),
+            LINKTYPE_PPI => Some(Self::Ppi),
+            _ => None,
+        }
+    }
+
+    // length of the preamble in front of the 802.11 header
+    // ('None' if the packet is too short or doesn't have an 802.11 frame)
+    pub fn preamble_len(&self, data: &[u8]) -> Option<usize> {
+        let len = match self {
+            Self::Ieee80211 => 0,
+            // version, padding and then the length
+            Self::Radiotap => LittleEndian::read_u16(data.get(2..4)?) as usize,
+            // version, flags, the length and then the link-layer type of what follows it
+            Self::Ppi => {
+                let dlt = LittleEndian::read_u32(data.get(4..8)?);
+                if dlt != LINKTYPE_IEEE802_11 as u32 {
+                    return None;
+                }
+                LittleEndian::read_u16(data.get(2..4)?) as usize
+            }
+            // version and then the length, in network byte order
+            Self::Avs => BigEndian::read_u32(data.get(4..8)?) as usize,
+        };
+        (len <= data.len()).then_some(len)
+    }
+}
+
+// check if an 802.11 frame announces a network other than the mesh with this ID
+// (beacons and probe responses without a mesh ID are from access points or ad hoc networks,
+//  every other frame isn't an announcement and so it's never from another network)
+pub fn is_other_network(frame: &[u8], mesh_id: &[u8]) -> bool {
+    if frame.len() < ANNOUNCEMENT_ELEMENTS {
+        return false;
+    }
+    let frm_ctrl = LittleEndian::read_u16(&frame[0..2]) & FRAME_TYPE;
+    if frm_ctrl != BEACON && frm_ctrl != PROBE_RESPONSE {
+        return false;
+    }
+
+    // elements are an id, a length and then the value
+    let mut elements = &frame[ANNOUNCEMENT_ELEMENTS..];
+    while let [id, len, rest @ ..] = elements {
+        let value = match rest.get(..*len as usize) {
+            Some(value) => value,
+            None => break,
+        };
+        if *id == ELEMENT_MESH_ID {
+            return value != mesh_id;
+        }
+        elements = &rest[*len as usize..];
+    }
+    true
+}
+
+// packet read from a capture file
+pub struct CapturedPacket {
+    // 'None' if the capture doesn't have it
+    pub ts: Option<SystemTime>,
+    // interface of the capture it came from ('None' if the capture only has one)
+    pub interface: Option<u32>,
+    // 'None' if the link-layer type of its interface doesn't have 802.11 frames
+    pub link: Option<LinkLayer>,
+    pub caplen: u32,
+    pub len: u32,
+    pub data: Vec<u8>,
+}
+
+impl CapturedPacket {
+    // the 802.11 frame, without the preamble
+    pub fn frame(&self) -> Option<&[u8]> {
+        let len = self.link?.preamble_len(&self.data)?;
+        Some(&self.data[len..])
+    }
+}
+
+// timestamp of a packet of a pcap file
+// (an error if it's corrupt, like a negative one)
+fn pcap_timestamp(ts: timeval) -> Result<SystemTime> {
+    let secs = u64::try_from(ts.tv_sec).ok();
+    let nanos = u32::try_from(ts.tv_usec)
+        .ok()
+        .and_then(|usecs| usecs.checked_mul(1000));
+    secs.zip(nanos)
+        .and_then(|(secs, nanos)| UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "packet has a timestamp out of range",
+            )
+        })
+}
+
+// packets of a capture file, in either pcap or pcapng
+// (pcap files are read by libpcap, but pcapng files are read here since libpcap
+//  doesn't support interfaces with different link-layer types in the same file)
+pub enum CaptureFile {
+    Pcap {
+        capture: Capture<Offline>,
+        link: LinkLayer,
+    },
+    Pcapng(PcapngReader<File>),
+}
+
+impl CaptureFile {
+    pub fn open(path: &Path) -> error::Result<Self> {
+        Self::open_file(path).map_err(|err| error::Error::file(path, err))
+    }
+
+    fn open_file(path: &Path) -> Result<Self> {
+        let mut magic = [0; 4];
+        let read = File::open(path)?.read(&mut magic)?;
+        if PcapngReader::<File>::detect(&magic[..read]) {
+            return Ok(Self::Pcapng(PcapngReader::new(File::open(path)?)?));
+        }
+
+        // without interfaces of their own, all packets have the same link-layer type
+        let capture = Capture::from_file(path).map_err(Error::other)?;
+        let linktype = capture.get_datalink();
+        let link = LinkLayer::from_linktype(linktype.0 as u16).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("datalink {linktype:?} doesn't have 802.11 frames"),
+            )
+        })?;
+        Ok(Self::Pcap { capture, link })
+    }
+
+    // next packet of the capture ('None' after the last one)
+    pub fn next_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        match self {
+            Self::Pcap { capture, link } => match capture.next() {
+                Ok(pkt) => Ok(Some(CapturedPacket {
+                    ts: Some(pcap_timestamp(pkt.header.ts)?),
+                    interface: None,
+                    link: Some(*link),
+                    caplen: pkt.header.caplen,
+                    len: pkt.header.len,
+                    data: pkt.data.to_vec(),
+                })),
+                Err(NoMorePackets) => Ok(None),
+                Err(err) => Err(Error::other(err)),
+            },
+            Self::Pcapng(reader) => reader.next_packet(),
+        }
+    }
+
+    // name of the device an interface of the capture was on ('None' if it doesn't say)
+    pub fn interface_name(&self, interface: u32) -> Option<&str> {
+        match self {
+            Self::Pcap { .. } => None,
+            Self::Pcapng(reader) => reader.interface_name(interface),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcap_timestamps() {
+        let ts = |tv_sec, tv_usec| pcap_timestamp(timeval { tv_sec, tv_usec });
+        assert_eq!(
+            ts(1_650_000_000, 250_000).unwrap(),
+            UNIX_EPOCH + Duration::from_millis(1_650_000_000_250)
+        );
+        assert!(ts(-1, 0).is_err());
+        assert!(ts(0, -1).is_err());
+        assert!(ts(0, 5_000_000_000).is_err());
+    }
+}
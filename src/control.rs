@@ -0,0 +1,128 @@
+use crate::stream::{read_message, write_message, Connection, StreamAddr};
+
+use std::{
+    io::{copy, Error, ErrorKind, Read, Result, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+// what can be asked to a service running as a daemon
+// (starting and stopping can wait for an instant of the wall clock of the daemon,
+//  so that several daemons with synchronized clocks do it at the same time)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    // start a capture session, with packets from monitor interfaces (separated by commas)
+    // (without one, the frames come from the kernel if the daemon captures them,
+    //  and without a station, the files are named after the session)
+    Start {
+        session: String,
+        station: Option<String>,
+        interface: Option<String>,
+        at: Option<SystemTime>,
+    },
+    // stop a capture session and correlate its files
+    Stop {
+        session: String,
+        at: Option<SystemTime>,
+    },
+    // status of a session, or of all of them
+    Status {
+        session: Option<String>,
+    },
+    // contents of one of the files of a finished session
+    Fetch {
+        session: String,
+        file: String,
+    },
+}
+
+// what a daemon answers to a request
+// ('File' is followed by the 'len' bytes of the file)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Started,
+    Stopped,
+    Status(Vec<SessionStatus>),
+    File { name: String, len: u64 },
+    Error(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionState {
+    Running,
+    Correlating,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub name: String,
+    pub state: SessionState,
+    pub events: usize,
+    pub drops: u64,
+    pub files: Vec<PathBuf>,
+}
+
+// connection to the control socket of a daemon
+pub struct ControlClient {
+    connection: Connection,
+}
+
+impl ControlClient {
+    pub fn connect(addr: &StreamAddr) -> Result<Self> {
+        Ok(Self {
+            connection: addr.connect()?,
+        })
+    }
+
+    // send a request and wait for its response
+    // (an error from the daemon becomes an 'io::Error')
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.connection, request)?;
+        match read_message(&mut self.connection)? {
+            Some(Response::Error(err)) => Err(Error::other(err)),
+            Some(response) => Ok(response),
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "daemon closed the connection",
+            )),
+        }
+    }
+
+    // how long to wait for a response ('None' waits forever)
+    #[inline]
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.connection.set_read_timeout(timeout)
+    }
+
+    // fetch a file of a finished session into 'writer'
+    pub fn fetch<W: Write + ?Sized>(
+        &mut self,
+        session: &str,
+        file: &str,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let request = Request::Fetch {
+            session: session.to_string(),
+            file: file.to_string(),
+        };
+        match self.request(&request)? {
+            Response::File { len, .. } => {
+                let copied = copy(&mut (&mut self.connection).take(len), writer)?;
+                if copied != len {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("file {file} ended after {copied} of {len} bytes"),
+                    ));
+                }
+                Ok(len)
+            }
+            response => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected response to fetch: {response:?}"),
+            )),
+        }
+    }
+}
@@ -0,0 +1,684 @@
+use crate::{
+    capture::CaptureFile,
+    error,
+    log::{read_events_file, write_events_file, EventFormat},
+    manifest::Manifest,
+    schema::ts_nanos,
+    Event, MacAddr, CHECK_QOS, HAS_ADDR4, HAS_QOS, HDR_SIZE_3ADDR, HDR_SIZE_4ADDR,
+};
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    fs::{read, write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec_pretty};
+
+// how far apart in time a packet and an event can be and still be related
+// (events are timestamped from the boot time of the station, which is only known to the second,
+//  so this is wide enough to not miss any, but still narrow enough to not reach a packet
+//  that reused the same sequence number)
+pub const CORRELATION_WINDOW: Duration = Duration::from_secs(5);
+
+// retry bit of the frame control field
+// (retransmissions of a frame only differ from it in this bit)
+const RETRY: u16 = 0x0800;
+
+// everything that comes out of relating events to packets
+#[derive(Debug, Clone, Default)]
+pub struct Correlation {
+    pub stats: CorrelationStats,
+    pub links: Vec<LinkRetries>,
+}
+
+// how well the events of a station were related to the packets of its capture
+// (only events caused by packets are expected to have any)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CorrelationStats {
+    pub packets: usize,
+    // packets that weren't captured whole, so they can't be compared
+    pub skipped: usize,
+    // events with a single packet
+    pub matched: usize,
+    // events caused by a packet that have none
+    pub unmatched: usize,
+    // events with more than one packet, which are ranked by their score
+    pub ambiguous: usize,
+    // retransmissions grouped with the packet of an event
+    pub retries: usize,
+}
+
+impl Display for CorrelationStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} matched, {} unmatched, {} ambiguous, {} retries ({} packets, {} skipped)",
+            self.matched, self.unmatched, self.ambiguous, self.retries, self.packets, self.skipped
+        )
+    }
+}
+
+// frames and retransmissions from a transmitter to a receiver in a capture
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkRetries {
+    pub transmitter: MacAddr,
+    pub receiver: MacAddr,
+    pub frames: usize,
+    pub retries: usize,
+    pub samples: Vec<RetrySample>,
+}
+
+// frames of a link in each second of the capture
+// (only the seconds that had any are kept)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrySample {
+    #[serde(with = "ts_nanos")]
+    pub ts: SystemTime,
+    pub frames: usize,
+    pub retries: usize,
+}
+
+impl LinkRetries {
+    // retries file that goes with an events file
+    pub fn path(events_file: &Path) -> PathBuf {
+        events_file.with_extension("retries")
+    }
+
+    pub fn read_file(path: &Path) -> error::Result<Vec<Self>> {
+        read(path)
+            .and_then(|data| Ok(from_slice(&data)?))
+            .map_err(|err| error::Error::file(path, err))
+    }
+
+    pub fn write_file(path: &Path, links: &[Self]) -> error::Result<()> {
+        to_vec_pretty(links)
+            .map_err(Into::into)
+            .and_then(|data| write(path, data))
+            .map_err(|err| error::Error::file(path, err))
+    }
+}
+
+// frames and retries of a link, by the second they're in
+type Seconds = BTreeMap<u64, (usize, usize)>;
+
+#[derive(Default)]
+struct LinkCounter {
+    links: HashMap<([u8; 6], [u8; 6]), Seconds>,
+}
+
+impl LinkCounter {
+    fn count(&mut self, header: &MacHeader, ts: SystemTime) {
+        let second = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let sample = self
+            .links
+            .entry((header.addr2, header.addr1))
+            .or_default()
+            .entry(second)
+            .or_default();
+        sample.0 += 1;
+        if header.is_retry() {
+            sample.1 += 1;
+        }
+    }
+
+    // links with the most retries first
+    fn finish(self) -> Vec<LinkRetries> {
+        let mut links = self
+            .links
+            .into_iter()
+            .map(|((transmitter, receiver), seconds)| {
+                let samples = seconds
+                    .into_iter()
+                    .map(|(second, (frames, retries))| RetrySample {
+                        ts: UNIX_EPOCH + Duration::from_secs(second),
+                        frames,
+                        retries,
+                    })
+                    .collect::<Vec<_>>();
+                LinkRetries {
+                    transmitter: MacAddr(transmitter),
+                    receiver: MacAddr(receiver),
+                    frames: samples.iter().map(|s| s.frames).sum(),
+                    retries: samples.iter().map(|s| s.retries).sum(),
+                    samples,
+                }
+            })
+            .collect::<Vec<_>>();
+        links.sort_by_key(|link| (Reverse(link.retries), link.transmitter.0, link.receiver.0));
+        links
+    }
+}
+
+// radios of the traced interfaces and of the devices of a capture, from the manifest of a station
+// (events only match the packets of a device on their own radio, but when the radio of either
+//  isn't known, or the capture doesn't say which device a packet is from, they can match any)
+#[derive(Debug, Clone, Default)]
+pub struct Radios {
+    ifaces: HashMap<String, String>,
+    captures: HashMap<String, String>,
+}
+
+impl Radios {
+    pub fn from_manifest(manifest: &Manifest) -> Self {
+        Self {
+            ifaces: manifest
+                .interfaces
+                .iter()
+                .filter_map(|i| Some((i.name.clone(), i.phy.clone()?)))
+                .collect(),
+            captures: manifest
+                .capture
+                .radios
+                .iter()
+                .filter_map(|r| Some((r.interface.clone(), r.phy.clone()?)))
+                .collect(),
+        }
+    }
+
+    fn same_radio(&self, event: &Event, device: Option<&str>) -> bool {
+        match (
+            self.ifaces.get(event.iface()),
+            device.and_then(|device| self.captures.get(device)),
+        ) {
+            (Some(event_phy), Some(packet_phy)) => event_phy == packet_phy,
+            _ => true,
+        }
+    }
+}
+
+// relate events to the packets of a capture, replacing the packets they had before
+// (packets are counted from 1, in the order of the capture, which is read only once,
+//  and each packet is only compared to the events near it, through an index of the events)
+// (when an event has more than one packet, they're ranked by how likely they are to be the one,
+//  and retransmissions of a packet it already has only count as retries)
+// (the retry bit isn't compared, so when the original packet of an event wasn't captured,
+//  a retransmission of it becomes its packet, which comparing the whole frame control field
+//  never matched)
+pub fn correlate(
+    events: &mut [Event],
+    capture_file: &Path,
+    radios: &Radios,
+    window: Duration,
+    verbose: bool,
+) -> error::Result<Correlation> {
+    let mut packets = CaptureFile::open(capture_file)?;
+    events.iter_mut().for_each(Event::clear_packets);
+    let index = EventIndex::new(events);
+
+    let mut stats = CorrelationStats::default();
+    let mut links = LinkCounter::default();
+    let mut counter = 0usize;
+    loop {
+        counter += 1;
+        match packets.next_packet() {
+            Ok(Some(pkt)) => {
+                stats.packets += 1;
+                if pkt.link.is_none() {
+                    if verbose {
+                        eprintln!(
+                            "packet {counter} skipped because it isn't from an 802.11 interface"
+                        );
+                    }
+                    stats.skipped += 1;
+                    continue;
+                }
+                // (only the header is needed, so packets cut short by a snap length still count)
+                let header = match pkt.frame().and_then(MacHeader::parse) {
+                    Some(header) => header,
+                    None if pkt.caplen != pkt.len => {
+                        if verbose {
+                            eprintln!(
+                                "packet {counter} skipped because its header wasn't captured whole"
+                            );
+                        }
+                        stats.skipped += 1;
+                        continue;
+                    }
+                    None => continue,
+                };
+                let ts = match pkt.ts {
+                    Some(ts) => ts,
+                    None => continue,
+                };
+                links.count(&header, ts);
+                let device = pkt.interface.and_then(|i| packets.interface_name(i));
+                for idx in index.candidates(&header, ts, window) {
+                    let evt = &mut events[idx];
+                    if !header.matches(evt) || !radios.same_radio(evt, device) {
+                        continue;
+                    }
+                    if header.is_retry() && !evt.pkts().is_empty() {
+                        evt.push_retry();
+                        stats.retries += 1;
+                        if verbose {
+                            println!("Packet {} is a retry for event {}", counter, evt.id());
+                        }
+                    } else {
+                        evt.push_packet(counter, header.likelihood(evt, ts));
+                        if verbose {
+                            println!("Associated packet {} with event {}", counter, evt.id());
+                        }
+                    }
+                }
+            }
+            Ok(None) => break,
+            // (the rest of the capture can't be read, like after a crash)
+            Err(err) => {
+                eprintln!("error on packet {counter}, ignoring the rest of the capture: {err}");
+                break;
+            }
+        }
+    }
+
+    for event in events.iter_mut() {
+        event.rank_packets();
+        match event.pkts().len() {
+            0 if event.from_pkt() => stats.unmatched += 1,
+            0 => {}
+            1 => stats.matched += 1,
+            _ => stats.ambiguous += 1,
+        }
+    }
+    Ok(Correlation {
+        stats,
+        links: links.finish(),
+    })
+}
+
+// relate the events of a file to the packets of a capture,
+// and write them to 'output' (in the same format as the events file),
+// with the retries of each link next to it
+pub fn correlate_files(
+    events_file: &Path,
+    capture_file: &Path,
+    output: &Path,
+    radios: &Radios,
+    window: Duration,
+    verbose: bool,
+) -> error::Result<Correlation> {
+    let mut file = read_events_file(events_file)?;
+    let correlation = correlate(&mut file.events, capture_file, radios, window, verbose)?;
+    write_events_file(output, &file, EventFormat::of_file(events_file)?)?;
+    LinkRetries::write_file(&LinkRetries::path(output), &correlation.links)?;
+    Ok(correlation)
+}
+
+// events by the fields of the header that every packet has (but the retry bit), each sorted by timestamp
+// (events that don't know their transmitter are kept under an address of all zeros,
+//  since they can match a packet from anyone)
+struct EventIndex {
+    events: HashMap<(u16, u16, MacAddr), Vec<(SystemTime, usize)>>,
+}
+
+impl EventIndex {
+    fn new(events: &[Event]) -> Self {
+        let mut index = HashMap::<_, Vec<_>>::new();
+        for (idx, event) in events.iter().enumerate() {
+            index
+                .entry((
+                    *event.frm_ctrl() & !RETRY,
+                    *event.seq_ctrl(),
+                    event.addr2().clone(),
+                ))
+                .or_default()
+                .push((*event.ts(), idx));
+        }
+        index.values_mut().for_each(|events| events.sort());
+        Self { events: index }
+    }
+
+    // events that a packet could belong to, within 'window' of its timestamp
+    fn candidates(&self, header: &MacHeader, ts: SystemTime, window: Duration) -> Vec<usize> {
+        let start = ts.checked_sub(window).unwrap_or(UNIX_EPOCH);
+        let end = ts + window;
+        let frm_ctrl = header.frm_ctrl & !RETRY;
+        let mut addrs = vec![MacAddr(header.addr2)];
+        if header.addr2 != ANY_ADDR {
+            addrs.push(MacAddr(ANY_ADDR));
+        }
+
+        let mut candidates = vec![];
+        for addr2 in addrs {
+            if let Some(events) = self.events.get(&(frm_ctrl, header.seq_ctrl, addr2)) {
+                let first = events.partition_point(|(event_ts, _)| *event_ts < start);
+                candidates.extend(
+                    events[first..]
+                        .iter()
+                        .take_while(|(event_ts, _)| *event_ts <= end)
+                        .map(|(_, idx)| *idx),
+                );
+            }
+        }
+        candidates
+    }
+}
+
+// address of events that don't know it
+const ANY_ADDR: [u8; 6] = [0; 6];
+
+// the parts of the 802.11 header of a packet that events are compared with
+struct MacHeader {
+    frm_ctrl: u16,
+    seq_ctrl: u16,
+    qos_ctrl: Option<u16>,
+    addr1: [u8; 6],
+    addr2: [u8; 6],
+}
+
+impl MacHeader {
+    // read the header of an 802.11 frame ('None' if the frame is too short)
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        // get the length of layer 2 and
+        // make sure we have access to everything we need
+        let frm_ctrl = LittleEndian::read_u16(&data[0..=1]);
+        let layer2_len = if frm_ctrl & HAS_ADDR4 == HAS_ADDR4 {
+            HDR_SIZE_4ADDR
+        } else {
+            HDR_SIZE_3ADDR
+        } as usize
+            + if frm_ctrl & CHECK_QOS == HAS_QOS {
+                2 // qos section is 2 bytes
+            } else {
+                0 // no qos
+            };
+        if data.len() < layer2_len {
+            return None;
+        }
+
+        // finally safe, get everything else
+        let seq_ctrl = LittleEndian::read_u16(&data[22..=23]);
+        let qos_ctrl = if frm_ctrl & CHECK_QOS == HAS_QOS {
+            Some(LittleEndian::read_u16(&data[(layer2_len - 2)..layer2_len]))
+        } else {
+            None
+        };
+        let mut addr1 = [0; 6];
+        addr1.copy_from_slice(&data[4..=9]);
+        let mut addr2 = [0; 6];
+        addr2.copy_from_slice(&data[10..=15]);
+
+        Some(Self {
+            frm_ctrl,
+            seq_ctrl,
+            qos_ctrl,
+            addr1,
+            addr2,
+        })
+    }
+
+    #[inline]
+    fn is_retry(&self) -> bool {
+        self.frm_ctrl & RETRY == RETRY
+    }
+
+    // match packets to events
+    // (a retransmission is the same frame, so the retry bit isn't compared)
+    fn matches(&self, event: &Event) -> bool {
+        self.frm_ctrl & !RETRY == *event.frm_ctrl() & !RETRY
+            && self.seq_ctrl == *event.seq_ctrl()
+            && self.qos_ctrl == *event.qos_ctrl()
+            && event.compare_addr1(&self.addr1)
+            && event.compare_addr2(&self.addr2)
+    }
+
+    // how likely a packet that matches an event is to be the one that caused it
+    // (the closer it is in time the better, a retry is less likely to be the original
+    //  and an event that has both addresses matches the packet exactly)
+    fn likelihood(&self, event: &Event, ts: SystemTime) -> f32 {
+        let distance = ts
+            .duration_since(*event.ts())
+            .unwrap_or_else(|err| err.duration());
+        let mut likelihood = 1.0 / (1.0 + distance.as_secs_f32());
+        if self.is_retry() {
+            likelihood *= 0.5;
+        }
+        // (an event only matches an address of all zeros if it doesn't know it)
+        if event.compare_addr1(&ANY_ADDR) || event.compare_addr2(&ANY_ADDR) {
+            likelihood *= 0.75;
+        }
+        likelihood
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcapng::PcapngWriter;
+
+    use std::{
+        collections::BTreeSet,
+        env::temp_dir,
+        fs::{remove_file, File},
+    };
+
+    use serde_json::{from_value, json};
+
+    const LINKTYPE_IEEE802_11_RADIOTAP: u16 = 127;
+    const STATIONS: [[u8; 6]; 4] = [
+        [2, 0, 0, 0, 0, 1],
+        [2, 0, 0, 0, 0, 2],
+        [2, 0, 0, 0, 0, 3],
+        [2, 0, 0, 0, 0, 4],
+    ];
+    // data without and with QoS, over 3 and 4 addresses, and an action frame
+    const FRM_CTRLS: [u16; 5] = [0x0008, 0x0088, 0x0308, 0x0388, 0x00d0];
+
+    // how the packets were matched before the index, comparing every packet with every event
+    fn event_matches_packet(event: &Event, data: &[u8], len: usize) -> bool {
+        if len < 4 {
+            return false;
+        }
+        let layer1_len = LittleEndian::read_u16(&data[2..=3]) as usize;
+        if len < layer1_len + 2 {
+            return false;
+        }
+        let data = &data[layer1_len..];
+        let frm_ctrl = LittleEndian::read_u16(&data[0..=1]);
+        let layer2_len = if frm_ctrl & HAS_ADDR4 == HAS_ADDR4 {
+            HDR_SIZE_4ADDR
+        } else {
+            HDR_SIZE_3ADDR
+        } as usize
+            + if frm_ctrl & CHECK_QOS == HAS_QOS {
+                2
+            } else {
+                0
+            };
+        if len < layer1_len + layer2_len {
+            return false;
+        }
+        let seq_ctrl = LittleEndian::read_u16(&data[22..=23]);
+        let qos_ctrl = if frm_ctrl & CHECK_QOS == HAS_QOS {
+            Some(LittleEndian::read_u16(&data[(layer2_len - 2)..layer2_len]))
+        } else {
+            None
+        };
+        frm_ctrl == *event.frm_ctrl()
+            && seq_ctrl == *event.seq_ctrl()
+            && qos_ctrl == *event.qos_ctrl()
+            && event.compare_addr1(&data[4..=9])
+            && event.compare_addr2(&data[10..=15])
+    }
+
+    fn old_correlate(events: &[Event], capture_file: &Path) -> Vec<BTreeSet<usize>> {
+        let mut packets = CaptureFile::open(capture_file).unwrap();
+        let mut matched = vec![BTreeSet::new(); events.len()];
+        let mut counter = 0;
+        while let Some(pkt) = packets.next_packet().unwrap() {
+            counter += 1;
+            for (idx, event) in events.iter().enumerate() {
+                if event_matches_packet(event, &pkt.data, pkt.caplen as usize) {
+                    matched[idx].insert(counter);
+                }
+            }
+        }
+        matched
+    }
+
+    fn event(
+        id: usize,
+        ts: SystemTime,
+        frm_ctrl: u16,
+        seq_ctrl: u16,
+        addrs: [[u8; 6]; 2],
+    ) -> Event {
+        let qos = frm_ctrl & CHECK_QOS == HAS_QOS;
+        from_value(json!({
+            "id": id,
+            "ts": ts.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+            "pkts": [],
+            "retries": 0,
+            "action": "RX_ADD",
+            "mac": STATIONS[0],
+            "iface": "mesh0",
+            "netns": 0,
+            "ifindex": 1,
+            "dst": STATIONS[3],
+            "frm_ctrl": frm_ctrl,
+            "seq_ctrl": seq_ctrl,
+            "qos_ctrl": qos.then_some(seq_ctrl & 0x7),
+            "addr1": addrs[0],
+            "addr2": addrs[1],
+            "addr3": STATIONS[3],
+        }))
+        .unwrap()
+    }
+
+    // radiotap header without any field, and then the 802.11 header
+    fn packet(frm_ctrl: u16, seq_ctrl: u16, addrs: [[u8; 6]; 2]) -> Vec<u8> {
+        let mut data = vec![0, 0, 8, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&frm_ctrl.to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&addrs[0]);
+        data.extend_from_slice(&addrs[1]);
+        data.extend_from_slice(&STATIONS[3]);
+        data.extend_from_slice(&seq_ctrl.to_le_bytes());
+        if frm_ctrl & HAS_ADDR4 == HAS_ADDR4 {
+            data.extend_from_slice(&STATIONS[0]);
+        }
+        if frm_ctrl & CHECK_QOS == HAS_QOS {
+            data.extend_from_slice(&(seq_ctrl & 0x7).to_le_bytes());
+        }
+        data
+    }
+
+    fn write_capture(name: &str, mut packets: Vec<(SystemTime, Vec<u8>)>) -> PathBuf {
+        packets.sort_by_key(|(ts, _)| *ts);
+        let path = temp_dir().join(format!("correlate-{}-{name}.pcapng", std::process::id()));
+        let mut writer = PcapngWriter::new(File::create(&path).unwrap()).unwrap();
+        writer
+            .add_interface("mon0", LINKTYPE_IEEE802_11_RADIOTAP, 0)
+            .unwrap();
+        for (ts, data) in &packets {
+            writer
+                .write_packet(0, *ts, data.len() as u32, data)
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        path
+    }
+
+    // a few seconds of traffic between four stations, with reused sequence numbers,
+    // events that don't know an address, retransmissions, packets of no event,
+    // and events whose packet wasn't captured
+    fn session() -> (Vec<Event>, Vec<(SystemTime, Vec<u8>)>) {
+        let start = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let mut events = vec![];
+        let mut packets = vec![];
+        for i in 0..200 {
+            let ts = start + Duration::from_millis(20 * i as u64);
+            let frm_ctrl = FRM_CTRLS[i % FRM_CTRLS.len()];
+            let seq_ctrl = ((i % 48) as u16) << 4;
+            let addrs = [STATIONS[(i + 1) % 4], STATIONS[i % 4]];
+            let mut known = addrs;
+            if i % 7 == 0 {
+                known[1] = ANY_ADDR;
+            }
+            if i % 11 == 0 {
+                known[0] = ANY_ADDR;
+            }
+            events.push(event(i, ts, frm_ctrl, seq_ctrl, known));
+
+            if i % 9 != 0 {
+                packets.push((
+                    ts + Duration::from_millis(1),
+                    packet(frm_ctrl, seq_ctrl, addrs),
+                ));
+                if i % 5 == 0 {
+                    let retry = packet(frm_ctrl | RETRY, seq_ctrl, addrs);
+                    packets.push((ts + Duration::from_millis(3), retry));
+                }
+            }
+            let other = [STATIONS[(i + 2) % 4], STATIONS[(i + 3) % 4]];
+            packets.push((
+                ts + Duration::from_millis(5),
+                packet(frm_ctrl, seq_ctrl + 16, other),
+            ));
+        }
+        (events, packets)
+    }
+
+    #[test]
+    fn same_packets_as_comparing_everything() {
+        let (mut events, packets) = session();
+        let capture = write_capture("session", packets);
+        let expected = old_correlate(&events, &capture);
+        let correlation = correlate(
+            &mut events,
+            &capture,
+            &Radios::default(),
+            CORRELATION_WINDOW,
+            false,
+        )
+        .unwrap();
+        remove_file(&capture).unwrap();
+
+        assert!(correlation.stats.ambiguous > 0);
+        assert!(correlation.stats.retries > 0);
+        for (event, expected) in events.iter().zip(expected) {
+            let pkts = event
+                .pkts()
+                .iter()
+                .map(|pkt| pkt.pkt)
+                .collect::<BTreeSet<_>>();
+            assert_eq!(pkts, expected, "packets of event {}", event.id());
+        }
+    }
+
+    #[test]
+    fn retry_without_original() {
+        let ts = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let addrs = [STATIONS[1], STATIONS[0]];
+        let mut events = vec![event(0, ts, FRM_CTRLS[1], 0x10, addrs)];
+        let retry = packet(FRM_CTRLS[1] | RETRY, 0x10, addrs);
+        let capture = write_capture("retry", vec![(ts, retry)]);
+        let expected = old_correlate(&events, &capture);
+        correlate(
+            &mut events,
+            &capture,
+            &Radios::default(),
+            CORRELATION_WINDOW,
+            false,
+        )
+        .unwrap();
+        remove_file(&capture).unwrap();
+
+        assert!(expected[0].is_empty());
+        assert_eq!(events[0].best_packet().map(|pkt| pkt.pkt), Some(1));
+        assert_eq!(*events[0].retries(), 0);
+        // (a retry on its own is still a weak match)
+        assert!(events[0]
+            .best_packet()
+            .map_or(false, |pkt| pkt.score <= 0.5));
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+// exit codes of the binaries (the ones from 'sysexits.h'),
+// so that whatever runs them can tell what went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ExitCode {
+    // the arguments are wrong, or would overwrite something
+    Usage = 64,
+    // a file is corrupt or from a newer version
+    Data = 65,
+    // a file that's needed isn't there
+    NoInput = 66,
+    // the kernel can't run the bpf program
+    Unavailable = 69,
+    // something that should never happen
+    Software = 70,
+    // a file couldn't be created
+    CantCreate = 73,
+    // reading or writing failed
+    Io = 74,
+    // another station or service didn't answer as expected
+    Protocol = 76,
+    // not allowed (usually because it's not running as root)
+    NoPermission = 77,
+}
+
+impl ExitCode {
+    #[inline]
+    pub const fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+// what can go wrong with the files of a station
+#[derive(Debug)]
+pub enum Error {
+    // a file that should be there isn't
+    Missing(PathBuf),
+    // not allowed to read or write a file
+    Permission(PathBuf),
+    // a file isn't valid (cut short in the middle, corrupt or from a newer version)
+    Corrupt { path: PathBuf, source: io::Error },
+    // anything else about reading or writing a file
+    Io { path: PathBuf, source: io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // an error about a file, by what kind of error it is
+    pub fn file(path: &Path, err: io::Error) -> Self {
+        let path = path.to_path_buf();
+        match err.kind() {
+            ErrorKind::NotFound => Self::Missing(path),
+            ErrorKind::PermissionDenied => Self::Permission(path),
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                Self::Corrupt { path, source: err }
+            }
+            _ => Self::Io { path, source: err },
+        }
+    }
+
+    pub const fn exit_code(&self) -> ExitCode {
+        match self {
+            Self::Missing(_) => ExitCode::NoInput,
+            Self::Permission(_) => ExitCode::NoPermission,
+            Self::Corrupt { .. } => ExitCode::Data,
+            Self::Io { .. } => ExitCode::Io,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(path) => write!(f, "{} doesn't exist", path.display()),
+            Self::Permission(path) => write!(
+                f,
+                "not allowed to use {} (files written by the service as root need sudo)",
+                path.display()
+            ),
+            Self::Corrupt { path, source } => write!(
+                f,
+                "{} isn't valid: {source} (it might be from a newer version, \
+                 or it's not the file that was expected)",
+                path.display()
+            ),
+            Self::Io { path, source } => write!(f, "{}: {source}", path.display()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Corrupt { source, .. } | Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -2,15 +2,54 @@
 #[allow(non_camel_case_types)]
 mod binds;
 
+mod binary;
 mod bpf;
+mod capture;
+mod control;
+mod correlate;
+mod error;
+mod log;
+mod manifest;
+mod ndjson;
+mod neighbors;
+mod paths;
+mod pcapng;
+mod schema;
+mod stream;
 
 #[path = "./bpf/.output/tracer.skel.rs"]
 mod tracer;
 
 pub use crate::{
     binds::{
-        Action, Event as RawEvent, CHECK_QOS, HAS_ADDR4, HAS_QOS, HDR_SIZE_3ADDR, HDR_SIZE_4ADDR,
+        Action, Event as RawEvent, Frame as RawFrame, Metric as RawMetric, PowerMode, CHECK_QOS,
+        HAS_ADDR4, HAS_QOS, HDR_SIZE_3ADDR, HDR_SIZE_4ADDR,
+    },
+    bpf::{
+        event::{Event, PacketMatch},
+        frame::Frame,
+        kernel_time,
+        metric::{Metric, MetricSample, NeighborMetrics},
+        MacAddr,
+    },
+    capture::{is_other_network, CaptureFile, CapturedPacket, LinkLayer},
+    control::{ControlClient, Request, Response, SessionState, SessionStatus},
+    correlate::{
+        correlate, correlate_files, Correlation, CorrelationStats, LinkRetries, Radios,
+        RetrySample, CORRELATION_WINDOW,
+    },
+    error::{Error, ExitCode, Result},
+    log::{read_events, read_events_file, write_events_file, EventFormat, EventLog},
+    manifest::{
+        CaptureConfig, CaptureInfo, Counters, InterfaceInfo, Manifest, ManifestFiles, RadioInfo,
+    },
+    neighbors::{NeighborSample, NeighborStats, PlinkState},
+    paths::{replay_paths, DivergenceKind, MeshPath, PathDivergence, PathSnapshot, ProxyPath},
+    pcapng::{PcapngReader, PcapngWriter},
+    schema::{migrate_event, EventsFile, Header, StationInfo, PRODUCER_VERSION, SCHEMA_VERSION},
+    stream::{
+        read_message, write_message, Connection, Message, StreamAddr, StreamListener,
+        STREAM_VERSION,
     },
-    bpf::{event::Event, MacAddr},
     tracer::{TracerSkel, TracerSkelBuilder},
 };
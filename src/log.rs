@@ -0,0 +1,266 @@
+use crate::{
+    binary::{read_binary, write_binary_event, write_binary_head, BINARY_MAGIC},
+    error,
+    schema::{migrate_event, EventsFile, Header, SCHEMA_VERSION},
+    Event,
+};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{read, rename, File},
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::{from_slice, from_value, to_vec, to_vec_pretty, Deserializer, Value};
+
+// how often the events log is flushed to the disk while tracing
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// how events files are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    Json,
+    Binary,
+}
+
+impl EventFormat {
+    #[inline]
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Binary => "bin",
+        }
+    }
+
+    // format of an existing events file, from its first bytes
+    pub fn of_file(path: &Path) -> error::Result<Self> {
+        let mut magic = [0; 4];
+        File::open(path)
+            .and_then(|mut file| file.read(&mut magic))
+            .map(|read| Self::detect(&magic[..read]))
+            .map_err(|err| error::Error::file(path, err))
+    }
+
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(BINARY_MAGIC) {
+            Self::Binary
+        } else {
+            Self::Json
+        }
+    }
+}
+
+impl FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(Self::Json),
+            "binary" => Ok(Self::Binary),
+            _ => Err(format!("'{format}' isn't a format, use 'json' or 'binary'")),
+        }
+    }
+}
+
+impl Display for EventFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+// events file written while tracing, with the header and then one event at a time
+// (one per line with JSON, or one per record with the binary format),
+// so that everything before a crash can still be read
+pub struct EventLog {
+    file: File,
+    format: EventFormat,
+    last_sync: Instant,
+}
+
+impl EventLog {
+    pub fn create(path: &Path, header: &Header, format: EventFormat) -> error::Result<Self> {
+        let create = || {
+            let mut log = Self {
+                file: File::create(path)?,
+                format,
+                last_sync: Instant::now(),
+            };
+            match format {
+                EventFormat::Json => log.write_line(header)?,
+                EventFormat::Binary => write_binary_head(&mut log.file, header)?,
+            }
+            Ok(log)
+        };
+        create().map_err(|err| error::Error::file(path, err))
+    }
+
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        match self.format {
+            EventFormat::Json => self.write_line(event)?,
+            EventFormat::Binary => write_binary_event(&mut self.file, event)?,
+        }
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.last_sync = Instant::now();
+        self.file.sync_data()
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let mut line = to_vec(value)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+// replace an events file with its header and all of its events
+// (in JSON as a single object, so that it's easy to read by anything else)
+// (written to another file first, so that a crash never leaves the old one half written)
+pub fn write_events_file(
+    path: &Path,
+    events: &EventsFile,
+    format: EventFormat,
+) -> error::Result<()> {
+    write_file(path, events, format).map_err(|err| error::Error::file(path, err))
+}
+
+fn write_file(path: &Path, events: &EventsFile, format: EventFormat) -> Result<()> {
+    let mut tmp = PathBuf::from(path);
+    tmp.set_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    match format {
+        EventFormat::Json => file.write_all(&to_vec_pretty(events)?)?,
+        EventFormat::Binary => {
+            let mut data = vec![];
+            write_binary_head(&mut data, &events.header)?;
+            for event in &events.events {
+                write_binary_event(&mut data, event)?;
+            }
+            file.write_all(&data)?;
+        }
+    }
+    file.sync_data()?;
+    rename(tmp, path)
+}
+
+pub fn read_events_file(path: &Path) -> error::Result<EventsFile> {
+    read(path)
+        .and_then(|data| read_events(&data))
+        .map_err(|err| error::Error::file(path, err))
+}
+
+// read events from any of the formats the service has written, migrating them if needed:
+// the binary format, a single object with the header and the events, the header and events as NDJSON,
+// events as NDJSON without a header or a JSON array of events
+// (the last event is ignored if it was cut short, since that's what a crash leaves behind)
+pub fn read_events(data: &[u8]) -> Result<EventsFile> {
+    if EventFormat::detect(data) == EventFormat::Binary {
+        return read_binary(data);
+    }
+    let (header, events) = match data.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[') => (Header::legacy(0), read_array(data)?),
+        Some(b'{') => match from_slice::<Value>(data) {
+            Ok(Value::Object(mut file)) if file.contains_key("events") => {
+                let events = match file.remove("events") {
+                    Some(Value::Array(events)) => events,
+                    _ => return Err(invalid("'events' isn't a list")),
+                };
+                let header = from_value::<Header>(Value::Object(file))?;
+                (header, events)
+            }
+            _ => read_lines(data)?,
+        },
+        Some(_) => return Err(invalid("not an events file")),
+        None => (Header::legacy(1), vec![]),
+    };
+
+    if header.schema > SCHEMA_VERSION {
+        return Err(invalid(&format!(
+            "file has schema {} from service {}, but only up to {SCHEMA_VERSION} is supported",
+            header.schema, header.producer
+        )));
+    }
+    let events = events
+        .into_iter()
+        .map(|event| from_value(migrate_event(event, header.schema)))
+        .collect::<serde_json::Result<_>>()?;
+    Ok(EventsFile { header, events })
+}
+
+// (files without a header line are from before there were headers)
+fn read_lines(data: &[u8]) -> Result<(Header, Vec<Value>)> {
+    let lines = data
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .collect::<Vec<_>>();
+    let mut values = Vec::with_capacity(lines.len());
+    for (idx, line) in lines.iter().enumerate() {
+        match from_slice::<Value>(line) {
+            Ok(value) => values.push(value),
+            Err(err) if err.is_eof() && idx == lines.len() - 1 => break,
+            Err(err) => {
+                return Err(invalid(&format!(
+                    "line {} of the events file is invalid: {err}",
+                    idx + 1
+                )))
+            }
+        }
+    }
+
+    let has_header = values
+        .first()
+        .and_then(Value::as_object)
+        .is_some_and(|first| first.contains_key("schema"));
+    if has_header {
+        let header = from_value(values.remove(0))?;
+        Ok((header, values))
+    } else {
+        Ok((Header::legacy(1), values))
+    }
+}
+
+// (an array that isn't closed is read up to its last complete event)
+fn read_array(data: &[u8]) -> Result<Vec<Value>> {
+    let start = data
+        .iter()
+        .position(|b| *b == b'[')
+        .expect("array has an opening bracket");
+    let mut rest = &data[start + 1..];
+    let mut values = vec![];
+    loop {
+        rest = match rest
+            .iter()
+            .position(|b| !b.is_ascii_whitespace() && *b != b',')
+        {
+            Some(idx) => &rest[idx..],
+            None => break,
+        };
+        if rest[0] == b']' {
+            break;
+        }
+        let mut stream = Deserializer::from_slice(rest).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(value)) => values.push(value),
+            Some(Err(err)) if err.is_eof() => break,
+            Some(Err(err)) => return Err(Error::new(ErrorKind::InvalidData, err)),
+            None => break,
+        }
+        rest = &rest[stream.byte_offset()..];
+    }
+    Ok(values)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
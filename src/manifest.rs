@@ -0,0 +1,162 @@
+use crate::{
+    error,
+    schema::{ts_nanos, StationInfo, PRODUCER_VERSION},
+    MacAddr,
+};
+
+use std::{
+    fs::{read, rename, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec_pretty};
+
+// description of how a station was traced, written next to its files
+// (central finds the files of a station through it)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub station: StationInfo,
+    pub producer: String,
+    pub interfaces: Vec<InterfaceInfo>,
+    pub capture: CaptureInfo,
+    #[serde(with = "ts_nanos")]
+    pub start: SystemTime,
+    #[serde(with = "ts_nanos::option")]
+    pub stop: Option<SystemTime>,
+    pub counters: Counters,
+    pub config: CaptureConfig,
+    pub files: ManifestFiles,
+}
+
+// interface that had events traced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub mac: MacAddr,
+    pub netns: u32,
+    pub ifindex: i32,
+    // radio of the interface ('None' if it couldn't be found, like in another namespace)
+    #[serde(default)]
+    pub phy: Option<String>,
+}
+
+// where the packets came from (a monitor interface or the kernel)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureInfo {
+    pub interface: Option<String>,
+    pub kernel: bool,
+    // what was kept from the monitor interface
+    // (the capture filter, how many bytes of each packet and the frames of which mesh)
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub snaplen: Option<u32>,
+    #[serde(default)]
+    pub mesh_id: Option<String>,
+    // devices that were captured from, with their radio
+    // (when there's more than one, the capture has an interface for each of them)
+    #[serde(default)]
+    pub radios: Vec<RadioInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RadioInfo {
+    pub interface: String,
+    pub phy: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Counters {
+    pub events: usize,
+    pub frames: usize,
+    pub metrics: usize,
+    // dumps of the path tables of the kernel (one per interface each time)
+    #[serde(default)]
+    pub snapshots: usize,
+    // samples of the statistics of the neighbors (one per neighbor each time)
+    #[serde(default)]
+    pub neighbor_samples: usize,
+    // submissions lost by the bpf program while the station was traced
+    // (shared by all the stations traced at the same time)
+    pub drops: u64,
+}
+
+// options the service was started with
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureConfig {
+    pub split: String,
+    pub netns: Option<u32>,
+    pub metric_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub path_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub neighbor_interval_ms: Option<u64>,
+    pub format: String,
+}
+
+// names of the files of the station, in the same folder as the manifest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestFiles {
+    pub events: String,
+    pub capture: String,
+    pub metrics: Option<String>,
+    // (from correlating the events with the capture)
+    #[serde(default)]
+    pub retries: Option<String>,
+    // (snapshots of the path tables of the kernel, to check the events against)
+    #[serde(default)]
+    pub paths: Option<String>,
+    // (statistics of the neighbors over time, from the station dumps of nl80211)
+    #[serde(default)]
+    pub neighbors: Option<String>,
+}
+
+impl Manifest {
+    pub fn new(station: StationInfo, config: CaptureConfig, files: ManifestFiles) -> Self {
+        Self {
+            station,
+            producer: PRODUCER_VERSION.to_string(),
+            interfaces: vec![],
+            capture: CaptureInfo::default(),
+            start: SystemTime::now(),
+            stop: None,
+            counters: Counters::default(),
+            config,
+            files,
+        }
+    }
+
+    // manifest file of a station
+    pub fn path(dir: &Path, station: &str) -> PathBuf {
+        dir.join(format!("{station}.manifest"))
+    }
+
+    pub fn read(path: &Path) -> error::Result<Self> {
+        read(path)
+            .and_then(|data| Ok(from_slice(&data)?))
+            .map_err(|err| error::Error::file(path, err))
+    }
+
+    // (written to another file first, so that a crash never leaves the old one half written)
+    pub fn write(&self, path: &Path) -> error::Result<()> {
+        let write = || {
+            let mut tmp = PathBuf::from(path);
+            tmp.set_extension("manifest.tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&to_vec_pretty(self)?)?;
+            file.sync_data()?;
+            rename(tmp, path)
+        };
+        write().map_err(|err| error::Error::file(path, err))
+    }
+
+    // keep track of an interface the first time one of its events shows up
+    pub fn add_interface(&mut self, interface: InterfaceInfo) {
+        if !self.interfaces.contains(&interface) {
+            self.interfaces.push(interface);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::error;
+
+use std::{
+    fs::read,
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{to_vec, Deserializer};
+
+// (one value per line, so that they're appended as they're taken)
+pub fn append_line<T: Serialize>(value: &T, out: &mut impl Write) -> io::Result<()> {
+    let mut line = to_vec(value)?;
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+// (a value cut short by a crash is left out)
+pub fn read_lines<T: DeserializeOwned>(path: &Path) -> error::Result<Vec<T>> {
+    let data = read(path).map_err(|err| error::Error::file(path, err))?;
+    let mut values = vec![];
+    for value in Deserializer::from_slice(&data).into_iter() {
+        match value {
+            Ok(value) => values.push(value),
+            Err(err) if err.is_eof() => break,
+            Err(err) => return Err(error::Error::file(path, err.into())),
+        }
+    }
+    Ok(values)
+}
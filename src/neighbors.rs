@@ -0,0 +1,132 @@
+use crate::{
+    error,
+    ndjson::{append_line, read_lines},
+    schema::ts_nanos,
+    MacAddr,
+};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+// state of the peer link with a neighbor ('enum nl80211_plink_state')
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlinkState {
+    Listen,
+    OpenSent,
+    OpenReceived,
+    ConfirmReceived,
+    Established,
+    Holding,
+    Blocked,
+}
+
+impl PlinkState {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => Self::Listen,
+            1 => Self::OpenSent,
+            2 => Self::OpenReceived,
+            3 => Self::ConfirmReceived,
+            4 => Self::Established,
+            5 => Self::Holding,
+            6 => Self::Blocked,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for PlinkState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // (the names 'iw' uses)
+        let name = match self {
+            Self::Listen => "LISTEN",
+            Self::OpenSent => "OPN_SNT",
+            Self::OpenReceived => "OPN_RCVD",
+            Self::ConfirmReceived => "CNF_RCVD",
+            Self::Established => "ESTAB",
+            Self::Holding => "HOLDING",
+            Self::Blocked => "BLOCKED",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// statistics of a neighbor at an instant, as the station dump of nl80211 has them
+// (anything the driver doesn't report is left out, and the timestamp is on the same clock
+//  as the events)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NeighborSample {
+    #[serde(with = "ts_nanos")]
+    pub ts: SystemTime,
+    pub mac: MacAddr,
+    pub iface: String,
+    pub netns: u32,
+    pub peer: MacAddr,
+    // in dBm
+    pub signal: Option<i8>,
+    pub signal_avg: Option<i8>,
+    // in kbps
+    pub tx_bitrate: Option<u32>,
+    // (counted since the neighbor was added)
+    pub tx_retries: Option<u32>,
+    pub tx_failed: Option<u32>,
+    pub plink_state: Option<PlinkState>,
+    pub airtime_metric: Option<u32>,
+    // milliseconds since anything was heard from the neighbor
+    pub inactive_ms: Option<u32>,
+}
+
+impl NeighborSample {
+    // neighbors file that goes with an events file
+    pub fn path(events_file: &Path) -> PathBuf {
+        events_file.with_extension("neighbors")
+    }
+
+    // (one sample per line)
+    pub fn append(&self, out: &mut impl Write) -> io::Result<()> {
+        append_line(self, out)
+    }
+
+    pub fn read_file(path: &Path) -> error::Result<Vec<Self>> {
+        read_lines(path)
+    }
+}
+
+// time series of the samples of a neighbor, seen from one interface
+#[derive(Debug, Clone)]
+pub struct NeighborStats {
+    pub mac: MacAddr,
+    pub iface: String,
+    pub peer: MacAddr,
+    pub samples: Vec<NeighborSample>,
+}
+
+impl NeighborStats {
+    // group samples by interface and neighbor, keeping them sorted by time
+    pub fn from_samples(mut samples: Vec<NeighborSample>) -> Vec<Self> {
+        samples.sort_by_key(|s| s.ts);
+
+        let mut neighbors: Vec<Self> = vec![];
+        for sample in samples {
+            match neighbors
+                .iter_mut()
+                .find(|n| n.mac == sample.mac && n.peer == sample.peer)
+            {
+                Some(neighbor) => neighbor.samples.push(sample),
+                None => neighbors.push(Self {
+                    mac: sample.mac.clone(),
+                    iface: sample.iface.clone(),
+                    peer: sample.peer.clone(),
+                    samples: vec![sample],
+                }),
+            }
+        }
+        neighbors
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    error,
+    ndjson::{append_line, read_lines},
+    schema::ts_nanos,
+    Action, Event, MacAddr,
+};
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+// mesh path in the table of the kernel, as nl80211 dumps it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshPath {
+    pub dst: MacAddr,
+    pub next_hop: Option<MacAddr>,
+    pub sn: u32,
+    pub metric: u32,
+    // milliseconds until it expires
+    pub exptime: u32,
+    // (active, resolving, sn valid, fixed and resolved, from 'enum nl80211_mpath_flags')
+    pub flags: u8,
+    pub hop_count: Option<u8>,
+}
+
+// destination outside of the mesh, reached through a proxy mesh gate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyPath {
+    pub dst: MacAddr,
+    pub proxy: MacAddr,
+}
+
+// path tables of an interface at an instant, to check the events against
+// (the timestamp is on the same clock as the events)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathSnapshot {
+    #[serde(with = "ts_nanos")]
+    pub ts: SystemTime,
+    pub iface: String,
+    pub mac: MacAddr,
+    pub netns: u32,
+    pub paths: Vec<MeshPath>,
+    pub proxies: Vec<ProxyPath>,
+}
+
+impl PathSnapshot {
+    // snapshots file that goes with an events file
+    pub fn path(events_file: &Path) -> PathBuf {
+        events_file.with_extension("paths")
+    }
+
+    // (one snapshot per line)
+    pub fn append(&self, out: &mut impl Write) -> io::Result<()> {
+        append_line(self, out)
+    }
+
+    pub fn read_file(path: &Path) -> error::Result<Vec<Self>> {
+        read_lines(path)
+    }
+}
+
+// how the table replayed from the events differs from the one of the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    // the kernel has a path that the events never added (or that they deleted)
+    Missing {
+        next_hop: Option<MacAddr>,
+    },
+    // the events have a path that the kernel doesn't (a delete or expiry was missed)
+    Stale {
+        next_hop: Option<MacAddr>,
+    },
+    // both have the path, with different nexthops
+    NextHop {
+        replayed: Option<MacAddr>,
+        kernel: Option<MacAddr>,
+    },
+}
+
+// path that didn't match between the events and a snapshot
+#[derive(Debug, Clone)]
+pub struct PathDivergence {
+    pub ts: SystemTime,
+    pub iface: String,
+    pub dst: MacAddr,
+    pub kind: DivergenceKind,
+}
+
+impl Display for DivergenceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let nh = |nh: &Option<MacAddr>| nh.as_ref().map_or("none".to_string(), MacAddr::to_string);
+        match self {
+            Self::Missing { next_hop } => {
+                write!(
+                    f,
+                    "in the kernel (nexthop {}) but not in the events",
+                    nh(next_hop)
+                )
+            }
+            Self::Stale { next_hop } => {
+                write!(
+                    f,
+                    "in the events (nexthop {}) but not in the kernel",
+                    nh(next_hop)
+                )
+            }
+            Self::NextHop { replayed, kernel } => write!(
+                f,
+                "nexthop {} in the events but {} in the kernel",
+                nh(replayed),
+                nh(kernel)
+            ),
+        }
+    }
+}
+
+// destinations of an interface and their nexthops
+type Table = HashMap<MacAddr, Option<MacAddr>>;
+
+// replay the path events of a station and compare them with the snapshots of its interfaces
+// (the first snapshot of an interface is where its table starts from, since the paths from
+//  before tracing never had events, and the table starts over from every snapshot after
+//  comparing, so that a missed event only shows up once)
+// (the proxies of the snapshots are only recorded, since no event changes them)
+pub fn replay_paths(events: &[Event], snapshots: &[PathSnapshot]) -> Vec<PathDivergence> {
+    let mut events = events.iter().filter(|e| e.from_path()).collect::<Vec<_>>();
+    events.sort_by_key(|e| *e.ts());
+    let mut snapshots = snapshots.iter().collect::<Vec<_>>();
+    snapshots.sort_by_key(|s| s.ts);
+
+    let mut tables: HashMap<&MacAddr, Table> = HashMap::new();
+    let mut divergences = vec![];
+    let mut events = events.into_iter().peekable();
+    for snapshot in snapshots {
+        // (events from before the first snapshot of their interface are already in it)
+        while let Some(event) = events.next_if(|e| *e.ts() <= snapshot.ts) {
+            if let Some(table) = tables.get_mut(event.mac()) {
+                apply(table, event);
+            }
+        }
+
+        let kernel = snapshot
+            .paths
+            .iter()
+            .map(|path| (path.dst.clone(), path.next_hop.clone()))
+            .collect::<Table>();
+        if let Some(replayed) = tables.get(&snapshot.mac) {
+            let diverged = |dst: &MacAddr, kind| PathDivergence {
+                ts: snapshot.ts,
+                iface: snapshot.iface.clone(),
+                dst: dst.clone(),
+                kind,
+            };
+            for (dst, next_hop) in &kernel {
+                match replayed.get(dst) {
+                    None => divergences.push(diverged(
+                        dst,
+                        DivergenceKind::Missing {
+                            next_hop: next_hop.clone(),
+                        },
+                    )),
+                    Some(replayed) if replayed != next_hop => divergences.push(diverged(
+                        dst,
+                        DivergenceKind::NextHop {
+                            replayed: replayed.clone(),
+                            kernel: next_hop.clone(),
+                        },
+                    )),
+                    Some(_) => {}
+                }
+            }
+            for (dst, next_hop) in replayed {
+                if !kernel.contains_key(dst) {
+                    divergences.push(diverged(
+                        dst,
+                        DivergenceKind::Stale {
+                            next_hop: next_hop.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+        tables.insert(&snapshot.mac, kernel);
+    }
+    divergences.sort_by(|a, b| (a.ts, &a.iface, a.dst.0).cmp(&(b.ts, &b.iface, b.dst.0)));
+    divergences
+}
+
+// change a table like the event changed the one of the kernel
+fn apply(table: &mut Table, event: &Event) {
+    match event.action() {
+        Action::ACT_TX_ADD | Action::ACT_RX_ADD | Action::ACT_US_ADD => {
+            table.insert(event.dst().clone(), None);
+        }
+        Action::ACT_TX_DEL | Action::ACT_RX_DEL | Action::ACT_US_DEL | Action::ACT_KR_EXP => {
+            table.remove(event.dst());
+        }
+        _ => {
+            table.insert(event.dst().clone(), event.new_nh().clone());
+        }
+    }
+}
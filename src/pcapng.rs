@@ -0,0 +1,550 @@
+use crate::capture::{CapturedPacket, LinkLayer};
+
+use std::{
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+// block types (https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-05.html)
+const SECTION_HEADER: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const OBSOLETE_PACKET: u32 = 0x00000002;
+const SIMPLE_PACKET: u32 = 0x00000003;
+const ENHANCED_PACKET: u32 = 0x00000006;
+
+// what tells the byte order of a section apart
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+// version of the format that gets written
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 0;
+
+// options of interfaces
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const IF_TSOFFSET: u16 = 14;
+
+// finest decimal resolution of timestamps that can be converted
+// (10^38 is the last power of 10 that fits in 128 bits)
+const MAX_DECIMAL_RESOLUTION: u8 = 9 + 38;
+
+// interface of a section, that packets refer to by their position
+struct Interface {
+    name: Option<String>,
+    link: Option<LinkLayer>,
+    snaplen: u32,
+    // timestamps are in units of 10^-n seconds, or 2^-n if 'binary'
+    resolution: u8,
+    binary: bool,
+    // seconds added to every timestamp
+    offset: i64,
+}
+
+impl Interface {
+    // (an error if it's out of the range of the clock, since the offset can be anything)
+    fn timestamp(&self, high: u32, low: u32) -> Result<SystemTime> {
+        let units = ((high as u128) << 32) | low as u128;
+        let nanos = if self.binary {
+            (units * 1_000_000_000) >> self.resolution
+        } else if self.resolution <= 9 {
+            units * 10u128.pow(9 - self.resolution as u32)
+        } else {
+            units / 10u128.pow(self.resolution as u32 - 9)
+        };
+        let offset = Duration::from_secs(self.offset.unsigned_abs());
+        u64::try_from(nanos)
+            .ok()
+            .and_then(|nanos| UNIX_EPOCH.checked_add(Duration::from_nanos(nanos)))
+            .and_then(|ts| {
+                if self.offset >= 0 {
+                    ts.checked_add(offset)
+                } else {
+                    ts.checked_sub(offset)
+                }
+            })
+            .ok_or_else(|| invalid("packet has a timestamp out of range"))
+    }
+}
+
+// packets of a pcapng file, with the link-layer type of the interface each one came from
+// (every section has its own byte order and interfaces)
+pub struct PcapngReader<R: Read> {
+    reader: BufReader<R>,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+
+impl<R: Read> PcapngReader<R> {
+    // check if a file is pcapng, from its first bytes
+    // (the type of the section header is the same in either byte order)
+    pub fn detect(data: &[u8]) -> bool {
+        data.len() >= 4 && LittleEndian::read_u32(data) == SECTION_HEADER
+    }
+
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = Self {
+            reader: BufReader::new(reader),
+            big_endian: false,
+            interfaces: vec![],
+        };
+        match reader.read_block()? {
+            Some((SECTION_HEADER, _)) => Ok(reader),
+            _ => Err(invalid("doesn't start with a section header")),
+        }
+    }
+
+    // next packet, from any interface ('None' after the last one)
+    pub fn next_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        loop {
+            let (kind, body) = match self.read_block()? {
+                Some(block) => block,
+                None => return Ok(None),
+            };
+            match kind {
+                INTERFACE_DESCRIPTION => {
+                    let interface = self.interface(&body)?;
+                    self.interfaces.push(interface);
+                }
+                ENHANCED_PACKET => {
+                    let fields = self.fields(&body, 5)?;
+                    return self
+                        .packet(
+                            fields[0],
+                            Some((fields[1], fields[2])),
+                            fields[3],
+                            fields[4],
+                            &body[20..],
+                        )
+                        .map(Some);
+                }
+                OBSOLETE_PACKET => {
+                    let fields = self.fields(&body, 5)?;
+                    let interface = self.u16(&body[0..2]) as u32;
+                    return self
+                        .packet(
+                            interface,
+                            Some((fields[1], fields[2])),
+                            fields[3],
+                            fields[4],
+                            &body[20..],
+                        )
+                        .map(Some);
+                }
+                SIMPLE_PACKET => {
+                    let len = self.fields(&body, 1)?[0];
+                    let snaplen = self.interface_at(0)?.snaplen;
+                    let caplen = if snaplen == 0 { len } else { len.min(snaplen) };
+                    return self.packet(0, None, caplen, len, &body[4..]).map(Some);
+                }
+                // the rest of the blocks don't have packets
+                _ => {}
+            }
+        }
+    }
+
+    fn packet(
+        &self,
+        interface: u32,
+        ts: Option<(u32, u32)>,
+        caplen: u32,
+        len: u32,
+        data: &[u8],
+    ) -> Result<CapturedPacket> {
+        let index = interface;
+        let interface = self.interface_at(interface)?;
+        let data = data
+            .get(..caplen as usize)
+            .ok_or_else(|| invalid("packet is longer than its block"))?;
+        Ok(CapturedPacket {
+            ts: ts
+                .map(|(high, low)| interface.timestamp(high, low))
+                .transpose()?,
+            interface: Some(index),
+            link: interface.link,
+            caplen,
+            len,
+            data: data.to_vec(),
+        })
+    }
+
+    // name of an interface of the current section (from the device it was captured on)
+    pub fn interface_name(&self, interface: u32) -> Option<&str> {
+        self.interfaces.get(interface as usize)?.name.as_deref()
+    }
+
+    fn interface_at(&self, interface: u32) -> Result<&Interface> {
+        self.interfaces
+            .get(interface as usize)
+            .ok_or_else(|| invalid(&format!("packet from unknown interface {interface}")))
+    }
+
+    fn interface(&self, body: &[u8]) -> Result<Interface> {
+        let fields = self.fields(body, 2)?;
+        let mut interface = Interface {
+            name: None,
+            link: LinkLayer::from_linktype(self.u16(&body[0..2])),
+            snaplen: fields[1],
+            resolution: 6,
+            binary: false,
+            offset: 0,
+        };
+
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = self.u16(&options[0..2]);
+            let len = self.u16(&options[2..4]) as usize;
+            let value = options
+                .get(4..4 + len)
+                .ok_or_else(|| invalid("option is longer than its block"))?;
+            match code {
+                OPT_END => break,
+                IF_NAME => {
+                    interface.name = Some(String::from_utf8_lossy(value).into_owned());
+                }
+                IF_TSRESOL if len == 1 => {
+                    interface.binary = value[0] & 0x80 != 0;
+                    interface.resolution = value[0] & 0x7f;
+                    if !interface.binary && interface.resolution > MAX_DECIMAL_RESOLUTION {
+                        return Err(invalid(&format!(
+                            "interface has a timestamp resolution of 10^-{} seconds",
+                            interface.resolution
+                        )));
+                    }
+                }
+                IF_TSOFFSET if len == 8 => {
+                    interface.offset = if self.big_endian {
+                        BigEndian::read_i64(value)
+                    } else {
+                        LittleEndian::read_i64(value)
+                    };
+                }
+                _ => {}
+            }
+            // values are padded to 32 bits
+            options = options.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+        }
+        Ok(interface)
+    }
+
+    // read a whole block, with its type and body
+    // (a section header changes the byte order and forgets the interfaces)
+    fn read_block(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        let mut head = [0; 8];
+        match self.reader.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let kind = self.u32(&head[0..4]);
+        if kind == SECTION_HEADER {
+            let mut magic = [0; 4];
+            self.reader.read_exact(&mut magic)?;
+            self.big_endian = match LittleEndian::read_u32(&magic) {
+                BYTE_ORDER_MAGIC => false,
+                magic if magic.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(invalid("section header has an unknown byte order")),
+            };
+            self.interfaces.clear();
+            let len = self.block_len(&head[4..8], 16)?;
+            let mut body = magic.to_vec();
+            body.resize(len - 12, 0);
+            self.reader.read_exact(&mut body[4..])?;
+            self.reader.read_exact(&mut [0; 4])?;
+            return Ok(Some((kind, body)));
+        }
+
+        let len = self.block_len(&head[4..8], 12)?;
+        let mut body = vec![0; len - 12];
+        // a block that was cut short is what a crash leaves behind
+        match self.reader.read_exact(&mut body) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        match self.reader.read_exact(&mut [0; 4]) {
+            Ok(()) => Ok(Some((kind, body))),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn block_len(&self, data: &[u8], min: usize) -> Result<usize> {
+        let len = self.u32(data) as usize;
+        if len < min || !len.is_multiple_of(4) {
+            return Err(invalid(&format!("block has an invalid length of {len}")));
+        }
+        Ok(len)
+    }
+
+    // the first 32-bit fields of a block
+    fn fields(&self, body: &[u8], count: usize) -> Result<Vec<u32>> {
+        if body.len() < count * 4 {
+            return Err(invalid("block is too short"));
+        }
+        Ok(body
+            .chunks_exact(4)
+            .take(count)
+            .map(|field| self.u32(field))
+            .collect())
+    }
+
+    fn u16(&self, data: &[u8]) -> u16 {
+        if self.big_endian {
+            BigEndian::read_u16(data)
+        } else {
+            LittleEndian::read_u16(data)
+        }
+    }
+
+    fn u32(&self, data: &[u8]) -> u32 {
+        if self.big_endian {
+            BigEndian::read_u32(data)
+        } else {
+            LittleEndian::read_u32(data)
+        }
+    }
+}
+
+// pcapng file with packets from several interfaces, each with its own link-layer type
+// (everything is written in little-endian, with timestamps in microseconds,
+//  and the interfaces have to be added before their packets)
+pub struct PcapngWriter<W: Write> {
+    writer: BufWriter<W>,
+    interfaces: u32,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(writer: W) -> Result<Self> {
+        let mut writer = Self {
+            writer: BufWriter::new(writer),
+            interfaces: 0,
+        };
+        let mut body = vec![];
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        body.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        // the length of the section isn't known
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_block(SECTION_HEADER, &body)?;
+        Ok(writer)
+    }
+
+    // add an interface with the name of its device, and get the index its packets refer to it by
+    pub fn add_interface(&mut self, name: &str, linktype: u16, snaplen: u32) -> Result<u32> {
+        let mut body = vec![];
+        body.extend_from_slice(&linktype.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&snaplen.to_le_bytes());
+        body.extend_from_slice(&IF_NAME.to_le_bytes());
+        body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+        pad(&mut body);
+        body.extend_from_slice(&OPT_END.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        self.write_block(INTERFACE_DESCRIPTION, &body)?;
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    pub fn write_packet(
+        &mut self,
+        interface: u32,
+        ts: SystemTime,
+        len: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        if interface >= self.interfaces {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("interface {interface} wasn't added"),
+            ));
+        }
+        let micros = ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = Vec::with_capacity(20 + data.len() + 3);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+        body.extend_from_slice(data);
+        pad(&mut body);
+        self.write_block(ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    // a block has its length both before and after the body
+    fn write_block(&mut self, kind: u32, body: &[u8]) -> Result<()> {
+        let len = (body.len() as u32 + 12).to_le_bytes();
+        self.writer.write_all(&kind.to_le_bytes())?;
+        self.writer.write_all(&len)?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&len)
+    }
+}
+
+// pad to 32 bits
+fn pad(data: &mut Vec<u8>) {
+    data.resize(data.len().div_ceil(4) * 4, 0);
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 802.11 data frame header, with nothing after it
+    const FRAME: [u8; 24] = [
+        0x08, 0x02, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 0x10, 0,
+    ];
+
+    fn ppi(frame: &[u8]) -> Vec<u8> {
+        let mut data = vec![0, 0];
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&105u32.to_le_bytes());
+        data.extend_from_slice(frame);
+        data
+    }
+
+    fn avs(frame: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 64];
+        data[0..4].copy_from_slice(&0x80211001u32.to_be_bytes());
+        data[4..8].copy_from_slice(&64u32.to_be_bytes());
+        data.extend_from_slice(frame);
+        data
+    }
+
+    fn radiotap(frame: &[u8]) -> Vec<u8> {
+        let mut data = vec![0, 0];
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(frame);
+        data
+    }
+
+    // section with an interface that has these options, and a packet at these units
+    fn section(options: &[(u16, &[u8])], units: u64) -> Vec<u8> {
+        let mut data = vec![];
+        let mut writer = PcapngWriter::new(&mut data).unwrap();
+        let mut body = vec![];
+        body.extend_from_slice(&105u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        for (code, value) in options {
+            body.extend_from_slice(&code.to_le_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            body.extend_from_slice(value);
+            pad(&mut body);
+        }
+        body.extend_from_slice(&[0; 4]);
+        writer.write_block(INTERFACE_DESCRIPTION, &body).unwrap();
+        writer.interfaces += 1;
+
+        let mut body = vec![];
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((units >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(units as u32).to_le_bytes());
+        body.extend_from_slice(&(FRAME.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(FRAME.len() as u32).to_le_bytes());
+        body.extend_from_slice(&FRAME);
+        writer.write_block(ENHANCED_PACKET, &body).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        data
+    }
+
+    fn read_one(data: &[u8]) -> Result<CapturedPacket> {
+        let mut reader = PcapngReader::new(data)?;
+        reader.next_packet().map(|pkt| pkt.expect("a packet"))
+    }
+
+    #[test]
+    fn mixed_link_layers() {
+        let ts = UNIX_EPOCH + Duration::from_micros(1_650_000_000_123_456);
+        let mut data = vec![];
+        let mut writer = PcapngWriter::new(&mut data).unwrap();
+        let packets = [
+            ("wlan0", 105, FRAME.to_vec(), LinkLayer::Ieee80211),
+            ("ppi0", 192, ppi(&FRAME), LinkLayer::Ppi),
+            ("avs0", 163, avs(&FRAME), LinkLayer::Avs),
+            ("mon0", 127, radiotap(&FRAME), LinkLayer::Radiotap),
+        ];
+        for (name, linktype, _, _) in &packets {
+            writer.add_interface(name, *linktype, 0).unwrap();
+        }
+        // (in reverse, so that packets don't just follow the order of the interfaces)
+        for (interface, (_, _, packet, _)) in packets.iter().enumerate().rev() {
+            writer
+                .write_packet(interface as u32, ts, packet.len() as u32, packet)
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut reader = PcapngReader::new(&data[..]).unwrap();
+        for (interface, (name, _, packet, link)) in packets.iter().enumerate().rev() {
+            let pkt = reader.next_packet().unwrap().unwrap();
+            assert_eq!(pkt.interface, Some(interface as u32));
+            assert_eq!(reader.interface_name(interface as u32), Some(*name));
+            assert_eq!(pkt.link, Some(*link));
+            assert_eq!(pkt.ts, Some(ts));
+            assert_eq!(pkt.data, *packet);
+            assert_eq!(pkt.frame(), Some(&FRAME[..]));
+        }
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn ppi_of_other_link_layer() {
+        let mut data = ppi(&FRAME);
+        data[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(LinkLayer::Ppi.preamble_len(&data), None);
+    }
+
+    #[test]
+    fn preamble_longer_than_packet() {
+        let mut data = avs(&FRAME);
+        data[4..8].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(LinkLayer::Avs.preamble_len(&data), None);
+        assert_eq!(LinkLayer::Radiotap.preamble_len(&[0, 0, 8]), None);
+    }
+
+    #[test]
+    fn timestamp_resolutions() {
+        let pkt = read_one(&section(&[(IF_TSRESOL, &[9])], 1_500_000_000)).unwrap();
+        assert_eq!(pkt.ts, Some(UNIX_EPOCH + Duration::from_millis(1500)));
+        let pkt = read_one(&section(&[(IF_TSRESOL, &[0x80 | 10])], 3 * 1024)).unwrap();
+        assert_eq!(pkt.ts, Some(UNIX_EPOCH + Duration::from_secs(3)));
+        let pkt = read_one(&section(&[(IF_TSRESOL, &[MAX_DECIMAL_RESOLUTION])], 1)).unwrap();
+        assert_eq!(pkt.ts, Some(UNIX_EPOCH));
+        let offset = (-10i64).to_le_bytes();
+        let pkt = read_one(&section(&[(IF_TSOFFSET, &offset)], 12_000_000)).unwrap();
+        assert_eq!(pkt.ts, Some(UNIX_EPOCH + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        let resolution = [MAX_DECIMAL_RESOLUTION + 1];
+        let err = read_one(&section(&[(IF_TSRESOL, &resolution)], 1)).err();
+        assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::InvalidData));
+
+        // (seconds that don't fit in nanoseconds, and an offset past any clock)
+        let err = read_one(&section(&[(IF_TSRESOL, &[0])], u64::MAX)).err();
+        assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::InvalidData));
+        let offset = i64::MAX.to_le_bytes();
+        let err = read_one(&section(&[(IF_TSOFFSET, &offset)], 1 << 60)).err();
+        assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{bpf::event::LEGACY_SCORE, Event};
+
+use std::fs::read_to_string;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// version of the events files written by this version of the service
+// 0: JSON array of events (timestamps as serde's 'SystemTime', actions by their bindgen name)
+// 1: same events as 0, one per line (NDJSON)
+// 2: header before the events, timestamps in nanoseconds and actions by their stable name
+// 3: packets of events with a score, best match first
+// 4: retries of the packet of events
+pub const SCHEMA_VERSION: u32 = 4;
+
+// version of the service that wrote a file
+pub const PRODUCER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// first thing in an events file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub schema: u32,
+    pub producer: String,
+    pub station: StationInfo,
+}
+
+impl Header {
+    pub fn new(station: StationInfo) -> Self {
+        Self {
+            schema: SCHEMA_VERSION,
+            producer: PRODUCER_VERSION.to_string(),
+            station,
+        }
+    }
+
+    // header for files written before there were headers
+    pub fn legacy(schema: u32) -> Self {
+        Self {
+            schema,
+            producer: "unknown".to_string(),
+            station: StationInfo::default(),
+        }
+    }
+}
+
+// where the events of a file were traced
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StationInfo {
+    pub name: String,
+    pub hostname: String,
+    pub kernel: String,
+}
+
+impl StationInfo {
+    // information about a station traced in this machine
+    pub fn local(name: &str) -> Self {
+        let read = |path| {
+            read_to_string(path)
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        Self {
+            name: name.to_string(),
+            hostname: read("/proc/sys/kernel/hostname"),
+            kernel: read("/proc/sys/kernel/osrelease"),
+        }
+    }
+}
+
+// everything in an events file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventsFile {
+    #[serde(flatten)]
+    pub header: Header,
+    pub events: Vec<Event>,
+}
+
+// bring an event written with an older schema up to the current one
+pub fn migrate_event(mut event: Value, schema: u32) -> Value {
+    if let Some(event) = event.as_object_mut() {
+        if schema < 2 {
+            migrate_to_2(event);
+        }
+        if schema < 3 {
+            migrate_to_3(event);
+        }
+        if schema < 4 {
+            event.entry("retries").or_insert(Value::from(0));
+        }
+    }
+    event
+}
+
+fn migrate_to_2(event: &mut Map<String, Value>) {
+    // 'SystemTime' was '{"secs_since_epoch": ..., "nanos_since_epoch": ...}'
+    if let Some(ts) = event.get("ts").and_then(Value::as_object) {
+        let secs = ts.get("secs_since_epoch").and_then(Value::as_u64);
+        let nanos = ts.get("nanos_since_epoch").and_then(Value::as_u64);
+        if let (Some(secs), Some(nanos)) = (secs, nanos) {
+            event.insert("ts".to_string(), Value::from(secs * 1_000_000_000 + nanos));
+        }
+    }
+
+    // the names from bindgen had the prefix of the C enum
+    strip_prefix(event, "action", "ACT_");
+    strip_prefix(event, "old_pm", "PM_");
+    strip_prefix(event, "new_pm", "PM_");
+
+    // files from before network namespaces were traced
+    event.entry("netns").or_insert(Value::from(0));
+    event.entry("ifindex").or_insert(Value::from(0));
+}
+
+// packets were only their number, so how likely they are isn't known
+fn migrate_to_3(event: &mut Map<String, Value>) {
+    if let Some(Value::Array(pkts)) = event.get_mut("pkts") {
+        let score = f64::from(LEGACY_SCORE);
+        for pkt in pkts.iter_mut() {
+            if pkt.is_u64() {
+                let mut matched = Map::new();
+                matched.insert("pkt".to_string(), pkt.take());
+                matched.insert("score".to_string(), Value::from(score));
+                *pkt = Value::Object(matched);
+            }
+        }
+    }
+}
+
+fn strip_prefix(event: &mut Map<String, Value>, field: &str, prefix: &str) {
+    if let Some(Value::String(name)) = event.get_mut(field) {
+        if let Some(stripped) = name.strip_prefix(prefix) {
+            *name = stripped.to_string();
+        }
+    }
+}
+
+// timestamps saved as nanoseconds since the epoch
+pub(crate) mod ts_nanos {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{ser::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ts: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanos = ts
+            .duration_since(UNIX_EPOCH)
+            .map_err(S::Error::custom)?
+            .as_nanos();
+        serializer.serialize_u64(u64::try_from(nanos).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let nanos = u64::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+
+    // (for timestamps that might not be known yet)
+    pub mod option {
+        use std::time::SystemTime;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            ts: &Option<SystemTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match ts {
+                Some(ts) => super::serialize(ts, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<SystemTime>, D::Error> {
+            Ok(Option::<u64>::deserialize(deserializer)?
+                .map(|nanos| super::UNIX_EPOCH + super::Duration::from_nanos(nanos)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn packets_without_score() {
+        let event = migrate_event(json!({ "pkts": [3, 5], "retries": 1 }), 2);
+        assert_eq!(
+            event,
+            json!({
+                "pkts": [{ "pkt": 3, "score": 1.0 }, { "pkt": 5, "score": 1.0 }],
+                "retries": 1,
+            })
+        );
+    }
+}
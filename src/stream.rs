@@ -0,0 +1,205 @@
+use crate::Event;
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{remove_file, symlink_metadata},
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+
+// every connection starts with these bytes followed by the version of the protocol
+const STREAM_MAGIC: &[u8; 4] = b"MESH";
+pub const STREAM_VERSION: u16 = 2;
+
+// anything bigger than this means that the stream is broken
+const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+// how long the other side has to send the handshake after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// what a service sends to central while tracing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    Event { station: String, event: Event },
+}
+
+// address for streaming, either 'unix:<path>' for a unix socket or '<host>:<port>' for tcp
+#[derive(Debug, Clone)]
+pub enum StreamAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for StreamAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> std::result::Result<Self, Self::Err> {
+        match addr.strip_prefix("unix:") {
+            Some("") => Err("unix socket address is missing the path".to_string()),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None if addr.contains(':') => Ok(Self::Tcp(addr.to_string())),
+            None => Err(format!(
+                "'{addr}' isn't in the format 'unix:<path>' nor '<host>:<port>'"
+            )),
+        }
+    }
+}
+
+impl Display for StreamAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl StreamAddr {
+    // connect and send the handshake
+    pub fn connect(&self) -> Result<Connection> {
+        let mut stream = match self {
+            Self::Tcp(addr) => Connection::Tcp(TcpStream::connect(addr)?),
+            Self::Unix(path) => Connection::Unix(UnixStream::connect(path)?),
+        };
+        stream.write_all(STREAM_MAGIC)?;
+        stream.write_u16::<LittleEndian>(STREAM_VERSION)?;
+        stream.flush()?;
+        Ok(stream)
+    }
+
+    // start listening for connections
+    // (a unix socket left behind by a previous listener gets replaced,
+    //  but not one that's still listened on, nor anything else in its place, like a file)
+    pub fn listen(&self) -> Result<StreamListener> {
+        match self {
+            Self::Tcp(addr) => Ok(StreamListener::Tcp(TcpListener::bind(addr)?)),
+            Self::Unix(path) => {
+                let is_socket = symlink_metadata(path)
+                    .map(|meta| meta.file_type().is_socket())
+                    .unwrap_or(false);
+                if is_socket && UnixStream::connect(path).is_err() {
+                    remove_file(path)?;
+                }
+                Ok(StreamListener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+}
+
+pub enum StreamListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl StreamListener {
+    // wait for a connection
+    // (its handshake is checked by whoever handles it, so that a silent one doesn't hold up
+    //  the ones after it)
+    pub fn accept(&self) -> Result<Connection> {
+        Ok(match self {
+            Self::Tcp(listener) => Connection::Tcp(listener.accept()?.0),
+            Self::Unix(listener) => Connection::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+// connection in both directions
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    // check the handshake of an accepted connection
+    // (which has to arrive in time, then reading waits forever again)
+    pub fn handshake(&mut self) -> Result<()> {
+        self.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut magic = [0; 4];
+        self.read_exact(&mut magic)?;
+        if &magic != STREAM_MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "connection isn't from mesh analyzer",
+            ));
+        }
+        let version = self.read_u16::<LittleEndian>()?;
+        if version != STREAM_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported stream version {version} (expected {STREAM_VERSION})"),
+            ));
+        }
+        self.set_read_timeout(None)
+    }
+
+    // how long reading waits before failing ('None' waits forever)
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// write a message, prefixed by its length
+pub fn write_message<W: Write + ?Sized, T: Serialize>(stream: &mut W, message: &T) -> Result<()> {
+    let data = to_vec(message)?;
+    stream.write_u32::<LittleEndian>(data.len() as u32)?;
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+// read a message written by 'write_message()'
+// ('None' if the other side closed the connection between messages)
+pub fn read_message<R: Read + ?Sized, T: DeserializeOwned>(stream: &mut R) -> Result<Option<T>> {
+    let len = match stream.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("message too big ({len} bytes)"),
+        ));
+    }
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(Some(from_slice(&data)?))
+}